@@ -0,0 +1,37 @@
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+
+use crate::pay_services::constant_time_eq;
+use crate::CONFIG;
+
+/**
+Доступ к админским endpoint'ам по заголовку `Authorization: Bearer <token>`.
+Если токен не задан в конфиге, админские endpoint'ы отключены
+ */
+pub struct AdminAuth;
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AdminAuth {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(token) = &CONFIG.admin_token else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+
+        let provided = parts
+            .headers
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+
+        if constant_time_eq(provided.trim().as_bytes(), token.as_bytes()) {
+            Ok(AdminAuth)
+        } else {
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use crate::get_db;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::Utc;
+use shared::CatalogProduct;
+
+pub async fn get_catalog() -> Response {
+    let Ok(products) = get_db().await.get_products().await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+
+    let now = Utc::now();
+
+    let products: Vec<CatalogProduct> = products
+        .iter()
+        .filter(|v| v.is_visible(now) && v.stock != Some(0))
+        .map(|v| v.to_catalog())
+        .collect();
+
+    Json(products).into_response()
+}
@@ -0,0 +1,18 @@
+use crate::database_connection::DbResponse;
+use crate::get_db;
+use axum::extract::Path;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use shared::CharacterInfoResponse;
+
+pub async fn get_character(Path(char_name): Path<String>) -> Response {
+    let Ok(char) = get_db().await.get_char_by_name(&char_name).await else {
+        return Json(CharacterInfoResponse::Err).into_response();
+    };
+
+    let DbResponse::NotFound(char) = char else {
+        return Json(CharacterInfoResponse::NotFound).into_response();
+    };
+
+    Json(CharacterInfoResponse::Ok(char.info)).into_response()
+}
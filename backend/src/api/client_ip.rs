@@ -0,0 +1,200 @@
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use crate::CONFIG;
+
+/**
+Подсеть в нотации CIDR. Одиночный адрес без `/` - подсеть из одного адреса
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = IpAddr::from_str(addr)
+            .map_err(|e| format!("{s}: {e}"))?
+            .to_canonical();
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(v) => v
+                .parse::<u8>()
+                .ok()
+                .filter(|v| *v <= max_prefix)
+                .ok_or_else(|| format!("{s}: wrong prefix"))?,
+            None => max_prefix,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+/**
+Пустой список не пропускает никого. Открыть webhook для всех можно только явно:
+`0.0.0.0/0,::/0`
+ */
+pub fn is_allowed(allowed: &[Cidr], ip: IpAddr) -> bool {
+    allowed.iter().any(|v| v.contains(ip))
+}
+
+/**
+IP клиента с учётом доверенных прокси (nginx). Заголовкам `X-Forwarded-For` и `X-Real-IP`
+верим, только если соединение пришло от доверенного прокси
+ */
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(ConnectInfo(peer)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() else {
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+
+        Ok(ClientIp(resolve_client_ip(
+            peer.ip(),
+            &parts.headers,
+            &CONFIG.trusted_proxies,
+        )))
+    }
+}
+
+fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[Cidr]) -> IpAddr {
+    let peer = peer.to_canonical();
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|v| v.contains(ip));
+
+    if !is_trusted(peer) {
+        return peer;
+    }
+
+    // Цепочку читаем справа налево: правые адреса дописаны нашими прокси,
+    // левые мог подставить сам клиент
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|v| IpAddr::from_str(v.trim()).ok())
+        .map(|v| v.to_canonical())
+        .collect();
+
+    if let Some(ip) = forwarded.iter().rev().find(|v| !is_trusted(**v)) {
+        return *ip;
+    }
+
+    if let Some(ip) = headers
+        .get("x-real-ip")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| IpAddr::from_str(v.trim()).ok())
+    {
+        return ip.to_canonical();
+    }
+
+    forwarded.first().copied().unwrap_or(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_allowed, resolve_client_ip, Cidr};
+    use axum::http::HeaderMap;
+    use std::net::IpAddr;
+
+    fn ip(v: &str) -> IpAddr {
+        v.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+
+        assert!(net.contains(ip("10.1.255.3")));
+        assert!(net.contains(ip("::ffff:10.1.0.1")));
+        assert!(!net.contains(ip("10.2.0.1")));
+
+        let single: Cidr = "77.88.1.1".parse().unwrap();
+        assert!(single.contains(ip("77.88.1.1")));
+        assert!(!single.contains(ip("77.88.1.2")));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("1.2.3.4")));
+        assert!("2a00:1450::/32"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(ip("2a00:1450:4010::1")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("nonsense".parse::<Cidr>().is_err());
+
+        assert!(!is_allowed(&[], ip("1.1.1.1")));
+        assert!(!is_allowed(&[net], ip("1.1.1.1")));
+
+        let any: Vec<Cidr> = vec!["0.0.0.0/0".parse().unwrap(), "::/0".parse().unwrap()];
+        assert!(is_allowed(&any, ip("1.1.1.1")));
+        assert!(is_allowed(&any, ip("2a00:1450:4010::1")));
+    }
+
+    #[test]
+    fn test_resolve_client_ip() {
+        let trusted = vec!["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap()];
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "6.6.6.6, 5.5.5.5, 10.0.0.2".parse().unwrap(),
+        );
+        headers.insert("x-real-ip", "4.4.4.4".parse().unwrap());
+
+        // Прямое подключение - заголовки игнорируются
+        assert_eq!(
+            resolve_client_ip(ip("1.2.3.4"), &headers, &trusted),
+            ip("1.2.3.4")
+        );
+
+        // Через прокси - первый недоверенный адрес справа
+        assert_eq!(
+            resolve_client_ip(ip("127.0.0.1"), &headers, &trusted),
+            ip("5.5.5.5")
+        );
+
+        headers.remove("x-forwarded-for");
+        assert_eq!(
+            resolve_client_ip(ip("127.0.0.1"), &headers, &trusted),
+            ip("4.4.4.4")
+        );
+
+        assert_eq!(
+            resolve_client_ip(ip("127.0.0.1"), &HeaderMap::new(), &trusted),
+            ip("127.0.0.1")
+        );
+    }
+}
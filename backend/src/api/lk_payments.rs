@@ -1,35 +1,214 @@
+use crate::api::client_ip::ClientIp;
+use crate::catalog::{reserve_cart, ProductError};
 use crate::database_connection::DbResponse;
-use crate::get_db;
-use crate::invoice_handler::INVOICE_HANDLER;
-use axum::extract::ConnectInfo;
+use crate::{get_db, CONFIG};
+use crate::invoice_handler::{InvoiceGift, NewInvoice, INVOICE_HANDLER};
+use crate::pay_services::enot::{available_currencies, available_pay_methods};
+use crate::pow::POW_GUARD;
+use crate::provider_health::PROVIDER_HEALTH;
+use crate::rate_limiter::INVOICE_RATE_LIMITER;
+use axum::extract::Path;
+use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::Json;
-use shared::{CreateInvoice, InvoiceCreationResponse};
-use std::net::SocketAddr;
+use shared::{
+    CreateInvoice, InvoiceCreationResponse, InvoiceInfoResponse, PaymentError, PaymentServices,
+    ProviderStatus,
+};
+use std::str::FromStr;
+use uuid::Uuid;
+
+const GIFT_MESSAGE_MAX_LEN: usize = 200;
+const PROMO_CODE_MAX_LEN: usize = 32;
 
 pub async fn create_invoice(
-    ConnectInfo(client_ip): ConnectInfo<SocketAddr>,
+    ClientIp(client_ip): ClientIp,
     Json(payload): Json<CreateInvoice>,
 ) -> Response {
-    let Ok(char_id) = get_db().await.get_char_id_by_name(&payload.char_name).await else {
-        return Json(InvoiceCreationResponse::Err).into_response();
+    if !INVOICE_RATE_LIMITER.check(client_ip, &payload.char_name) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(InvoiceCreationResponse::RateLimited),
+        )
+            .into_response();
+    }
+
+    if POW_GUARD.is_required(client_ip) {
+        let verified = match &payload.pow {
+            Some(solution) => match POW_GUARD.verify(solution) {
+                Ok(_) => true,
+                Err(e) => {
+                    println!("Rejected proof-of-work from {}: {e}", client_ip);
+                    false
+                }
+            },
+            None => false,
+        };
+
+        if !verified {
+            return Json(InvoiceCreationResponse::ChallengeRequired(POW_GUARD.issue()))
+                .into_response();
+        }
+    }
+
+    // Выбор способа оплаты поддерживает только Enot, для остальных он игнорируется
+    let pay_method = payload
+        .pay_method
+        .filter(|_| payload.service == PaymentServices::Enot);
+
+    if pay_method.is_some_and(|v| !available_pay_methods().contains(&v)) {
+        return Json(InvoiceCreationResponse::PayMethodUnavailable).into_response();
+    }
+
+    // Другую валюту можно выбрать только для CRD через Enot, товары продаются по ценам каталога
+    let currency_rate = match payload.currency {
+        Some(currency) if currency != payload.service.currency() => {
+            let rate = available_currencies()
+                .into_iter()
+                .find(|v| v.currency == currency)
+                .filter(|_| payload.service == PaymentServices::Enot && payload.cart.is_empty());
+
+            match rate {
+                Some(v) => Some(v),
+                None => return Json(InvoiceCreationResponse::CurrencyUnavailable).into_response(),
+            }
+        }
+        _ => None,
     };
 
-    let DbResponse::NotFound(char_id) = char_id else {
+    let Ok(char) = get_db().await.get_char_by_name(&payload.char_name).await else {
+        return Json(InvoiceCreationResponse::Failed(PaymentError::GameDbUnavailable))
+            .into_response();
+    };
+
+    let DbResponse::NotFound(char) = char else {
         return Json(InvoiceCreationResponse::WrongNick).into_response();
     };
 
+    if char.info.banned {
+        return Json(InvoiceCreationResponse::Banned).into_response();
+    }
+
+    if let Some(email) = &payload.payer_email {
+        if !is_valid_email(email) {
+            return Json(InvoiceCreationResponse::WrongEmail).into_response();
+        }
+    }
+
+    let gift = match payload.gift {
+        Some(gift) => {
+            let payer_char_id = match &gift.payer_char_name {
+                Some(payer_char_name) => {
+                    let Ok(payer) = get_db().await.get_char_by_name(payer_char_name).await else {
+                        return Json(InvoiceCreationResponse::Failed(
+                            PaymentError::GameDbUnavailable,
+                        ))
+                        .into_response();
+                    };
+
+                    let DbResponse::NotFound(payer) = payer else {
+                        return Json(InvoiceCreationResponse::WrongPayerNick).into_response();
+                    };
+
+                    Some(payer.obj_id)
+                }
+                None => None,
+            };
+
+            Some(InvoiceGift {
+                payer_char_name: gift.payer_char_name,
+                payer_char_id,
+                message: gift
+                    .message
+                    .map(|v| v.trim().chars().take(GIFT_MESSAGE_MAX_LEN).collect())
+                    .filter(|v: &String| !v.is_empty()),
+            })
+        }
+        None => None,
+    };
+
+    let (amount, lines) = if payload.cart.is_empty() {
+        (payload.amount, vec![])
+    } else {
+        match reserve_cart(&payload.cart, payload.service.currency()).await {
+            Ok(v) => v,
+            Err(ProductError::Db(_)) => {
+                return Json(InvoiceCreationResponse::Failed(PaymentError::Internal))
+                    .into_response();
+            }
+            Err(_) => {
+                return Json(InvoiceCreationResponse::ProductUnavailable).into_response();
+            }
+        }
+    };
+
     match INVOICE_HANDLER
-        .create_invoice(
-            payload.amount,
-            payload.char_name,
-            char_id,
-            payload.service,
-            client_ip.ip(),
-        )
+        .create_invoice(NewInvoice {
+            amount,
+            char_name: payload.char_name,
+            char_id: char.obj_id,
+            service: payload.service,
+            client_ip,
+            lines,
+            payer_email: payload.payer_email,
+            gift,
+            locale: payload.locale,
+            pay_method,
+            promo_code: payload
+                .promo_code
+                .map(|v| v.trim().chars().take(PROMO_CODE_MAX_LEN).collect())
+                .filter(|v: &String| !v.is_empty()),
+            currency_rate,
+        })
         .await
     {
         Ok(v) => Json(InvoiceCreationResponse::Ok(v)).into_response(),
-        Err(_) => Json(InvoiceCreationResponse::Err).into_response(),
+        Err(e) => Json(InvoiceCreationResponse::Failed(e)).into_response(),
+    }
+}
+
+fn is_valid_email(email: &str) -> bool {
+    let Some((user, domain)) = email.split_once('@') else {
+        return false;
+    };
+
+    email.len() <= 254
+        && !user.is_empty()
+        && !domain.contains('@')
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+}
+
+pub async fn get_pay_methods() -> Response {
+    Json(available_pay_methods()).into_response()
+}
+
+pub async fn get_currencies() -> Response {
+    Json(available_currencies()).into_response()
+}
+
+pub async fn get_providers() -> Response {
+    let statuses: Vec<ProviderStatus> = [
+        PaymentServices::Enot,
+        PaymentServices::Paypalych,
+        PaymentServices::PaypalychUk,
+        PaymentServices::Hotskins,
+    ]
+    .into_iter()
+    .map(|v| PROVIDER_HEALTH.status(v, &CONFIG.fallback_chain))
+    .collect();
+
+    Json(statuses).into_response()
+}
+
+pub async fn get_invoice(Path(order_id): Path<String>) -> Response {
+    let Ok(order_id) = Uuid::from_str(&order_id) else {
+        return Json(InvoiceInfoResponse::NotFound).into_response();
+    };
+
+    match get_db().await.get_invoice_by_id(order_id).await {
+        Some(invoice) => Json(InvoiceInfoResponse::Ok(invoice.to_info())).into_response(),
+        None => Json(InvoiceInfoResponse::NotFound).into_response(),
     }
 }
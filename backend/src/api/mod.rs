@@ -1,2 +1,8 @@
+pub mod admin;
+pub mod catalog;
+pub mod characters;
+pub mod client_ip;
 pub mod lk_payments;
+pub mod reconciliation;
+pub mod reports;
 pub mod webhooks;
@@ -0,0 +1,99 @@
+use crate::api::admin::AdminAuth;
+use crate::get_db;
+use crate::reconciliation::{import_statement, StatementError};
+use crate::reporting::ReportRange;
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::NaiveDate;
+use serde::Deserialize;
+use shared::PaymentServices;
+use uuid::Uuid;
+
+#[derive(Deserialize, Debug)]
+pub struct ImportQuery {
+    service: PaymentServices,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResolvePayload {
+    note: String,
+}
+
+/**
+Тело запроса - CSV выгрузка платёжки как есть
+ */
+pub async fn import_reconciliation_statement(
+    _: AdminAuth,
+    Query(query): Query<ImportQuery>,
+    statement: String,
+) -> Response {
+    let range = ReportRange {
+        from: query.from,
+        to: query.to,
+    };
+
+    match import_statement(query.service, range, &statement).await {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => match e.downcast_ref::<StatementError>() {
+            Some(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+            None => {
+                println!("Err on import {} statement {e:#?}", query.service);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        },
+    }
+}
+
+pub async fn get_reconciliation_reports(_: AdminAuth) -> Response {
+    match get_db().await.get_reconciliation_reports().await {
+        Ok(v) => Json(v).into_response(),
+        Err(e) => {
+            println!("Err on get reconciliation reports {e:#?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn get_reconciliation_report(_: AdminAuth, Path(report_id): Path<Uuid>) -> Response {
+    match get_db().await.get_reconciliation_report(report_id).await {
+        Ok(Some(v)) => Json(v).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("Err on get reconciliation report {e:#?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn resolve_discrepancy(
+    _: AdminAuth,
+    Path((report_id, discrepancy_id)): Path<(Uuid, Uuid)>,
+    Json(payload): Json<ResolvePayload>,
+) -> Response {
+    let db = get_db().await;
+
+    let mut report = match db.get_reconciliation_report(report_id).await {
+        Ok(Some(v)) => v,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            println!("Err on get reconciliation report {e:#?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    if !report.resolve(discrepancy_id, payload.note) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    match db.update_reconciliation_report(&report).await {
+        Ok(_) => Json(report).into_response(),
+        Err(e) => {
+            println!("Err on update reconciliation report {e:#?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use crate::api::admin::AdminAuth;
+use crate::get_db;
+use crate::reporting::{
+    conversion_pipeline, revenue_pipeline, to_csv, top_donors_pipeline, ConversionRow, CsvRow,
+    ReportPeriod, ReportRange, RevenueRow, TopDonorRow,
+};
+use axum::extract::Query;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::NaiveDate;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+const TOP_DONORS_DEFAULT_LIMIT: i64 = 50;
+const TOP_DONORS_MAX_LIMIT: i64 = 1000;
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReportQuery {
+    #[serde(default)]
+    period: ReportPeriod,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    #[serde(default)]
+    format: ReportFormat,
+    limit: Option<i64>,
+}
+
+impl ReportQuery {
+    fn range(&self) -> ReportRange {
+        ReportRange {
+            from: self.from,
+            to: self.to,
+        }
+    }
+}
+
+pub async fn get_revenue_report(_: AdminAuth, Query(query): Query<ReportQuery>) -> Response {
+    let pipeline = revenue_pipeline(query.period, query.range());
+
+    report_response::<RevenueRow>("revenue", pipeline, query.format).await
+}
+
+pub async fn get_conversion_report(_: AdminAuth, Query(query): Query<ReportQuery>) -> Response {
+    let pipeline = conversion_pipeline(query.period, query.range());
+
+    report_response::<ConversionRow>("conversion", pipeline, query.format).await
+}
+
+pub async fn get_top_donors_report(_: AdminAuth, Query(query): Query<ReportQuery>) -> Response {
+    let limit = query
+        .limit
+        .unwrap_or(TOP_DONORS_DEFAULT_LIMIT)
+        .clamp(1, TOP_DONORS_MAX_LIMIT);
+
+    let pipeline = top_donors_pipeline(query.range(), limit);
+
+    report_response::<TopDonorRow>("top_donors", pipeline, query.format).await
+}
+
+async fn report_response<T: CsvRow + Serialize + DeserializeOwned>(
+    name: &str,
+    pipeline: Vec<mongodb::bson::Document>,
+    format: ReportFormat,
+) -> Response {
+    let rows: Vec<T> = match get_db().await.aggregate_invoices(pipeline).await {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Err on build {name} report {e:#?}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    match format {
+        ReportFormat::Json => Json(rows).into_response(),
+        ReportFormat::Csv => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{name}.csv\""),
+                ),
+            ],
+            to_csv(&rows),
+        )
+            .into_response(),
+    }
+}
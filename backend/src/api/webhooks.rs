@@ -1,41 +1,72 @@
-use axum::extract::ConnectInfo;
+use axum::body::Bytes;
 use axum::http::{HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
-use axum::{Form, Json};
-use serde_json::Value;
-use std::net::SocketAddr;
+use axum::Form;
 
+use crate::api::client_ip::{is_allowed, ClientIp};
 use crate::invoice_handler::{ServiceInvoiceUpdate, INVOICE_HANDLER};
-use crate::pay_services::{hotskins, paypalich};
+use crate::pay_services::{hotskins, paypalich, ProceedInvoiceError};
 use crate::CONFIG;
 
+/**
+Ошибки в данных от платёжки - 4xx, чтобы она не ретраила заведомо плохой запрос.
+Всё остальное - 500
+ */
+fn error_response(e: &anyhow::Error) -> Response {
+    match e.downcast_ref::<ProceedInvoiceError>() {
+        Some(ProceedInvoiceError::InvalidSignature) => StatusCode::UNAUTHORIZED.into_response(),
+        Some(
+            ProceedInvoiceError::MalformedBody(_)
+            | ProceedInvoiceError::StaleCallback
+            | ProceedInvoiceError::UnsupportedCurrency { .. }
+            | ProceedInvoiceError::AlreadyPaid(_)
+            | ProceedInvoiceError::MetadataMismatch { .. }
+            | ProceedInvoiceError::WrongService { .. },
+        ) => StatusCode::BAD_REQUEST.into_response(),
+        _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
 pub async fn enot_invoice_webhook(
-    ConnectInfo(client_ip): ConnectInfo<SocketAddr>,
+    ClientIp(client_ip): ClientIp,
     headers: HeaderMap,
-    body: Json<Value>,
+    body: Bytes,
 ) -> Response {
-    if !CONFIG.enot_allowed_ips.contains(&client_ip.ip()) {
+    if !is_allowed(&CONFIG.enot_allowed_ips, client_ip) {
+        println!("enot webhook from not allowed ip {client_ip}");
         return StatusCode::FORBIDDEN.into_response();
     }
 
-    let Some(hash) = headers.get("x-api-sha256-signature") else {
+    let Some(hash) = headers
+        .get("x-api-sha256-signature")
+        .and_then(|v| v.to_str().ok())
+    else {
         return StatusCode::NOT_ACCEPTABLE.into_response();
     };
 
-    let Ok(_) = INVOICE_HANDLER
+    if let Err(e) = INVOICE_HANDLER
         .handle_invoice_update(ServiceInvoiceUpdate::Enot {
             body,
-            hash: hash.to_str().unwrap().to_string(),
+            hash: hash.to_string(),
         })
         .await
-    else {
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    {
+        println!("enot webhook err {e:#?}");
+        return error_response(&e);
     };
 
     StatusCode::OK.into_response()
 }
 
-pub async fn paypalich_invoice_webhook(Form(data): Form<paypalich::InvoiceUpdate>) -> Response {
+pub async fn paypalich_invoice_webhook(
+    ClientIp(client_ip): ClientIp,
+    Form(data): Form<paypalich::InvoiceUpdate>,
+) -> Response {
+    if !is_allowed(&CONFIG.paypalich_allowed_ips, client_ip) {
+        println!("paypalich webhook from not allowed ip {client_ip}");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
     println!("paypalich {:#?}", data);
 
     let res = INVOICE_HANDLER
@@ -44,14 +75,22 @@ pub async fn paypalich_invoice_webhook(Form(data): Form<paypalich::InvoiceUpdate
 
     println!("paypalich res {:#?}", res);
 
-    let Ok(_) = res else {
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    if let Err(e) = res {
+        return error_response(&e);
     };
 
     StatusCode::OK.into_response()
 }
 
-pub async fn paypalich_uk_invoice_webhook(Form(data): Form<paypalich::InvoiceUpdate>) -> Response {
+pub async fn paypalich_uk_invoice_webhook(
+    ClientIp(client_ip): ClientIp,
+    Form(data): Form<paypalich::InvoiceUpdate>,
+) -> Response {
+    if !is_allowed(&CONFIG.paypalich_uk_allowed_ips, client_ip) {
+        println!("paypalich_uk webhook from not allowed ip {client_ip}");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
     println!("paypalich_uk {:#?}", data);
 
     let res = INVOICE_HANDLER
@@ -60,19 +99,27 @@ pub async fn paypalich_uk_invoice_webhook(Form(data): Form<paypalich::InvoiceUpd
 
     println!("paypalich_uk res {:#?}", res);
 
-    let Ok(_) = res else {
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    if let Err(e) = res {
+        return error_response(&e);
     };
 
     StatusCode::OK.into_response()
 }
 
-pub async fn hotskins_invoice_webhook(Form(data): Form<hotskins::InvoiceUpdate>) -> Response {
-    let Ok(_) = INVOICE_HANDLER
+pub async fn hotskins_invoice_webhook(
+    ClientIp(client_ip): ClientIp,
+    Form(data): Form<hotskins::InvoiceUpdate>,
+) -> Response {
+    if !is_allowed(&CONFIG.hotskins_allowed_ips, client_ip) {
+        println!("hotskins webhook from not allowed ip {client_ip}");
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    if let Err(e) = INVOICE_HANDLER
         .handle_invoice_update(ServiceInvoiceUpdate::Hotskins { data })
         .await
-    else {
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    {
+        return error_response(&e);
     };
 
     StatusCode::OK.into_response()
@@ -0,0 +1,313 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::serde_helpers::uuid_1_as_binary;
+use serde::{Deserialize, Serialize};
+use shared::{CartLine, CatalogProduct, Currency, ProductItem};
+use std::collections::HashMap;
+use std::str::FromStr;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::get_db;
+
+pub const CRD_ID: u32 = 26352;
+/**
+Больше одной строкой корзины не купить
+ */
+pub const MAX_LINE_QUANTITY: u32 = 1000;
+/**
+Больше разных товаров в одной корзине не купить
+ */
+pub const MAX_CART_LINES: usize = 50;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Product {
+    #[serde(rename = "_id")]
+    #[serde(with = "uuid_1_as_binary")]
+    pub id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub items: Vec<ProductItem>,
+    pub prices: HashMap<Currency, f32>,
+    /**
+    Остаток на складе, `None` - без ограничений
+     */
+    pub stock: Option<u32>,
+    pub visible_from: Option<DateTime<Utc>>,
+    pub visible_until: Option<DateTime<Utc>>,
+}
+
+impl Product {
+    pub fn is_visible(&self, now: DateTime<Utc>) -> bool {
+        self.visible_from.is_none_or(|v| v <= now) && self.visible_until.is_none_or(|v| now < v)
+    }
+
+    pub fn to_catalog(&self) -> CatalogProduct {
+        CatalogProduct {
+            id: self.id.to_string(),
+            name: self.name.clone(),
+            description: self.description.clone(),
+            image: self.image.clone(),
+            items: self.items.clone(),
+            prices: self.prices.clone(),
+            stock: self.stock,
+        }
+    }
+}
+
+/**
+Строка счёта: товар, зарезервированный под конкретный счёт
+ */
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InvoiceLine {
+    #[serde(with = "uuid_1_as_binary")]
+    pub product_id: Uuid,
+    pub name: String,
+    pub quantity: u32,
+    pub price: f32,
+    pub items: Vec<ProductItem>,
+}
+
+impl InvoiceLine {
+    pub fn total(&self) -> f32 {
+        self.price * self.quantity as f32
+    }
+
+    /**
+    `None`, если количество предметов не помещается в `u32`
+     */
+    pub fn delayed_items(&self) -> Option<Vec<ProductItem>> {
+        self.items
+            .iter()
+            .map(|v| {
+                Some(ProductItem {
+                    item_id: v.item_id,
+                    count: v.count.checked_mul(self.quantity)?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ProductError {
+    #[error("Product not found")]
+    NotFound,
+
+    #[error("Product is not sold for {0}")]
+    NoPrice(Currency),
+
+    #[error("Product is out of stock")]
+    OutOfStock,
+
+    #[error("Wrong quantity")]
+    WrongQuantity,
+
+    #[error("Database error: {0}")]
+    Db(#[from] anyhow::Error),
+}
+
+/**
+Проверяет корзину и списывает товары со склада. Возвращает сумму в `currency`.
+При ошибке всё, что уже было списано, возвращается на склад.
+ */
+pub async fn reserve_cart(
+    cart: &[CartLine],
+    currency: Currency,
+) -> Result<(f32, Vec<InvoiceLine>), ProductError> {
+    let cart = merge_cart(cart)?;
+    let mut lines: Vec<InvoiceLine> = Vec::with_capacity(cart.len());
+
+    for line in &cart {
+        match reserve_line(line, currency).await {
+            Ok(v) => lines.push(v),
+            Err(e) => {
+                release_lines(&lines).await;
+                return Err(e);
+            }
+        }
+    }
+
+    Ok((lines.iter().map(InvoiceLine::total).sum(), lines))
+}
+
+/**
+Склеивает строки с одним товаром, чтобы повторами нельзя было обойти `MAX_LINE_QUANTITY`
+ */
+fn merge_cart(cart: &[CartLine]) -> Result<Vec<CartLine>, ProductError> {
+    let mut res: Vec<CartLine> = Vec::new();
+
+    for line in cart {
+        match res.iter_mut().find(|v| v.product_id == line.product_id) {
+            Some(v) => {
+                v.quantity = v
+                    .quantity
+                    .checked_add(line.quantity)
+                    .ok_or(ProductError::WrongQuantity)?
+            }
+            None => res.push(line.clone()),
+        }
+
+        if res.len() > MAX_CART_LINES {
+            return Err(ProductError::WrongQuantity);
+        }
+    }
+
+    Ok(res)
+}
+
+async fn reserve_line(line: &CartLine, currency: Currency) -> Result<InvoiceLine, ProductError> {
+    if line.quantity == 0 || line.quantity > MAX_LINE_QUANTITY {
+        return Err(ProductError::WrongQuantity);
+    }
+
+    let Ok(product_id) = Uuid::from_str(&line.product_id) else {
+        return Err(ProductError::NotFound);
+    };
+
+    let Some(product) = get_db().await.get_product_by_id(product_id).await? else {
+        return Err(ProductError::NotFound);
+    };
+
+    if !product.is_visible(Utc::now()) {
+        return Err(ProductError::NotFound);
+    }
+
+    let Some(price) = product.prices.get(&currency) else {
+        return Err(ProductError::NoPrice(currency));
+    };
+
+    let res = InvoiceLine {
+        product_id,
+        name: product.name,
+        quantity: line.quantity,
+        price: *price,
+        items: product.items,
+    };
+
+    if res.delayed_items().is_none() {
+        return Err(ProductError::WrongQuantity);
+    }
+
+    if product.stock.is_some()
+        && !get_db()
+            .await
+            .reserve_product_stock(product_id, line.quantity)
+            .await?
+    {
+        return Err(ProductError::OutOfStock);
+    }
+
+    Ok(res)
+}
+
+pub async fn release_lines(lines: &[InvoiceLine]) {
+    for line in lines {
+        let _ = get_db()
+            .await
+            .release_product_stock(line.product_id, line.quantity)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge_cart, InvoiceLine, Product, MAX_CART_LINES};
+    use chrono::{Duration, Utc};
+    use shared::{CartLine, ProductItem};
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_visibility_window() {
+        let now = Utc::now();
+        let mut product = Product {
+            id: Uuid::new_v4(),
+            name: "Starter pack".to_string(),
+            description: None,
+            image: None,
+            items: vec![],
+            prices: HashMap::new(),
+            stock: None,
+            visible_from: None,
+            visible_until: None,
+        };
+
+        assert!(product.is_visible(now));
+
+        product.visible_from = Some(now + Duration::hours(1));
+        assert!(!product.is_visible(now));
+
+        product.visible_from = Some(now - Duration::hours(1));
+        product.visible_until = Some(now);
+        assert!(!product.is_visible(now));
+
+        product.visible_until = Some(now + Duration::hours(1));
+        assert!(product.is_visible(now));
+    }
+
+    #[test]
+    fn test_line_delayed_items() {
+        let line = InvoiceLine {
+            product_id: Uuid::new_v4(),
+            name: "Starter pack".to_string(),
+            quantity: 3,
+            price: 150.0,
+            items: vec![
+                ProductItem {
+                    item_id: 57,
+                    count: 1000,
+                },
+                ProductItem {
+                    item_id: 1538,
+                    count: 1,
+                },
+            ],
+        };
+
+        assert_eq!(line.total(), 450.0);
+        assert_eq!(
+            line.delayed_items(),
+            Some(vec![
+                ProductItem {
+                    item_id: 57,
+                    count: 3000,
+                },
+                ProductItem {
+                    item_id: 1538,
+                    count: 3,
+                },
+            ])
+        );
+
+        let line = InvoiceLine {
+            quantity: 5,
+            items: vec![ProductItem {
+                item_id: 57,
+                count: u32::MAX / 4,
+            }],
+            ..line
+        };
+
+        assert_eq!(line.delayed_items(), None);
+    }
+
+    #[test]
+    fn test_merge_cart() {
+        let line = |product_id: &str, quantity| CartLine {
+            product_id: product_id.to_string(),
+            quantity,
+        };
+
+        let cart = merge_cart(&[line("a", 600), line("b", 1), line("a", 600)]).unwrap();
+        assert_eq!(cart, vec![line("a", 1200), line("b", 1)]);
+
+        assert!(merge_cart(&[line("a", u32::MAX), line("a", 1)]).is_err());
+
+        let cart: Vec<CartLine> = (0..=MAX_CART_LINES)
+            .map(|v| line(&v.to_string(), 1))
+            .collect();
+        assert!(merge_cart(&cart[1..]).is_ok());
+        assert!(merge_cart(&cart).is_err());
+    }
+}
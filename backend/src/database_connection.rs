@@ -1,15 +1,28 @@
 use anyhow::Result;
-use chrono::Utc;
+use chrono::{DateTime, SecondsFormat, Utc};
 use futures::TryStreamExt;
-use mongodb::bson::{doc, serde_helpers::uuid_1_as_binary, to_document};
-use mongodb::options::ClientOptions;
-use mongodb::{bson, Client, Database};
+use mongodb::bson::{doc, serde_helpers::uuid_1_as_binary, to_document, Document};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{
+    ClientOptions, FindOneAndUpdateOptions, FindOptions, IndexOptions, ReturnDocument,
+    UpdateOptions,
+};
+use mongodb::{bson, Client, Database, IndexModel};
+use serde::de::DeserializeOwned;
 use serde::Serialize;
+use shared::{CharacterInfo, PaymentServices, ProductItem};
 use sqlx::mysql::{MySqlConnectOptions, MySqlPoolOptions};
 use sqlx::{Error, MySql, Pool};
 use uuid::Uuid;
 
-use crate::invoice_handler::{Invoice, InvoiceData};
+use crate::catalog::Product;
+use crate::invoice_handler::{Invoice, InvoiceData, InvoiceSettlement, ProviderTransaction};
+use crate::jobs::Job;
+use crate::mailer::OutgoingEmail;
+use crate::outgoing_webhooks::{OutgoingWebhook, WebhookSubscriber};
+use crate::pay_services::paypalich::PaypalichAccounting;
+use crate::reconciliation::ReconciliationReport;
+use crate::reporting::ReportRange;
 use crate::vote_services::VoteOptions;
 use crate::CONFIG;
 
@@ -31,6 +44,27 @@ pub enum DbResponse<T> {
     Err,
 }
 
+#[derive(sqlx::FromRow)]
+struct CharacterRow {
+    obj_id: i32,
+    char_name: String,
+    level: i64,
+    class_id: i64,
+    clan_name: Option<String>,
+    online: i64,
+    accesslevel: i64,
+    /**
+    Уровень доступа аккаунта персонажа, отрицательный у заблокированных
+     */
+    account_accesslevel: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct L2Character {
+    pub obj_id: i32,
+    pub info: CharacterInfo,
+}
+
 impl DatabaseConnection {
     pub async fn validate_connections(&mut self) {
         if self.l2_database.is_closed() {
@@ -58,31 +92,82 @@ impl DatabaseConnection {
         }
     }
 
-    pub async fn add_crd_to_delayed(
+    pub async fn get_char_by_name(&self, char_name: &str) -> Result<DbResponse<L2Character>> {
+        let query: Result<CharacterRow, _> = sqlx::query_as(
+            "SELECT c.obj_id, c.char_name, CAST(c.level AS SIGNED) AS level, CAST(c.class_id AS SIGNED) AS class_id, \
+             cd.clan_name, CAST(c.online AS SIGNED) AS online, CAST(c.accesslevel AS SIGNED) AS accesslevel, \
+             CAST(COALESCE(a.accessLevel, 0) AS SIGNED) AS account_accesslevel \
+             FROM characters c LEFT JOIN clan_data cd ON cd.clan_id = c.clanid \
+             LEFT JOIN accounts a ON a.login = c.account_name WHERE c.char_name = ?",
+        )
+        .bind(char_name)
+        .fetch_one(&self.l2_database)
+        .await;
+
+        match query {
+            Ok(v) => Ok(DbResponse::NotFound(L2Character {
+                obj_id: v.obj_id,
+                info: CharacterInfo {
+                    name: v.char_name,
+                    level: v.level as u8,
+                    class_id: v.class_id as i32,
+                    clan: v.clan_name,
+                    online: v.online > 0,
+                    banned: v.accesslevel < 0 || v.account_accesslevel < 0,
+                },
+            })),
+            Err(e) => match e {
+                Error::RowNotFound => Ok(DbResponse::Err),
+                _ => Err(anyhow::Error::from(e)),
+            },
+        }
+    }
+
+    /**
+    Кладёт товары счёта в `items_delayed`. Если товары по этому счёту уже лежат там,
+    второй раз не кладёт и возвращает `false`
+     */
+    pub async fn add_items_to_delayed(
         &self,
         char_id: i32,
-        char_name: &str,
-        count: u32,
+        description: &str,
+        items: &[ProductItem],
         order_id: Uuid,
         service: &str,
-    ) -> Result<()> {
-        const CRD_ID: u32 = 26352;
+    ) -> Result<bool> {
+        let mut tx = self.l2_database.begin().await?;
 
-        sqlx::query(
-            "INSERT INTO items_delayed (owner_id, item_id, count, payment_status, description, time, outer_id, outer_service) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+        let (delivered,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM items_delayed WHERE outer_id = ? AND outer_service = ? FOR UPDATE",
         )
-            .bind(char_id)
-            .bind(CRD_ID)
-            .bind(count)
-            .bind(0)
-            .bind(char_name)
-            .bind(get_current_time())
-            .bind(order_id.to_string())
-            .bind(service)
-            .execute(&self.l2_database)
-            .await?;
+        .bind(order_id.to_string())
+        .bind(service)
+        .fetch_one(&mut *tx)
+        .await?;
 
-        Ok(())
+        if delivered > 0 {
+            return Ok(false);
+        }
+
+        for item in items {
+            sqlx::query(
+                "INSERT INTO items_delayed (owner_id, item_id, count, payment_status, description, time, outer_id, outer_service) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+            )
+                .bind(char_id)
+                .bind(item.item_id)
+                .bind(item.count)
+                .bind(0)
+                .bind(description)
+                .bind(get_current_time())
+                .bind(order_id.to_string())
+                .bind(service)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(true)
     }
 
     pub async fn add_vote_to_delayed(
@@ -111,13 +196,116 @@ impl DatabaseConnection {
         Ok(())
     }
 
-    pub async fn get_all_invoices(&self) -> Vec<Invoice> {
+    /**
+    Агрегация по коллекции счетов для отчётов, строки разбираются в `T`
+     */
+    pub async fn aggregate_invoices<T: DeserializeOwned>(
+        &self,
+        pipeline: Vec<Document>,
+    ) -> Result<Vec<T>> {
         let collection = self.database.collection::<Invoice>("invoice");
-        let res = collection.find(None, None).await.unwrap();
+        let res = collection.aggregate(pipeline, None).await?;
 
-        let res: Vec<Invoice> = res.try_collect().await.unwrap();
+        let docs: Vec<Document> = res.try_collect().await?;
 
-        res
+        Ok(docs
+            .into_iter()
+            .map(bson::from_document)
+            .collect::<Result<_, _>>()?)
+    }
+
+    pub async fn get_paid_invoices(
+        &self,
+        service: PaymentServices,
+        range: ReportRange,
+    ) -> Result<Vec<Invoice>> {
+        let collection = self.database.collection::<Invoice>("invoice");
+
+        let mut filter = range.to_filter("created_at");
+        filter.insert("service", bson::to_bson(&service)?);
+        filter.insert("data.Payed", doc! {"$exists": true});
+
+        let res = collection.find(filter, None).await?;
+
+        Ok(res.try_collect().await?)
+    }
+
+    /**
+    Счета сервиса в любом статусе по id платёжки или нашему id
+     */
+    pub async fn get_invoices_by_external_ids(
+        &self,
+        service: PaymentServices,
+        external_ids: Vec<String>,
+        order_ids: Vec<Uuid>,
+    ) -> Result<Vec<Invoice>> {
+        let collection = self.database.collection::<Invoice>("invoice");
+
+        let order_ids = order_ids
+            .into_iter()
+            .map(|v| bson::Binary::from_uuid(v.into()))
+            .collect::<Vec<_>>();
+
+        let filter = doc! {
+            "service": bson::to_bson(&service)?,
+            "$or": [
+                {"data.WaitingForPayment.external_id": {"$in": &external_ids}},
+                {"data.Aborted.external_id": {"$in": &external_ids}},
+                {"data.Payed.external_id": {"$in": &external_ids}},
+                {"data.Refunded.external_id": {"$in": &external_ids}},
+                {"_id": {"$in": order_ids}},
+            ]
+        };
+
+        let res = collection.find(filter, None).await?;
+
+        Ok(res.try_collect().await?)
+    }
+
+    pub async fn create_reconciliation_report(&self, report: &ReconciliationReport) -> Result<()> {
+        let collection = self
+            .database
+            .collection::<ReconciliationReport>("reconciliation_report");
+        collection.insert_one(report, None).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_reconciliation_reports(&self) -> Result<Vec<ReconciliationReport>> {
+        let collection = self
+            .database
+            .collection::<ReconciliationReport>("reconciliation_report");
+        let options = FindOptions::builder()
+            .sort(doc! {"created_at": -1})
+            .build();
+        let res = collection.find(None, options).await?;
+
+        Ok(res.try_collect().await?)
+    }
+
+    pub async fn get_reconciliation_report(
+        &self,
+        report_id: Uuid,
+    ) -> Result<Option<ReconciliationReport>> {
+        let collection = self
+            .database
+            .collection::<ReconciliationReport>("reconciliation_report");
+
+        let search = to_document(&MongoIdDoc { id: report_id })?;
+
+        Ok(collection.find_one(search, None).await?)
+    }
+
+    pub async fn update_reconciliation_report(&self, report: &ReconciliationReport) -> Result<()> {
+        let collection = self
+            .database
+            .collection::<ReconciliationReport>("reconciliation_report");
+
+        let search = to_document(&MongoIdDoc { id: report.id })?;
+
+        collection.replace_one(search, report, None).await?;
+
+        Ok(())
     }
 
     pub async fn get_unfinished_payed_invoices(&self) -> Vec<Invoice> {
@@ -132,6 +320,47 @@ impl DatabaseConnection {
         res
     }
 
+    pub async fn get_products(&self) -> Result<Vec<Product>> {
+        let collection = self.database.collection::<Product>("product");
+        let res = collection.find(None, None).await?;
+
+        Ok(res.try_collect().await?)
+    }
+
+    pub async fn get_product_by_id(&self, product_id: Uuid) -> Result<Option<Product>> {
+        let collection = self.database.collection::<Product>("product");
+
+        let search = to_document(&MongoIdDoc { id: product_id })?;
+
+        Ok(collection.find_one(search, None).await?)
+    }
+
+    pub async fn reserve_product_stock(&self, product_id: Uuid, count: u32) -> Result<bool> {
+        let collection = self.database.collection::<Product>("product");
+
+        let mut search = to_document(&MongoIdDoc { id: product_id })?;
+        search.insert("stock", doc! {"$gte": count});
+
+        let res = collection
+            .update_one(search, doc! {"$inc": {"stock": -(count as i64)}}, None)
+            .await?;
+
+        Ok(res.modified_count == 1)
+    }
+
+    pub async fn release_product_stock(&self, product_id: Uuid, count: u32) -> Result<()> {
+        let collection = self.database.collection::<Product>("product");
+
+        let mut search = to_document(&MongoIdDoc { id: product_id })?;
+        search.insert("stock", doc! {"$ne": null});
+
+        collection
+            .update_one(search, doc! {"$inc": {"stock": count as i64}}, None)
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn get_vote_options(&self) -> VoteOptions {
         let collection = self.database.collection::<VoteOptions>("vote_options");
         let res = collection.find(doc! {}, None).await.unwrap();
@@ -143,7 +372,7 @@ impl DatabaseConnection {
             return VoteOptions::default();
         }
 
-        *res.get(0).unwrap()
+        *res.first().unwrap()
     }
 
     pub async fn update_last_mmotop_id(&self, id: u32, last_mmotop_id: u32) -> Result<()> {
@@ -168,6 +397,98 @@ impl DatabaseConnection {
         collection.find_one(search, None).await.unwrap()
     }
 
+    /**
+    Забирает оплаченный, но не выданный счёт под выдачу до `locked_until`. Пока аренда
+    не истекла, другой воркер этот счёт не получит
+     */
+    pub async fn claim_invoice_delivery(
+        &self,
+        invoice_id: Uuid,
+        now: i64,
+        locked_until: i64,
+    ) -> Result<Option<Invoice>> {
+        let collection = self.database.collection::<Invoice>("invoice");
+
+        let mut search = to_document(&MongoIdDoc { id: invoice_id })?;
+        search.insert("data.Payed.stored_in_l2_db", false);
+        search.insert(
+            "$or",
+            vec![
+                doc! {"delivery_locked_until": {"$exists": false}},
+                doc! {"delivery_locked_until": {"$lte": now}},
+            ],
+        );
+
+        let res = collection
+            .find_one_and_update(
+                search,
+                doc! {"$set": {"delivery_locked_until": locked_until}},
+                FindOneAndUpdateOptions::builder()
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?;
+
+        Ok(res)
+    }
+
+    /**
+    Снимает аренду выдачи, чтобы повтор не ждал её истечения
+     */
+    pub async fn release_invoice_delivery(&self, invoice_id: Uuid) -> Result<()> {
+        let collection = self.database.collection::<Invoice>("invoice");
+
+        let search = to_document(&MongoIdDoc { id: invoice_id })?;
+
+        collection
+            .update_one(search, doc! {"$unset": {"delivery_locked_until": ""}}, None)
+            .await?;
+
+        Ok(())
+    }
+
+    /**
+    Отмечает счёт выданным. Если пока шла выдача счёт вернули, статус возврата не
+    перезаписывается, в нём только отмечается, что товары выданы. Тогда возвращает `false`
+     */
+    pub async fn finish_invoice_delivery(&self, invoice_id: Uuid) -> Result<bool> {
+        let collection = self.database.collection::<Invoice>("invoice");
+
+        let mut search = to_document(&MongoIdDoc { id: invoice_id })?;
+        search.insert("data.Payed.stored_in_l2_db", false);
+
+        let res = collection
+            .update_one(
+                search,
+                doc! {
+                    "$set": {"data.Payed.stored_in_l2_db": true, "updated_at": bson::to_bson(&Utc::now())?},
+                    "$unset": {"delivery_locked_until": ""},
+                },
+                None,
+            )
+            .await?;
+
+        if res.modified_count > 0 {
+            return Ok(true);
+        }
+
+        let mut search = to_document(&MongoIdDoc { id: invoice_id })?;
+        search.insert("data.Refunded", doc! {"$exists": true});
+
+        collection
+            .update_one(
+                search,
+                doc! {
+                    "$set": {"data.Refunded.delivered": true, "updated_at": bson::to_bson(&Utc::now())?},
+                    "$unset": {"delivery_locked_until": ""},
+                },
+                None,
+            )
+            .await?;
+
+        Ok(false)
+    }
+
     pub async fn update_invoice_data(&self, invoice_id: Uuid, data: InvoiceData) -> Result<()> {
         let collection = self.database.collection::<Invoice>("invoice");
 
@@ -176,7 +497,7 @@ impl DatabaseConnection {
         collection
             .update_one(
                 search,
-                doc! {"$set": {"data": bson::to_bson(&data).unwrap()}},
+                doc! {"$set": {"data": bson::to_bson(&data)?, "updated_at": bson::to_bson(&Utc::now())?}},
                 None,
             )
             .await?;
@@ -184,6 +505,54 @@ impl DatabaseConnection {
         Ok(())
     }
 
+    /**
+    Неоплаченные счета с товарами, созданные раньше `created_before`
+     */
+    pub async fn get_expired_cart_invoices(
+        &self,
+        created_before: DateTime<Utc>,
+    ) -> Result<Vec<Invoice>> {
+        let collection = self.database.collection::<Invoice>("invoice");
+
+        let res = collection
+            .find(
+                doc! {
+                    "data.WaitingForPayment": {"$exists": true},
+                    "lines.0": {"$exists": true},
+                    "created_at": {"$lt": created_before.to_rfc3339_opts(SecondsFormat::Secs, true)},
+                },
+                None,
+            )
+            .await?;
+
+        Ok(res.try_collect().await?)
+    }
+
+    /**
+    Меняет данные счёта, только если он всё ещё ждёт оплаты. `false`, если статус
+    уже сменил webhook
+     */
+    pub async fn update_waiting_invoice_data(
+        &self,
+        invoice_id: Uuid,
+        data: InvoiceData,
+    ) -> Result<bool> {
+        let collection = self.database.collection::<Invoice>("invoice");
+
+        let mut search = to_document(&MongoIdDoc { id: invoice_id })?;
+        search.insert("data.WaitingForPayment", doc! {"$exists": true});
+
+        let res = collection
+            .update_one(
+                search,
+                doc! {"$set": {"data": bson::to_bson(&data)?, "updated_at": bson::to_bson(&Utc::now())?}},
+                None,
+            )
+            .await?;
+
+        Ok(res.modified_count > 0)
+    }
+
     pub async fn update_invoice_data_and_amount(
         &self,
         invoice_id: Uuid,
@@ -197,7 +566,59 @@ impl DatabaseConnection {
         collection
             .update_one(
                 search,
-                doc! {"$set": {"data": bson::to_bson(&data).unwrap(), "amount": amount}},
+                doc! {"$set": {"data": bson::to_bson(&data)?, "amount": amount, "updated_at": bson::to_bson(&Utc::now())?}},
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_invoice_payer_steam_id(&self, invoice_id: Uuid, steam_id: String) -> Result<()> {
+        let collection = self.database.collection::<Invoice>("invoice");
+
+        let search = to_document(&MongoIdDoc { id: invoice_id }).unwrap();
+
+        collection
+            .update_one(search, doc! {"$set": {"payer_steam_id": steam_id}}, None)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_invoice_settlement(
+        &self,
+        invoice_id: Uuid,
+        settlement: InvoiceSettlement,
+    ) -> Result<()> {
+        let collection = self.database.collection::<Invoice>("invoice");
+
+        let search = to_document(&MongoIdDoc { id: invoice_id }).unwrap();
+
+        collection
+            .update_one(
+                search,
+                doc! {"$set": {"settlement": bson::to_bson(&settlement)?}},
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn push_invoice_paypalich_accounting(
+        &self,
+        invoice_id: Uuid,
+        accounting: PaypalichAccounting,
+    ) -> Result<()> {
+        let collection = self.database.collection::<Invoice>("invoice");
+
+        let search = to_document(&MongoIdDoc { id: invoice_id }).unwrap();
+
+        collection
+            .update_one(
+                search,
+                doc! {"$push": {"paypalich_accounting": bson::to_bson(&accounting)?}},
                 None,
             )
             .await?;
@@ -210,6 +631,267 @@ impl DatabaseConnection {
         collection.insert_one(rec, None).await.unwrap();
     }
 
+    /**
+    `false`, если такой webhook уже был обработан
+     */
+    pub async fn record_provider_transaction(
+        &self,
+        transaction: &ProviderTransaction,
+    ) -> Result<bool> {
+        let collection = self
+            .database
+            .collection::<ProviderTransaction>("provider_transaction");
+
+        match collection.insert_one(transaction, None).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn delete_provider_transaction(
+        &self,
+        transaction: &ProviderTransaction,
+    ) -> Result<()> {
+        let collection = self
+            .database
+            .collection::<ProviderTransaction>("provider_transaction");
+
+        collection
+            .delete_one(
+                doc! {
+                    "service": bson::to_bson(&transaction.service)?,
+                    "transaction_id": &transaction.transaction_id,
+                    "kind": &transaction.kind,
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn create_outgoing_email(&self, email: OutgoingEmail) -> Result<()> {
+        let collection = self.database.collection::<OutgoingEmail>("email_outbox");
+        collection.insert_one(email, None).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_pending_emails(&self, now: i64, max_attempts: u32) -> Result<Vec<OutgoingEmail>> {
+        let collection = self.database.collection::<OutgoingEmail>("email_outbox");
+        let res = collection
+            .find(
+                doc! {
+                    "sent": false,
+                    "attempts": {"$lt": max_attempts},
+                    "next_try_at": {"$lte": now},
+                },
+                None,
+            )
+            .await?;
+
+        Ok(res.try_collect().await?)
+    }
+
+    pub async fn mark_email_sent(&self, email_id: Uuid) -> Result<()> {
+        let collection = self.database.collection::<OutgoingEmail>("email_outbox");
+
+        let search = to_document(&MongoIdDoc { id: email_id })?;
+
+        collection
+            .update_one(search, doc! {"$set": {"sent": true}}, None)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn mark_email_failed(&self, email_id: Uuid, next_try_at: i64) -> Result<()> {
+        let collection = self.database.collection::<OutgoingEmail>("email_outbox");
+
+        let search = to_document(&MongoIdDoc { id: email_id })?;
+
+        collection
+            .update_one(
+                search,
+                doc! {"$inc": {"attempts": 1}, "$set": {"next_try_at": next_try_at}},
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_webhook_subscribers(&self) -> Result<Vec<WebhookSubscriber>> {
+        let collection = self
+            .database
+            .collection::<WebhookSubscriber>("webhook_subscriber");
+        let res = collection.find(None, None).await?;
+
+        Ok(res.try_collect().await?)
+    }
+
+    pub async fn create_outgoing_webhook(&self, webhook: OutgoingWebhook) -> Result<()> {
+        let collection = self.database.collection::<OutgoingWebhook>("webhook_outbox");
+        collection.insert_one(webhook, None).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_pending_webhooks(&self, now: i64) -> Result<Vec<OutgoingWebhook>> {
+        let collection = self.database.collection::<OutgoingWebhook>("webhook_outbox");
+        let res = collection
+            .find(doc! {"next_try_at": {"$lte": now}}, None)
+            .await?;
+
+        Ok(res.try_collect().await?)
+    }
+
+    pub async fn update_outgoing_webhook(&self, webhook: OutgoingWebhook) -> Result<()> {
+        let collection = self.database.collection::<OutgoingWebhook>("webhook_outbox");
+
+        let search = to_document(&MongoIdDoc { id: webhook.id })?;
+
+        collection.replace_one(search, webhook, None).await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_outgoing_webhook(&self, webhook_id: Uuid) -> Result<()> {
+        let collection = self.database.collection::<OutgoingWebhook>("webhook_outbox");
+
+        let search = to_document(&MongoIdDoc { id: webhook_id })?;
+
+        collection.delete_one(search, None).await?;
+
+        Ok(())
+    }
+
+    pub async fn move_webhook_to_dead_letter(&self, webhook: OutgoingWebhook) -> Result<()> {
+        let dead_letter = self
+            .database
+            .collection::<OutgoingWebhook>("webhook_dead_letter");
+
+        let webhook_id = webhook.id;
+        dead_letter.insert_one(webhook, None).await?;
+
+        self.delete_outgoing_webhook(webhook_id).await
+    }
+
+    /**
+    `false`, если задача с таким ключом уже стоит в очереди
+     */
+    pub async fn create_job(&self, job: Job) -> Result<bool> {
+        let collection = self.database.collection::<Job>("job");
+
+        match collection.insert_one(job, None).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /**
+    Создаёт задачу, только если задачи с таким ключом ещё нет. Расписание уже стоящей
+    не трогает
+     */
+    pub async fn ensure_job(&self, job: Job) -> Result<()> {
+        let collection = self.database.collection::<Job>("job");
+
+        collection
+            .update_one(
+                doc! {"key": &job.key},
+                doc! {"$setOnInsert": to_document(&job)?},
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /**
+    Забирает самую старую готовую к запуску задачу, свободную или с истёкшей арендой
+     */
+    pub async fn claim_job(
+        &self,
+        now: i64,
+        locked_until: i64,
+        worker_id: &str,
+    ) -> Result<Option<Job>> {
+        let collection = self.database.collection::<Job>("job");
+
+        let res = collection
+            .find_one_and_update(
+                doc! {
+                    "next_run_at": {"$lte": now},
+                    "locked_until": {"$lte": now},
+                },
+                doc! {"$set": {"locked_until": locked_until, "locked_by": worker_id}},
+                FindOneAndUpdateOptions::builder()
+                    .sort(doc! {"next_run_at": 1})
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await?;
+
+        Ok(res)
+    }
+
+    /**
+    Снимает аренду и назначает следующий запуск. Если аренда истекла и задачу забрал
+    другой воркер, ничего не меняет
+     */
+    pub async fn reschedule_job(
+        &self,
+        job_id: Uuid,
+        worker_id: &str,
+        attempts: u32,
+        next_run_at: i64,
+        last_error: Option<String>,
+    ) -> Result<()> {
+        let collection = self.database.collection::<Job>("job");
+
+        let mut search = to_document(&MongoIdDoc { id: job_id })?;
+        search.insert("locked_by", worker_id);
+
+        collection
+            .update_one(
+                search,
+                doc! {"$set": {
+                    "attempts": attempts,
+                    "next_run_at": next_run_at,
+                    "last_error": last_error,
+                    "locked_until": 0_i64,
+                    "locked_by": null,
+                }},
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_job(&self, job_id: Uuid, worker_id: &str) -> Result<()> {
+        let collection = self.database.collection::<Job>("job");
+
+        let mut search = to_document(&MongoIdDoc { id: job_id })?;
+        search.insert("locked_by", worker_id);
+
+        collection.delete_one(search, None).await?;
+
+        Ok(())
+    }
+
+    pub async fn move_job_to_dead_letter(&self, job: Job) -> Result<()> {
+        let dead_letter = self.database.collection::<Job>("job_dead_letter");
+
+        let job_id = job.id;
+        let worker_id = job.locked_by.clone().unwrap_or_default();
+        dead_letter.insert_one(job, None).await?;
+
+        self.delete_job(job_id, &worker_id).await
+    }
+
     fn get_l2_db_options() -> MySqlConnectOptions {
         MySqlConnectOptions::new()
             .host(&CONFIG.l2_db_path)
@@ -228,6 +910,42 @@ impl DatabaseConnection {
             .await
     }
 
+    async fn create_indexes(database: &Database) -> Result<()> {
+        database
+            .collection::<ProviderTransaction>("provider_transaction")
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"service": 1, "transaction_id": 1, "kind": 1})
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+
+        database
+            .collection::<Job>("job")
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"key": 1})
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+
+        database
+            .collection::<Job>("job")
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! {"next_run_at": 1})
+                    .build(),
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn new() -> Self {
         let mut client_options = ClientOptions::parse(&CONFIG.db_path).await.unwrap();
         client_options.app_name = Some("l2w_lk_app".to_string());
@@ -237,6 +955,8 @@ impl DatabaseConnection {
 
         let database = client.database("l2w_lk_payments_db");
 
+        Self::create_indexes(&database).await.unwrap();
+
         let options = Self::get_l2_db_options();
 
         let l2_database = MySqlPoolOptions::new()
@@ -252,6 +972,13 @@ impl DatabaseConnection {
     }
 }
 
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(v)) if v.code == 11000
+    )
+}
+
 fn get_current_time() -> String {
     format!(
         "{}",
@@ -1,21 +1,36 @@
 use anyhow::Result;
-use axum::Json;
-use chrono::{DateTime, Utc};
+use axum::body::Bytes;
+use chrono::{DateTime, Duration, Utc};
 use lazy_static::lazy_static;
 use mongodb::bson::serde_helpers::uuid_1_as_binary;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use shared::PaymentServices;
+use shared::{
+    Currency, CurrencyRate, InvoiceInfo, InvoiceLineInfo, InvoiceStatus, Locale, PayMethod,
+    PaymentError, PaymentServices, ProductItem,
+};
+use std::future::Future;
 use std::net::IpAddr;
-use std::time::SystemTime;
+use std::time::{Duration as StdDuration, Instant, SystemTime};
 use uuid::Uuid;
 
+use crate::catalog::{release_lines, InvoiceLine, CRD_ID};
 use crate::pay_services::enot::handler::EnotInvoiceHandler;
 use crate::pay_services::hotskins::handler::HotSkinsInvoiceHandler;
 use crate::pay_services::paypalich::handler::PaypalichInvoiceHandler;
-use crate::pay_services::{enot, hotskins, paypalich};
+use crate::pay_services::http::send_with_retry;
+use crate::pay_services::paypalich::PaypalichAccounting;
+use crate::provider_health::{is_failover_error, is_provider_fault, PROVIDER_HEALTH};
+use crate::notifier::{Notification, NOTIFIER};
+use crate::outgoing_webhooks::{dispatch_invoice_event, InvoiceEvent};
+use crate::pay_services::{
+    enot, hotskins, paypalich, CreateInvoiceError, InvoiceMetadata, ProceedInvoiceError, USD_RATE,
+};
+use crate::jobs::enqueue_delivery;
+use crate::mailer::{enqueue_receipt, ReceiptKind};
 use crate::{get_db, CONFIG};
 
+const DELAYED_DESCRIPTION_MAX_LEN: usize = 255;
+
 lazy_static! {
     pub static ref INVOICE_HANDLER: InvoiceHandler = InvoiceHandler::new();
 }
@@ -29,7 +44,7 @@ pub struct InvoiceHandler {
 }
 
 pub enum ServiceInvoiceUpdate {
-    Enot { body: Json<Value>, hash: String },
+    Enot { body: Bytes, hash: String },
     Hotskins { data: hotskins::InvoiceUpdate },
     Paypalich { data: paypalich::InvoiceUpdate },
     PaypalichUk { data: paypalich::InvoiceUpdate },
@@ -56,35 +71,167 @@ impl InvoiceHandler {
     }
 
     pub async fn handle_invoice_update(&self, data: ServiceInvoiceUpdate) -> Result<()> {
-        let invoice_update = match data {
-            ServiceInvoiceUpdate::Enot { body, hash } => {
-                self.enot.parse_invoice_status_update(body, &hash)?
-            }
-            ServiceInvoiceUpdate::Hotskins { data } => {
-                self.hotskins.parse_invoice_status_update(data)?
-            }
-            ServiceInvoiceUpdate::Paypalich { data } => {
-                self.paypalych.parse_invoice_status_update(data)?
+        let (service, invoice_update) = match data {
+            ServiceInvoiceUpdate::Enot { body, hash } => (
+                PaymentServices::Enot,
+                self.enot.parse_invoice_status_update(&body, &hash),
+            ),
+            ServiceInvoiceUpdate::Hotskins { data } => (
+                PaymentServices::Hotskins,
+                self.hotskins.parse_invoice_status_update(data),
+            ),
+            ServiceInvoiceUpdate::Paypalich { data } => (
+                PaymentServices::Paypalych,
+                self.paypalych.parse_invoice_status_update(data),
+            ),
+            ServiceInvoiceUpdate::PaypalichUk { data } => (
+                PaymentServices::PaypalychUk,
+                self.paypalych_uk.parse_invoice_status_update(data),
+            ),
+        };
+
+        let invoice_update = match invoice_update {
+            Ok(v) => v,
+            Err(e) => {
+                match e.downcast_ref() {
+                    Some(ProceedInvoiceError::InvalidSignature) => {
+                        NOTIFIER
+                            .notify(Notification::InvalidSignature { service })
+                            .await;
+                    }
+                    Some(ProceedInvoiceError::UnsupportedCurrency { .. }) => {
+                        NOTIFIER
+                            .notify(Notification::RejectedCallback {
+                                service,
+                                reason: e.to_string(),
+                            })
+                            .await;
+                    }
+                    _ => {}
+                }
+
+                return Err(e);
             }
-            ServiceInvoiceUpdate::PaypalichUk { data } => {
-                self.paypalych_uk.parse_invoice_status_update(data)?
+        };
+
+        if let Some(event_time) = invoice_update.event_time {
+            if is_stale(event_time, Utc::now(), CONFIG.webhook_max_age_hours) {
+                println!(
+                    "Stale {service} callback {} for order {}, event time {event_time}",
+                    invoice_update.transaction_id, invoice_update.order_id
+                );
+
+                return Err(ProceedInvoiceError::StaleCallback.into());
             }
+        }
+
+        let transaction = ProviderTransaction {
+            service,
+            transaction_id: invoice_update.transaction_id.clone(),
+            kind: invoice_update.data.kind().to_string(),
+            order_id: invoice_update.order_id,
+            received_at: Utc::now(),
         };
 
+        apply_once(
+            &MongoTransactionLog,
+            &transaction,
+            self.apply_invoice_update(service, invoice_update),
+        )
+        .await
+    }
+
+    async fn apply_invoice_update(
+        &self,
+        service: PaymentServices,
+        invoice_update: InvoiceStatusUpdate,
+    ) -> Result<()> {
         let Some(original_invoice) = get_db()
             .await
             .get_invoice_by_id(invoice_update.order_id)
             .await
         else {
+            // Счёт мог потеряться в базе - по метаданным его можно восстановить вручную
+            if let Some(metadata) = &invoice_update.metadata {
+                NOTIFIER
+                    .notify(Notification::RejectedCallback {
+                        service,
+                        reason: format!(
+                            "order {} not found, transaction {}, metadata {metadata:?}",
+                            invoice_update.order_id, invoice_update.transaction_id
+                        ),
+                    })
+                    .await;
+            }
+
             return Ok(());
         };
 
-        let update_res = match original_invoice.data {
+        if let Some(reason) = invoice_update
+            .metadata
+            .as_ref()
+            .and_then(|v| v.mismatch(&original_invoice))
+        {
+            let e = ProceedInvoiceError::MetadataMismatch {
+                order_id: original_invoice.id,
+                reason,
+            };
+
+            NOTIFIER
+                .notify(Notification::RejectedCallback {
+                    service: original_invoice.service,
+                    reason: e.to_string(),
+                })
+                .await;
+
+            return Err(e.into());
+        }
+
+        // После переключения на запасную платёжку счёт живёт только в одной из них
+        if original_invoice.service != service {
+            let e = ProceedInvoiceError::WrongService {
+                order_id: original_invoice.id,
+                service: original_invoice.service,
+            };
+
+            NOTIFIER
+                .notify(Notification::RejectedCallback {
+                    service,
+                    reason: format!("{e}, transaction {}", invoice_update.transaction_id),
+                })
+                .await;
+
+            return Err(e.into());
+        }
+
+        if original_invoice.service == PaymentServices::Hotskins
+            && !matches!(original_invoice.data, InvoiceData::WaitingForPayment { .. })
+        {
+            // Ссылка на оплату Hotskins многоразовая, повторное пополнение по уже
+            // закрытому заказу не начисляем автоматически
+            let e = ProceedInvoiceError::AlreadyPaid(original_invoice.id);
+
+            NOTIFIER
+                .notify(Notification::RejectedCallback {
+                    service: original_invoice.service,
+                    reason: format!("{e}, transaction {}", invoice_update.transaction_id),
+                })
+                .await;
+
+            return Err(e.into());
+        }
+
+        let update_res = match original_invoice.data.clone() {
             InvoiceData::WaitingForPayment { external_id, .. } => {
-                if external_id != invoice_update.external_id {
+                // У Hotskins id транзакции до оплаты неизвестен
+                if original_invoice.service != PaymentServices::Hotskins
+                    && external_id != invoice_update.external_id
+                {
                     return Ok(());
                 }
 
+                let external_id = invoice_update.external_id;
+
                 match invoice_update.data {
                     InvoiceStatusUpdateData::Payed => {
                         get_db()
@@ -98,7 +245,28 @@ impl InvoiceHandler {
                             )
                             .await
                     }
-                    InvoiceStatusUpdateData::PayedWithChangedSum { new_amount } => {
+                    InvoiceStatusUpdateData::PayedWithChangedSum { new_amount, .. }
+                        if !original_invoice.lines.is_empty()
+                            && new_amount < original_invoice.amount =>
+                    {
+                        release_invoice_stock(&original_invoice).await;
+
+                        get_db()
+                            .await
+                            .update_invoice_data(
+                                original_invoice.id,
+                                InvoiceData::Aborted {
+                                    reason: format!(
+                                        "Underpaid: {new_amount} of {}",
+                                        original_invoice.amount
+                                    ),
+                                    external_id,
+                                },
+                            )
+                            .await
+                    }
+                    // Товары считаются в валюте счёта, CRD - в рублях
+                    InvoiceStatusUpdateData::PayedWithChangedSum { new_amount, scale } => {
                         get_db()
                             .await
                             .update_invoice_data_and_amount(
@@ -107,7 +275,25 @@ impl InvoiceHandler {
                                     stored_in_l2_db: false,
                                     external_id,
                                 },
-                                new_amount,
+                                if original_invoice.lines.is_empty() {
+                                    new_amount * scale
+                                } else {
+                                    new_amount
+                                },
+                            )
+                            .await
+                    }
+                    InvoiceStatusUpdateData::PayedWithScaleSum { .. }
+                        if !original_invoice.lines.is_empty() =>
+                    {
+                        get_db()
+                            .await
+                            .update_invoice_data(
+                                original_invoice.id,
+                                InvoiceData::Payed {
+                                    stored_in_l2_db: false,
+                                    external_id,
+                                },
                             )
                             .await
                     }
@@ -125,6 +311,8 @@ impl InvoiceHandler {
                             .await
                     }
                     InvoiceStatusUpdateData::Aborted { reason } => {
+                        release_invoice_stock(&original_invoice).await;
+
                         get_db()
                             .await
                             .update_invoice_data(
@@ -136,169 +324,398 @@ impl InvoiceHandler {
                             )
                             .await
                     }
-                    InvoiceStatusUpdateData::None => {
+                    InvoiceStatusUpdateData::None | InvoiceStatusUpdateData::Refunded { .. } => {
                         return Ok(());
                     }
                 }
             }
+            InvoiceData::Payed {
+                stored_in_l2_db,
+                external_id,
+            } => {
+                if external_id != invoice_update.external_id {
+                    return Ok(());
+                }
+
+                let InvoiceStatusUpdateData::Refunded {
+                    amount,
+                    chargeback,
+                    reason,
+                } = invoice_update.data
+                else {
+                    return Ok(());
+                };
+
+                // Ещё не выданные товары возвращаем на склад, выданные забирать вручную
+                if !stored_in_l2_db {
+                    release_invoice_stock(&original_invoice).await;
+                }
+
+                get_db()
+                    .await
+                    .update_invoice_data(
+                        original_invoice.id,
+                        InvoiceData::Refunded {
+                            external_id,
+                            amount,
+                            chargeback,
+                            reason,
+                            delivered: stored_in_l2_db,
+                        },
+                    )
+                    .await
+            }
+            // Оплата пришла после отмены брошенной корзины, товары уже вернулись на склад
+            InvoiceData::Aborted { .. }
+                if matches!(
+                    invoice_update.data,
+                    InvoiceStatusUpdateData::Payed
+                        | InvoiceStatusUpdateData::PayedWithChangedSum { .. }
+                        | InvoiceStatusUpdateData::PayedWithScaleSum { .. }
+                ) =>
+            {
+                NOTIFIER
+                    .notify(Notification::RejectedCallback {
+                        service: original_invoice.service,
+                        reason: format!("Payment for aborted invoice {}", original_invoice.id),
+                    })
+                    .await;
+
+                return Ok(());
+            }
             _ => {
                 return Ok(());
             }
         };
 
-        if let Err(_e) = update_res {
-            //TODO: mb do something
+        match update_res {
+            Ok(_) => {
+                if let Some(steam_id) = invoice_update.payer_steam_id {
+                    get_db()
+                        .await
+                        .set_invoice_payer_steam_id(original_invoice.id, steam_id)
+                        .await?;
+                }
+
+                if let Some(accounting) = invoice_update.paypalich_accounting {
+                    get_db()
+                        .await
+                        .push_invoice_paypalich_accounting(original_invoice.id, accounting)
+                        .await?;
+                }
+
+                if let Some(settlement) = invoice_update.settlement {
+                    get_db()
+                        .await
+                        .set_invoice_settlement(original_invoice.id, settlement)
+                        .await?;
+                }
+
+                // Гард get_db() должен отпуститься до вложенных вызовов get_db() ниже
+                let invoice = get_db().await.get_invoice_by_id(original_invoice.id).await;
+
+                if let Some(invoice) = invoice {
+                    if let InvoiceData::Aborted { .. } = invoice.data {
+                        dispatch_invoice_event(&invoice, InvoiceEvent::Aborted).await;
+                    }
+
+                    if let InvoiceData::Refunded {
+                        amount,
+                        chargeback,
+                        delivered,
+                        ..
+                    } = invoice.data
+                    {
+                        dispatch_invoice_event(&invoice, InvoiceEvent::Refunded).await;
+
+                        NOTIFIER
+                            .notify(Notification::Refunded {
+                                order_id: invoice.id,
+                                char_name: invoice.char_name.clone(),
+                                amount,
+                                service: invoice.service,
+                                chargeback,
+                                delivered,
+                            })
+                            .await;
+                    }
+
+                    if let InvoiceData::Payed { .. } = invoice.data {
+                        enqueue_delivery(invoice.id).await;
+                        enqueue_receipt(&invoice, ReceiptKind::Payed).await;
+                        dispatch_invoice_event(&invoice, InvoiceEvent::Paid).await;
+
+                        if invoice.amount_rub() >= CONFIG.notify_large_payment {
+                            NOTIFIER
+                                .notify(Notification::LargePayment {
+                                    order_id: invoice.id,
+                                    char_name: invoice.char_name.clone(),
+                                    amount: invoice.amount,
+                                    service: invoice.service,
+                                })
+                                .await;
+                        }
+                    }
+                }
+            }
+            Err(e) => return Err(e),
         };
 
         Ok(())
     }
 
-    pub async fn create_invoice(
-        &self,
-        amount: f32,
-        char_name: String,
-        char_id: i32,
-        service: PaymentServices,
-        client_ip: IpAddr,
-    ) -> Result<String, ()> {
+    pub async fn create_invoice(&self, request: NewInvoice) -> Result<String, PaymentError> {
+        let NewInvoice {
+            amount,
+            char_name,
+            char_id,
+            service,
+            client_ip,
+            lines,
+            payer_email,
+            gift,
+            locale,
+            pay_method,
+            promo_code,
+            currency_rate,
+        } = request;
+
         let order_id = Uuid::new_v4();
+        let metadata = InvoiceMetadata::new(char_id, &lines, promo_code.clone());
 
-        let created_invoice = match service {
-            PaymentServices::Enot => {
-                let invoice_request = self.enot.create_invoice_request(amount, order_id);
+        // Счёт в другой валюте и с выбранным способом оплаты умеет создавать только Enot
+        let candidates = if currency_rate.is_some() || pay_method.is_some() {
+            vec![service]
+        } else {
+            PROVIDER_HEALTH.candidates(service, &CONFIG.fallback_chain)
+        };
 
-                let resp = invoice_request.send().await;
+        let mut service = service;
+        let mut data: InvoiceData =
+            CreateInvoiceError::new(service, PaymentError::Internal, "No services to try").into();
 
-                match resp {
-                    Ok(res) => {
-                        let invoice_data = self.enot.proceed_create_invoice_response(res).await;
+        let deadline =
+            Instant::now() + StdDuration::from_millis(CONFIG.create_invoice_deadline_ms);
 
-                        Invoice {
-                            id: order_id,
-                            char_id,
-                            char_name,
-                            client_ip,
-                            service,
-                            amount,
-                            created_at: DateTime::from(SystemTime::now()),
-                            updated_at: DateTime::from(SystemTime::now()),
-                            data: invoice_data,
-                        }
-                    }
+        for candidate in candidates {
+            let started = Instant::now();
 
-                    Err(err) => Invoice {
-                        id: order_id,
-                        char_id,
-                        char_name,
-                        client_ip,
-                        service,
-                        amount,
-                        created_at: DateTime::from(SystemTime::now()),
-                        updated_at: DateTime::from(SystemTime::now()),
-                        data: InvoiceData::FailedToCreate {
-                            reason: format!("Can't connect to Enot servers: {err}"),
-                        },
-                    },
+            if started >= deadline {
+                break;
+            }
+
+            // Игрок не должен ждать, пока каждая платёжка исчерпает свои повторы
+            let res = match tokio::time::timeout(
+                deadline.saturating_duration_since(started),
+                self.request_invoice(
+                    candidate,
+                    amount,
+                    order_id,
+                    currency_rate,
+                    pay_method,
+                    metadata.clone(),
+                ),
+            )
+            .await
+            {
+                Ok(v) => v,
+                Err(_) => CreateInvoiceError::new(
+                    candidate,
+                    PaymentError::ProviderUnavailable,
+                    "Create invoice deadline exceeded",
+                )
+                .into(),
+            };
+
+            let failed = match &res {
+                InvoiceData::FailedToCreate { error, .. } => Some(*error),
+                _ => None,
+            };
+
+            if candidate != PaymentServices::Hotskins {
+                PROVIDER_HEALTH.record(
+                    candidate,
+                    !failed.is_some_and(is_provider_fault),
+                    started.elapsed(),
+                );
+            }
+
+            if service != candidate {
+                println!("Invoice {order_id} routed from {service} to {candidate}");
+            }
+
+            service = candidate;
+            data = res;
+
+            match failed {
+                Some(error) if is_failover_error(error) => {
+                    println!("{candidate} failed to create invoice {order_id}: {error:?}");
                 }
+                _ => break,
             }
+        }
 
-            PaymentServices::Hotskins => Invoice {
-                id: order_id,
-                char_id,
-                char_name,
-                client_ip,
-                service,
-                amount,
-                created_at: DateTime::from(SystemTime::now()),
-                updated_at: DateTime::from(SystemTime::now()),
-                data: self.hotskins.create_invoice(order_id),
-            },
+        let created_invoice = Invoice {
+            id: order_id,
+            char_id,
+            char_name,
+            client_ip,
+            service,
+            amount,
+            lines,
+            payer_email,
+            gift,
+            locale,
+            payer_steam_id: None,
+            paypalich_accounting: vec![],
+            settlement: None,
+            promo_code,
+            currency_rate,
+            created_at: DateTime::from(SystemTime::now()),
+            updated_at: DateTime::from(SystemTime::now()),
+            data,
+        };
 
-            PaymentServices::Paypalych => {
-                let invoice_request = self.paypalych.create_invoice_request(amount, order_id, true);
-
-                let resp = invoice_request.send().await;
-                match resp {
-                    Ok(res) => {
-                        let invoice_data =
-                            self.paypalych.proceed_create_invoice_response(res).await;
-
-                        Invoice {
-                            id: order_id,
-                            char_id,
-                            char_name,
-                            client_ip,
-                            service,
-                            amount,
-                            created_at: DateTime::from(SystemTime::now()),
-                            updated_at: DateTime::from(SystemTime::now()),
-                            data: invoice_data,
-                        }
-                    }
+        if let InvoiceData::FailedToCreate { reason, .. } = &created_invoice.data {
+            release_invoice_stock(&created_invoice).await;
 
-                    Err(err) => Invoice {
-                        id: order_id,
-                        char_id,
-                        char_name,
-                        client_ip,
-                        service,
-                        amount,
-                        created_at: DateTime::from(SystemTime::now()),
-                        updated_at: DateTime::from(SystemTime::now()),
-                        data: InvoiceData::FailedToCreate {
-                            reason: format!("Can't connect to Paypalich servers: {err}"),
-                        },
-                    },
+            NOTIFIER.record_create_failure(reason).await;
+        }
+
+        get_db().await.create_invoice(created_invoice.clone()).await;
+
+        if let InvoiceData::WaitingForPayment { .. } = created_invoice.data {
+            dispatch_invoice_event(&created_invoice, InvoiceEvent::Created).await;
+        }
+
+        match created_invoice.data {
+            InvoiceData::WaitingForPayment { payment_url, .. } => Ok(payment_url),
+            InvoiceData::FailedToCreate { error, .. } => Err(error),
+            _ => Err(PaymentError::Internal),
+        }
+    }
+
+    async fn request_invoice(
+        &self,
+        service: PaymentServices,
+        amount: f32,
+        order_id: Uuid,
+        currency_rate: Option<CurrencyRate>,
+        pay_method: Option<PayMethod>,
+        metadata: InvoiceMetadata,
+    ) -> InvoiceData {
+        match service {
+            PaymentServices::Enot => {
+                let invoice_request = self.enot.create_invoice_request(
+                    amount,
+                    order_id,
+                    currency_rate.map_or(Currency::RUB, |v| v.currency),
+                    pay_method,
+                    metadata,
+                );
+
+                match send_with_retry(service, invoice_request).await {
+                    Ok(res) => self.enot.proceed_create_invoice_response(res).await,
+
+                    Err(err) => err.into(),
                 }
             }
 
-            PaymentServices::PaypalychUk => {
-                let invoice_request = self.paypalych_uk.create_invoice_request(amount, order_id, false);
+            PaymentServices::Hotskins => self.hotskins.create_invoice(order_id),
 
-                let resp = invoice_request.send().await;
+            PaymentServices::Paypalych => {
+                let invoice_request = self.paypalych.create_invoice_request(
+                    amount,
+                    order_id,
+                    true,
+                    metadata,
+                );
 
-                match resp {
-                    Ok(res) => {
-                        let invoice_data =
-                            self.paypalych_uk.proceed_create_invoice_response(res).await;
+                match send_with_retry(service, invoice_request).await {
+                    Ok(res) => self.paypalych.proceed_create_invoice_response(res).await,
 
-                        Invoice {
-                            id: order_id,
-                            char_id,
-                            char_name,
-                            client_ip,
-                            service,
-                            amount,
-                            created_at: DateTime::from(SystemTime::now()),
-                            updated_at: DateTime::from(SystemTime::now()),
-                            data: invoice_data,
-                        }
-                    }
+                    Err(err) => err.into(),
+                }
+            }
 
-                    Err(err) => Invoice {
-                        id: order_id,
-                        char_id,
-                        char_name,
-                        client_ip,
-                        service,
-                        amount,
-                        created_at: DateTime::from(SystemTime::now()),
-                        updated_at: DateTime::from(SystemTime::now()),
-                        data: InvoiceData::FailedToCreate {
-                            reason: format!("Can't connect to PaypalichUK servers: {err}"),
-                        },
-                    },
+            PaymentServices::PaypalychUk => {
+                let invoice_request = self.paypalych_uk.create_invoice_request(
+                    amount,
+                    order_id,
+                    false,
+                    metadata,
+                );
+
+                match send_with_retry(service, invoice_request).await {
+                    Ok(res) => self.paypalych_uk.proceed_create_invoice_response(res).await,
+
+                    Err(err) => err.into(),
                 }
             }
+        }
+    }
+}
+
+async fn release_invoice_stock(invoice: &Invoice) {
+    release_lines(&invoice.lines).await;
+}
+
+/**
+Запас после срока счёта в Enot, чтобы успел дойти webhook об оплате в последнюю минуту
+ */
+const CART_EXPIRE_GRACE_MINUTES: i64 = 5;
+
+/**
+Отменяет неоплаченные счета с товарами старше `cart_reservation_minutes` и возвращает
+товары на склад. Hotskins не присылает отмену, а брошенная корзина держала бы остатки вечно
+ */
+pub async fn expire_abandoned_carts() -> Result<()> {
+    let created_before = Utc::now()
+        - Duration::minutes(CONFIG.cart_reservation_minutes as i64 + CART_EXPIRE_GRACE_MINUTES);
+
+    let invoices = get_db()
+        .await
+        .get_expired_cart_invoices(created_before)
+        .await?;
+
+    for invoice in invoices {
+        let InvoiceData::WaitingForPayment { external_id, .. } = &invoice.data else {
+            continue;
         };
 
-        get_db().await.create_invoice(created_invoice.clone()).await;
+        let data = InvoiceData::Aborted {
+            reason: "Expired".to_string(),
+            external_id: external_id.clone(),
+        };
 
-        match created_invoice.data {
-            InvoiceData::WaitingForPayment { payment_url, .. } => Ok(payment_url),
-            _ => Err(()),
+        if !get_db()
+            .await
+            .update_waiting_invoice_data(invoice.id, data.clone())
+            .await?
+        {
+            continue;
         }
+
+        release_invoice_stock(&invoice).await;
+        dispatch_invoice_event(&Invoice { data, ..invoice }, InvoiceEvent::Aborted).await;
     }
+
+    Ok(())
+}
+
+pub struct NewInvoice {
+    pub amount: f32,
+    pub char_name: String,
+    pub char_id: i32,
+    pub service: PaymentServices,
+    pub client_ip: IpAddr,
+    pub lines: Vec<InvoiceLine>,
+    pub payer_email: Option<String>,
+    pub gift: Option<InvoiceGift>,
+    pub locale: Locale,
+    pub pay_method: Option<PayMethod>,
+    pub promo_code: Option<String>,
+    pub currency_rate: Option<CurrencyRate>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -317,10 +734,185 @@ pub struct Invoice {
     pub char_id: i32,
     pub data: InvoiceData,
     created_at: DateTime<Utc>,
-    updated_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
     client_ip: IpAddr,
     pub service: PaymentServices,
     pub amount: f32,
+    #[serde(default)]
+    pub lines: Vec<InvoiceLine>,
+    pub payer_email: Option<String>,
+    pub gift: Option<InvoiceGift>,
+    #[serde(default)]
+    pub locale: Locale,
+    /**
+    SteamID плательщика Hotskins, для разбора мошенничества
+     */
+    #[serde(default)]
+    pub payer_steam_id: Option<String>,
+    #[serde(default)]
+    pub paypalich_accounting: Vec<PaypalichAccounting>,
+    #[serde(default)]
+    pub settlement: Option<InvoiceSettlement>,
+    #[serde(default)]
+    pub promo_code: Option<String>,
+    /**
+    Валюта CRD-счёта, если она отличается от валюты платёжки, и курс на момент создания.
+    `amount` такого счёта хранится в этой валюте
+     */
+    #[serde(default)]
+    pub currency_rate: Option<CurrencyRate>,
+}
+
+/**
+Фактическое зачисление по оплате: сколько заплатил клиент, сколько удержала платёжка
+и сколько пришло на баланс
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InvoiceSettlement {
+    pub gross: f32,
+    /**
+    Валюта `gross`, если клиент платил не в валюте зачисления `currency`. Тогда `fee`
+    неизвестна и равна 0
+     */
+    #[serde(default)]
+    pub gross_currency: Option<String>,
+    pub fee: f32,
+    pub net: f32,
+    /**
+    Валюта зачисления на баланс
+     */
+    pub currency: String,
+    pub pay_method: String,
+    pub pay_time: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct InvoiceGift {
+    pub payer_char_name: Option<String>,
+    pub payer_char_id: Option<i32>,
+    pub message: Option<String>,
+}
+
+impl Invoice {
+    /**
+    `None`, если количество предметов в какой-то строке переполняет `u32`
+     */
+    pub fn delayed_items(&self) -> Option<Vec<ProductItem>> {
+        if self.lines.is_empty() {
+            return Some(vec![ProductItem {
+                item_id: CRD_ID,
+                count: self.crd_amount() as u32,
+            }]);
+        }
+
+        let mut res = vec![];
+
+        for line in &self.lines {
+            res.extend(line.delayed_items()?);
+        }
+
+        Some(res)
+    }
+
+    /**
+    Сумма в рублях. У CRD-счетов после оплаты `amount` уже в CRD (1 CRD = 1 RUB).
+     */
+    pub fn amount_rub(&self) -> f32 {
+        match self.service.currency() {
+            Currency::USD if !self.lines.is_empty() => self.amount * USD_RATE as f32,
+            _ => self.crd_amount(),
+        }
+    }
+
+    /**
+    Сколько заплатил игрок в валюте `currency()`. У оплаченного CRD-счёта в долларах
+    `amount` уже пересчитан в рубли для начисления CRD
+     */
+    pub fn charged_amount(&self) -> f32 {
+        let rescaled = self.lines.is_empty()
+            && self.currency_rate.is_none()
+            && self.service.currency() == Currency::USD
+            && matches!(
+                self.data,
+                InvoiceData::Payed { .. } | InvoiceData::Refunded { .. }
+            );
+
+        if rescaled {
+            self.amount / USD_RATE as f32
+        } else {
+            self.amount
+        }
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency_rate
+            .map_or(self.service.currency(), |v| v.currency)
+    }
+
+    /**
+    Сколько CRD выдать по счёту без товаров
+     */
+    pub fn crd_amount(&self) -> f32 {
+        self.currency_rate
+            .map_or(self.amount, |v| v.to_crd(self.amount))
+    }
+
+    pub fn delayed_description(&self) -> String {
+        let Some(gift) = &self.gift else {
+            return self.char_name.clone();
+        };
+
+        let description = format!(
+            "{} - подарок от {}{}",
+            self.char_name,
+            gift.payer_char_name.as_deref().unwrap_or("анонима"),
+            gift.message
+                .as_ref()
+                .map(|v| format!(": {v}"))
+                .unwrap_or_default()
+        );
+
+        description.chars().take(DELAYED_DESCRIPTION_MAX_LEN).collect()
+    }
+
+    pub fn to_info(&self) -> InvoiceInfo {
+        InvoiceInfo {
+            order_id: self.id.to_string(),
+            char_name: self.char_name.clone(),
+            service: self.service,
+            amount: self.charged_amount(),
+            currency: self.currency(),
+            status: match &self.data {
+                InvoiceData::WaitingForPayment { .. } => InvoiceStatus::WaitingForPayment,
+                InvoiceData::FailedToCreate { .. } => InvoiceStatus::FailedToCreate,
+                InvoiceData::Aborted { .. } => InvoiceStatus::Aborted,
+                InvoiceData::Payed {
+                    stored_in_l2_db: false,
+                    ..
+                } => InvoiceStatus::Payed,
+                InvoiceData::Payed {
+                    stored_in_l2_db: true,
+                    ..
+                } => InvoiceStatus::Delivered,
+                InvoiceData::Refunded { .. } => InvoiceStatus::Refunded,
+            },
+            error: match &self.data {
+                InvoiceData::FailedToCreate { error, .. } => Some(*error),
+                _ => None,
+            },
+            lines: self
+                .lines
+                .iter()
+                .map(|v| InvoiceLineInfo {
+                    product_id: v.product_id.to_string(),
+                    name: v.name.clone(),
+                    quantity: v.quantity,
+                    price: v.price,
+                    items: v.items.clone(),
+                })
+                .collect(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -332,6 +924,8 @@ pub enum InvoiceData {
     },
     FailedToCreate {
         reason: String,
+        #[serde(default)]
+        error: PaymentError,
     },
     Aborted {
         reason: String,
@@ -341,19 +935,360 @@ pub enum InvoiceData {
         stored_in_l2_db: bool,
         external_id: String,
     },
+    /**
+    Возврат или chargeback после оплаты. `delivered` - товары успели выдать персонажу
+     */
+    Refunded {
+        external_id: String,
+        amount: f32,
+        chargeback: bool,
+        reason: String,
+        delivered: bool,
+    },
 }
 
+#[derive(Default)]
 pub struct InvoiceStatusUpdate {
     pub(crate) order_id: Uuid,
     pub(crate) external_id: String,
+    /**
+    Id транзакции у платёжки. Повторный webhook с тем же id и того же типа не обрабатывается
+     */
+    pub(crate) transaction_id: String,
+    /**
+    Время события по данным платёжки, если она его присылает
+     */
+    pub(crate) event_time: Option<DateTime<Utc>>,
+    pub(crate) payer_steam_id: Option<String>,
+    pub(crate) paypalich_accounting: Option<PaypalichAccounting>,
+    /**
+    Метаданные, вернувшиеся из произвольного поля платёжки
+     */
+    pub(crate) metadata: Option<InvoiceMetadata>,
+    /**
+    Заполняется только для оплат
+     */
+    pub(crate) settlement: Option<InvoiceSettlement>,
     pub(crate) data: InvoiceStatusUpdateData,
 }
 
+#[derive(Default, Debug, PartialEq)]
 pub enum InvoiceStatusUpdateData {
+    #[default]
     None,
     Aborted { reason: String },
     Payed,
-    PayedWithChangedSum { new_amount: f32 },
+    /**
+    `new_amount` в валюте счёта, `scale` - курс к рублю для начисления CRD
+     */
+    PayedWithChangedSum { new_amount: f32, scale: f32 },
     //For usd
     PayedWithScaleSum { scale: f32 },
+    Refunded {
+        amount: f32,
+        chargeback: bool,
+        reason: String,
+    },
+}
+
+impl InvoiceStatusUpdateData {
+    fn kind(&self) -> &'static str {
+        match self {
+            InvoiceStatusUpdateData::None => "none",
+            InvoiceStatusUpdateData::Aborted { .. } => "aborted",
+            InvoiceStatusUpdateData::Payed
+            | InvoiceStatusUpdateData::PayedWithChangedSum { .. }
+            | InvoiceStatusUpdateData::PayedWithScaleSum { .. } => "payed",
+            InvoiceStatusUpdateData::Refunded {
+                chargeback: false, ..
+            } => "refunded",
+            InvoiceStatusUpdateData::Refunded {
+                chargeback: true, ..
+            } => "chargeback",
+        }
+    }
+}
+
+/**
+Обработанный webhook платёжки. Уникальный индекс по (service, transaction_id, kind)
+ */
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProviderTransaction {
+    pub service: PaymentServices,
+    pub transaction_id: String,
+    pub kind: String,
+    #[serde(with = "uuid_1_as_binary")]
+    pub order_id: Uuid,
+    pub received_at: DateTime<Utc>,
+}
+
+/**
+Журнал обработанных webhook платёжек
+ */
+pub(crate) trait TransactionLog {
+    /**
+    `false`, если такая транзакция уже записана
+     */
+    async fn record(&self, transaction: &ProviderTransaction) -> Result<bool>;
+    async fn delete(&self, transaction: &ProviderTransaction) -> Result<()>;
+}
+
+struct MongoTransactionLog;
+
+impl TransactionLog for MongoTransactionLog {
+    async fn record(&self, transaction: &ProviderTransaction) -> Result<bool> {
+        get_db().await.record_provider_transaction(transaction).await
+    }
+
+    async fn delete(&self, transaction: &ProviderTransaction) -> Result<()> {
+        get_db().await.delete_provider_transaction(transaction).await
+    }
+}
+
+/**
+Применяет webhook, только если транзакция ещё не обрабатывалась. Повтор с тем же id
+транзакции, в том числе подставленный к чужому заказу, игнорируется
+ */
+async fn apply_once(
+    log: &impl TransactionLog,
+    transaction: &ProviderTransaction,
+    apply: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    if !log.record(transaction).await? {
+        println!(
+            "Duplicate {} callback {} ({}) for order {}, ignored",
+            transaction.service, transaction.transaction_id, transaction.kind, transaction.order_id
+        );
+
+        return Ok(());
+    }
+
+    let res = apply.await;
+
+    // Обработка не удалась - платёжка повторит webhook, он не должен считаться дублем
+    if res.is_err() {
+        if let Err(e) = log.delete(transaction).await {
+            println!("Err on delete provider transaction {e:#?}");
+        }
+    }
+
+    res
+}
+
+fn is_stale(event_time: DateTime<Utc>, now: DateTime<Utc>, max_age_hours: i64) -> bool {
+    now - event_time > Duration::hours(max_age_hours)
+}
+
+impl InvoiceData {
+    pub fn external_id(&self) -> Option<&str> {
+        match self {
+            InvoiceData::WaitingForPayment { external_id, .. }
+            | InvoiceData::Aborted { external_id, .. }
+            | InvoiceData::Payed { external_id, .. }
+            | InvoiceData::Refunded { external_id, .. } => Some(external_id),
+            InvoiceData::FailedToCreate { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Invoice {
+    /**
+    Оплаченный, но не выданный счёт Enot на `amount` без товаров. Тесты меняют только
+    нужные им поля
+     */
+    pub(crate) fn test_new(amount: f32) -> Self {
+        Invoice {
+            id: Uuid::new_v4(),
+            char_name: "Player".to_string(),
+            char_id: 1,
+            data: InvoiceData::Payed {
+                stored_in_l2_db: false,
+                external_id: "e1".to_string(),
+            },
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            client_ip: IpAddr::from([127, 0, 0, 1]),
+            service: PaymentServices::Enot,
+            amount,
+            lines: vec![],
+            payer_email: None,
+            gift: None,
+            locale: Default::default(),
+            payer_steam_id: None,
+            paypalich_accounting: vec![],
+            settlement: None,
+            promo_code: None,
+            currency_rate: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::catalog::CRD_ID;
+    use crate::invoice_handler::{
+        apply_once, is_stale, Invoice, InvoiceGift, ProviderTransaction, TransactionLog,
+    };
+    use anyhow::{anyhow, Result};
+    use chrono::{Duration, Utc};
+    use shared::{Currency, CurrencyRate, PaymentServices};
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use uuid::Uuid;
+
+    /**
+    Ключ как у уникального индекса `provider_transaction` в базе
+     */
+    #[derive(Default)]
+    struct MemoryTransactionLog(Mutex<HashSet<(PaymentServices, String, String)>>);
+
+    impl TransactionLog for MemoryTransactionLog {
+        async fn record(&self, transaction: &ProviderTransaction) -> Result<bool> {
+            Ok(self.0.lock().unwrap().insert((
+                transaction.service,
+                transaction.transaction_id.clone(),
+                transaction.kind.clone(),
+            )))
+        }
+
+        async fn delete(&self, transaction: &ProviderTransaction) -> Result<()> {
+            self.0.lock().unwrap().remove(&(
+                transaction.service,
+                transaction.transaction_id.clone(),
+                transaction.kind.clone(),
+            ));
+
+            Ok(())
+        }
+    }
+
+    fn transaction(transaction_id: &str, kind: &str, order_id: Uuid) -> ProviderTransaction {
+        ProviderTransaction {
+            service: PaymentServices::Paypalych,
+            transaction_id: transaction_id.to_string(),
+            kind: kind.to_string(),
+            order_id,
+            received_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_gift_description() {
+        let mut invoice = Invoice {
+            char_name: "Recipient".to_string(),
+            ..Invoice::test_new(100.0)
+        };
+
+        assert_eq!(invoice.delayed_description(), "Recipient");
+
+        invoice.gift = Some(InvoiceGift {
+            payer_char_name: Some("Payer".to_string()),
+            payer_char_id: Some(2),
+            message: Some("С Новым годом!".to_string()),
+        });
+        assert_eq!(
+            invoice.delayed_description(),
+            "Recipient - подарок от Payer: С Новым годом!"
+        );
+
+        invoice.gift = Some(InvoiceGift {
+            payer_char_name: None,
+            payer_char_id: None,
+            message: Some("x".repeat(300)),
+        });
+        assert_eq!(invoice.delayed_description().chars().count(), 255);
+    }
+
+    #[test]
+    fn test_stale_callback() {
+        let now = Utc::now();
+
+        assert!(!is_stale(now - Duration::hours(47), now, 48));
+        assert!(is_stale(now - Duration::hours(49), now, 48));
+        // Часы платёжки могут спешить
+        assert!(!is_stale(now + Duration::minutes(5), now, 48));
+    }
+
+    #[test]
+    fn test_currency_invoice() {
+        let mut invoice = Invoice::test_new(5000.0);
+
+        assert_eq!(invoice.currency(), Currency::RUB);
+        assert_eq!(invoice.crd_amount(), 5000.0);
+
+        invoice.currency_rate = Some(CurrencyRate {
+            currency: Currency::KZT,
+            crd_rate: 0.2,
+        });
+
+        assert_eq!(invoice.currency(), Currency::KZT);
+        assert_eq!(invoice.to_info().amount, 5000.0);
+        assert_eq!(invoice.amount_rub(), 1000.0);
+
+        let items = invoice.delayed_items().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].item_id, CRD_ID);
+        assert_eq!(items[0].count, 1000);
+
+        invoice.amount = 100.0;
+        invoice.currency_rate = Some(CurrencyRate {
+            currency: Currency::KZT,
+            crd_rate: 0.19,
+        });
+        assert_eq!(invoice.delayed_items().unwrap()[0].count, 19);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_transaction() {
+        let log = MemoryTransactionLog::default();
+        let applied = Mutex::new(vec![]);
+        let order_id = Uuid::new_v4();
+
+        let apply = |order_id: Uuid| {
+            let applied = &applied;
+            async move {
+                applied.lock().unwrap().push(order_id);
+                Ok(())
+            }
+        };
+
+        let paid = transaction("t1", "paid", order_id);
+
+        apply_once(&log, &paid, apply(order_id)).await.unwrap();
+        apply_once(&log, &paid, apply(order_id)).await.unwrap();
+
+        // Тот же id транзакции, подставленный к другому заказу
+        let other_order = Uuid::new_v4();
+        apply_once(&log, &transaction("t1", "paid", other_order), apply(other_order))
+            .await
+            .unwrap();
+
+        // Возврат по той же транзакции - другое событие
+        apply_once(&log, &transaction("t1", "refunded", order_id), apply(order_id))
+            .await
+            .unwrap();
+
+        assert_eq!(*applied.lock().unwrap(), vec![order_id, order_id]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_transaction_retry() {
+        let log = MemoryTransactionLog::default();
+        let paid = transaction("t2", "paid", Uuid::new_v4());
+
+        let res = apply_once(&log, &paid, async { Err(anyhow!("Db is down")) }).await;
+        assert!(res.is_err());
+
+        // Запись удалена, повтор от платёжки обрабатывается
+        let mut applied = false;
+        apply_once(&log, &paid, async {
+            applied = true;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        assert!(applied);
+    }
 }
@@ -0,0 +1,320 @@
+use anyhow::Result;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use mongodb::bson::serde_helpers::uuid_1_as_binary;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::invoice_handler::expire_abandoned_carts;
+use crate::mailer::send_pending_emails;
+use crate::outgoing_webhooks::send_pending_webhooks;
+use crate::tasks::{check_undelivered, deliver_invoice, give_votes};
+use crate::{get_db, get_db_mut, CONFIG};
+
+/**
+За это время задача должна завершиться, иначе её заберёт другой воркер
+ */
+const LEASE_SECS: i64 = 300;
+/**
+Задача, не уложившаяся в это время, отменяется. Меньше `LEASE_SECS`, чтобы задачу не
+выполняли два воркера сразу
+ */
+const JOB_TIMEOUT: Duration = Duration::from_secs(240);
+const MAX_RETRY_DELAY_SECS: i64 = 3600;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /**
+    Будит воркеров, когда появилась задача, которую надо выполнить сразу
+     */
+    static ref JOB_ADDED: Notify = Notify::new();
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum JobKind {
+    ValidateConnections,
+    DeliverInvoice {
+        #[serde(with = "uuid_1_as_binary")]
+        invoice_id: Uuid,
+    },
+    /**
+    Ищет оплаченные, но не выданные счета: ставит им выдачу и сообщает о зависших
+     */
+    CheckUndelivered,
+    /**
+    Отменяет брошенные корзины и возвращает товары на склад
+     */
+    ExpireCarts,
+    ScrapeVotes,
+    SendEmails,
+    SendWebhooks,
+}
+
+impl JobKind {
+    /**
+    Повторяющиеся задачи живут в очереди всегда, по одной на тип
+     */
+    pub const RECURRING: [JobKind; 6] = [
+        JobKind::ValidateConnections,
+        JobKind::CheckUndelivered,
+        JobKind::ExpireCarts,
+        JobKind::ScrapeVotes,
+        JobKind::SendEmails,
+        JobKind::SendWebhooks,
+    ];
+
+    /**
+    Интервал между запусками. `None` - разовая задача, после выполнения удаляется
+     */
+    fn schedule(&self) -> Option<Duration> {
+        match self {
+            JobKind::ValidateConnections => Some(Duration::from_secs(10)),
+            JobKind::DeliverInvoice { .. } => None,
+            JobKind::CheckUndelivered => Some(Duration::from_secs(60)),
+            JobKind::ExpireCarts => Some(Duration::from_secs(60)),
+            JobKind::ScrapeVotes => Some(Duration::from_secs(60)),
+            JobKind::SendEmails => Some(Duration::from_secs(10)),
+            JobKind::SendWebhooks => Some(Duration::from_secs(10)),
+        }
+    }
+
+    /**
+    Разовая задача после стольких неудач уходит в `job_dead_letter`
+     */
+    fn max_attempts(&self) -> u32 {
+        match self {
+            JobKind::DeliverInvoice { .. } => 20,
+            _ => u32::MAX,
+        }
+    }
+
+    fn retry_base_delay_secs(&self) -> i64 {
+        match self {
+            JobKind::DeliverInvoice { .. } => 5,
+            _ => 30,
+        }
+    }
+
+    /**
+    Уникальный ключ: не даёт поставить одну и ту же задачу дважды
+     */
+    fn key(&self) -> String {
+        match self {
+            JobKind::DeliverInvoice { invoice_id } => format!("DeliverInvoice:{invoice_id}"),
+            v => format!("{v:?}"),
+        }
+    }
+
+    async fn run(self) -> Result<()> {
+        match self {
+            JobKind::ValidateConnections => {
+                get_db_mut().await.validate_connections().await;
+                Ok(())
+            }
+            JobKind::DeliverInvoice { invoice_id } => deliver_invoice(invoice_id).await,
+            JobKind::CheckUndelivered => check_undelivered().await,
+            JobKind::ExpireCarts => expire_abandoned_carts().await,
+            JobKind::ScrapeVotes => give_votes().await,
+            JobKind::SendEmails => {
+                send_pending_emails().await;
+                Ok(())
+            }
+            JobKind::SendWebhooks => {
+                send_pending_webhooks().await;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    #[serde(rename = "_id")]
+    #[serde(with = "uuid_1_as_binary")]
+    pub id: Uuid,
+    pub key: String,
+    pub kind: JobKind,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /**
+    Unix timestamp следующего запуска
+     */
+    pub next_run_at: i64,
+    /**
+    Unix timestamp, до которого задачу держит воркер `locked_by`. 0 - задача свободна
+     */
+    pub locked_until: i64,
+    pub locked_by: Option<String>,
+}
+
+impl Job {
+    pub fn new(kind: JobKind, next_run_at: i64) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            key: kind.key(),
+            kind,
+            attempts: 0,
+            last_error: None,
+            next_run_at,
+            locked_until: 0,
+            locked_by: None,
+        }
+    }
+}
+
+/**
+Ставит выдачу покупки по оплаченному счёту. Если задача уже стоит, вторая не создаётся
+ */
+pub async fn enqueue_delivery(invoice_id: Uuid) {
+    let job = Job::new(
+        JobKind::DeliverInvoice { invoice_id },
+        Utc::now().timestamp(),
+    );
+
+    match get_db().await.create_job(job).await {
+        Ok(true) => JOB_ADDED.notify_one(),
+        Ok(false) => {}
+        Err(e) => println!("Err on enqueue delivery {invoice_id} {e:#?}"),
+    }
+}
+
+/**
+Заводит повторяющиеся задачи и запускает воркеров. Каждая задача выполняется в отдельной
+tokio задаче: паника или долгий парсинг MMOTOP не останавливают остальные
+ */
+pub fn spawn_job_workers() {
+    tokio::spawn(async move {
+        let now = Utc::now().timestamp();
+
+        for kind in JobKind::RECURRING {
+            if let Err(e) = get_db().await.ensure_job(Job::new(kind, now)).await {
+                println!("Err on ensure recurring job {e:#?}");
+            }
+        }
+
+        for _ in 0..CONFIG.job_workers.max(1) {
+            tokio::spawn(work());
+        }
+    });
+}
+
+async fn work() {
+    let worker_id = Uuid::new_v4().to_string();
+
+    loop {
+        let now = Utc::now().timestamp();
+
+        let job = match get_db()
+            .await
+            .claim_job(now, now + LEASE_SECS, &worker_id)
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Err on claim job {e:#?}");
+                None
+            }
+        };
+
+        let Some(job) = job else {
+            let _ = tokio::time::timeout(POLL_INTERVAL, JOB_ADDED.notified()).await;
+            continue;
+        };
+
+        let run = tokio::time::timeout(JOB_TIMEOUT, job.kind.clone().run());
+
+        let res = match tokio::spawn(run).await {
+            Ok(Ok(v)) => v,
+            Ok(Err(_)) => Err(anyhow::anyhow!("Job timed out after {JOB_TIMEOUT:?}")),
+            Err(e) => Err(anyhow::anyhow!("Job panicked: {e}")),
+        };
+
+        if let Err(e) = finish_job(job, res, &worker_id).await {
+            println!("Err on finish job {e:#?}");
+        }
+    }
+}
+
+async fn finish_job(job: Job, res: Result<()>, worker_id: &str) -> Result<()> {
+    let now = Utc::now().timestamp();
+    let db = get_db().await;
+
+    match (res, job.kind.schedule()) {
+        (Ok(_), None) => db.delete_job(job.id, worker_id).await,
+        (Ok(_), Some(schedule)) => {
+            db.reschedule_job(job.id, worker_id, 0, now + schedule.as_secs() as i64, None)
+                .await
+        }
+        (Err(e), _) => {
+            let attempts = job.attempts.saturating_add(1);
+
+            println!("Job {} failed, attempt {attempts}: {e:#}", job.key);
+
+            if attempts >= job.kind.max_attempts() {
+                return db
+                    .move_job_to_dead_letter(Job {
+                        attempts,
+                        last_error: Some(format!("{e:#}")),
+                        ..job
+                    })
+                    .await;
+            }
+
+            db.reschedule_job(
+                job.id,
+                worker_id,
+                attempts,
+                now + retry_delay(&job.kind, attempts),
+                Some(format!("{e:#}")),
+            )
+            .await
+        }
+    }
+}
+
+/**
+Повторяющаяся задача после ошибки не ждёт дольше своего интервала
+ */
+fn retry_delay(kind: &JobKind, attempts: u32) -> i64 {
+    let delay = (kind.retry_base_delay_secs() << (attempts - 1).min(10)).min(MAX_RETRY_DELAY_SECS);
+
+    match kind.schedule() {
+        Some(schedule) => delay.min(schedule.as_secs() as i64),
+        None => delay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{retry_delay, JobKind};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_retry_delay() {
+        let delivery = JobKind::DeliverInvoice {
+            invoice_id: Uuid::new_v4(),
+        };
+
+        assert_eq!(retry_delay(&delivery, 1), 5);
+        assert_eq!(retry_delay(&delivery, 3), 20);
+        assert_eq!(retry_delay(&delivery, 30), 3600);
+
+        assert_eq!(retry_delay(&JobKind::ScrapeVotes, 1), 30);
+        assert_eq!(retry_delay(&JobKind::ScrapeVotes, 5), 60);
+        assert_eq!(retry_delay(&JobKind::SendEmails, 1), 10);
+    }
+
+    #[test]
+    fn test_key() {
+        let invoice_id = Uuid::new_v4();
+
+        assert_eq!(JobKind::ScrapeVotes.key(), "ScrapeVotes");
+        assert_eq!(
+            JobKind::DeliverInvoice { invoice_id }.key(),
+            format!("DeliverInvoice:{invoice_id}")
+        );
+    }
+}
@@ -0,0 +1,288 @@
+use chrono::Utc;
+use lazy_static::lazy_static;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use mongodb::bson::serde_helpers::uuid_1_as_binary;
+use serde::{Deserialize, Serialize};
+use shared::Locale;
+use uuid::Uuid;
+
+use crate::invoice_handler::Invoice;
+use crate::{get_db, CONFIG};
+
+const MAX_SEND_ATTEMPTS: u32 = 8;
+const RETRY_BASE_DELAY_SECS: i64 = 60;
+
+lazy_static! {
+    static ref MAILER: Option<Mailer> = Mailer::from_config();
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum ReceiptKind {
+    Payed,
+    Delivered,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutgoingEmail {
+    #[serde(rename = "_id")]
+    #[serde(with = "uuid_1_as_binary")]
+    pub id: Uuid,
+    #[serde(with = "uuid_1_as_binary")]
+    pub invoice_id: Uuid,
+    pub kind: ReceiptKind,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub attempts: u32,
+    pub sent: bool,
+    /**
+    Unix timestamp следующей попытки отправки
+     */
+    pub next_try_at: i64,
+}
+
+struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl Mailer {
+    fn from_config() -> Option<Self> {
+        let host = CONFIG.smtp_host.as_ref()?;
+
+        let Some(from) = CONFIG.smtp_from.as_ref().and_then(|v| v.parse().ok()) else {
+            println!("SMTP host is set, but l2w_backend_smtp_from is missing or invalid");
+            return None;
+        };
+
+        let mut builder = if CONFIG.smtp_tls {
+            match AsyncSmtpTransport::<Tokio1Executor>::relay(host) {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("Can't create SMTP transport: {e}");
+                    return None;
+                }
+            }
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        };
+
+        if let Some(port) = CONFIG.smtp_port {
+            builder = builder.port(port);
+        }
+
+        if let (Some(login), Some(password)) = (&CONFIG.smtp_login, &CONFIG.smtp_password) {
+            builder = builder.credentials(Credentials::new(login.clone(), password.clone()));
+        }
+
+        Some(Self {
+            transport: builder.build(),
+            from,
+        })
+    }
+
+    async fn send(&self, email: &OutgoingEmail) -> anyhow::Result<()> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse()?)
+            .subject(&email.subject)
+            .body(email.body.clone())?;
+
+        self.transport.send(message).await?;
+
+        Ok(())
+    }
+}
+
+/**
+Ставит чек в очередь на отправку, если плательщик оставил email
+ */
+pub async fn enqueue_receipt(invoice: &Invoice, kind: ReceiptKind) {
+    if MAILER.is_none() {
+        return;
+    }
+
+    let Some(to) = &invoice.payer_email else {
+        return;
+    };
+
+    let (subject, body) = render_receipt(invoice, kind);
+
+    let email = OutgoingEmail {
+        id: Uuid::new_v4(),
+        invoice_id: invoice.id,
+        kind,
+        to: to.clone(),
+        subject,
+        body,
+        attempts: 0,
+        sent: false,
+        next_try_at: Utc::now().timestamp(),
+    };
+
+    if let Err(e) = get_db().await.create_outgoing_email(email).await {
+        println!("Err on enqueue receipt {e:#?}");
+    }
+}
+
+pub async fn send_pending_emails() {
+    let Some(mailer) = MAILER.as_ref() else {
+        return;
+    };
+
+    let now = Utc::now().timestamp();
+
+    let emails = match get_db()
+        .await
+        .get_pending_emails(now, MAX_SEND_ATTEMPTS)
+        .await
+    {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Err on get pending emails {e:#?}");
+            return;
+        }
+    };
+
+    for email in emails {
+        let res = match mailer.send(&email).await {
+            Ok(_) => get_db().await.mark_email_sent(email.id).await,
+            Err(e) => {
+                println!("Err on send email {}: {e:#?}", email.id);
+
+                get_db()
+                    .await
+                    .mark_email_failed(email.id, next_try_at(now, email.attempts))
+                    .await
+            }
+        };
+
+        if let Err(e) = res {
+            println!("Err on update email {}: {e:#?}", email.id);
+        }
+    }
+}
+
+fn next_try_at(now: i64, attempts: u32) -> i64 {
+    now + RETRY_BASE_DELAY_SECS * (1 << attempts.min(10))
+}
+
+fn render_receipt(invoice: &Invoice, kind: ReceiptKind) -> (String, String) {
+    let currency = invoice.currency();
+
+    let granted = if invoice.lines.is_empty() {
+        format!("{} CRD", invoice.crd_amount() as u32)
+    } else {
+        invoice
+            .lines
+            .iter()
+            .map(|v| format!("{} x{}", v.name, v.quantity))
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+
+    let amount = invoice.charged_amount();
+
+    match invoice.locale {
+        Locale::Ru => {
+            let (subject, status) = match kind {
+                ReceiptKind::Payed => ("La2World: оплата получена", "Оплата получена"),
+                ReceiptKind::Delivered => (
+                    "La2World: покупка доставлена",
+                    "Покупка доставлена персонажу",
+                ),
+            };
+
+            (
+                subject.to_string(),
+                format!(
+                    "{status}.\n\n\
+                     Номер заказа: {}\n\
+                     Сумма: {amount} {currency}\n\
+                     Начислено: {granted}\n\
+                     Персонаж: {}\n\
+                     Способ оплаты: {}\n\n\
+                     Спасибо за поддержку сервера La2World!",
+                    invoice.id, invoice.char_name, invoice.service
+                ),
+            )
+        }
+        Locale::En => {
+            let (subject, status) = match kind {
+                ReceiptKind::Payed => ("La2World: payment received", "Payment received"),
+                ReceiptKind::Delivered => (
+                    "La2World: purchase delivered",
+                    "Purchase delivered to the character",
+                ),
+            };
+
+            (
+                subject.to_string(),
+                format!(
+                    "{status}.\n\n\
+                     Order id: {}\n\
+                     Amount: {amount} {currency}\n\
+                     Granted: {granted}\n\
+                     Character: {}\n\
+                     Payment service: {}\n\n\
+                     Thank you for supporting La2World!",
+                    invoice.id, invoice.char_name, invoice.service
+                ),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_try_at, render_receipt, ReceiptKind};
+    use crate::catalog::InvoiceLine;
+    use crate::invoice_handler::Invoice;
+    use shared::{Locale, PaymentServices, ProductItem};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_render_receipt() {
+        // Оплаченный CRD-счёт в долларах: amount уже пересчитан в рубли
+        let mut invoice = Invoice::test_new(900.0);
+        invoice.service = PaymentServices::PaypalychUk;
+        invoice.payer_email = Some("player@example.com".to_string());
+        invoice.locale = Locale::En;
+
+        let (subject, body) = render_receipt(&invoice, ReceiptKind::Payed);
+
+        assert_eq!(subject, "La2World: payment received");
+        assert!(body.contains("Amount: 10 USD\n"));
+        assert!(body.contains("Granted: 900 CRD\n"));
+
+        invoice.service = PaymentServices::Enot;
+        invoice.locale = Locale::Ru;
+        invoice.amount = 300.0;
+        invoice.lines = vec![InvoiceLine {
+            product_id: Uuid::new_v4(),
+            name: "Starter pack".to_string(),
+            quantity: 2,
+            price: 150.0,
+            items: vec![ProductItem {
+                item_id: 57,
+                count: 1000,
+            }],
+        }];
+
+        let (subject, body) = render_receipt(&invoice, ReceiptKind::Delivered);
+
+        assert_eq!(subject, "La2World: покупка доставлена");
+        assert!(body.contains("Сумма: 300 RUB\n"));
+        assert!(body.contains("Начислено: Starter pack x2\n"));
+    }
+
+    #[test]
+    fn test_retry_schedule() {
+        assert_eq!(next_try_at(1000, 0), 1060);
+        assert_eq!(next_try_at(1000, 3), 1480);
+        // Задержка перестаёт расти после 10 попыток
+        assert_eq!(next_try_at(0, 10), next_try_at(0, 20));
+    }
+}
@@ -1,7 +1,17 @@
 mod api;
+mod catalog;
 mod database_connection;
 mod invoice_handler;
+mod jobs;
+mod mailer;
+mod notifier;
+mod outgoing_webhooks;
+mod rate_limiter;
+mod reconciliation;
+mod reporting;
 mod pay_services;
+mod pow;
+mod provider_health;
 mod tasks;
 mod vote_services;
 
@@ -14,20 +24,35 @@ use axum::Router;
 use axum_server::tls_rustls::RustlsConfig;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Deserializer};
-use std::net::{IpAddr, SocketAddr};
+use shared::{CurrencyRate, PaymentServices};
+use std::fmt::Display;
+use std::net::SocketAddr;
 use std::str::FromStr;
 use tokio::sync::{OnceCell, RwLock};
 use tower::util::ServiceExt;
 use tower_http::services::ServeDir;
 use uuid::Uuid;
 
-use crate::api::lk_payments::create_invoice;
+use crate::api::catalog::get_catalog;
+use crate::api::client_ip::Cidr;
+use crate::api::characters::get_character;
+use crate::api::lk_payments::{
+    create_invoice, get_currencies, get_invoice, get_pay_methods, get_providers,
+};
+use crate::api::reconciliation::{
+    get_reconciliation_report, get_reconciliation_reports, import_reconciliation_statement,
+    resolve_discrepancy,
+};
+use crate::api::reports::{get_conversion_report, get_revenue_report, get_top_donors_report};
 use crate::api::webhooks::{
     enot_invoice_webhook, hotskins_invoice_webhook, paypalich_invoice_webhook,
     paypalich_uk_invoice_webhook,
 };
 use crate::database_connection::DatabaseConnection;
-use crate::tasks::spawn_tasks;
+use crate::jobs::spawn_job_workers;
+use crate::pay_services::enot::PaymentMethod;
+use crate::pow::PowMode;
+use crate::provider_health::FallbackRule;
 
 lazy_static! {
     static ref CONFIG: MainConfig = envy::from_env::<MainConfig>().unwrap();
@@ -63,9 +88,26 @@ struct MainConfig {
     enot_shop_id: Uuid,
     #[serde(rename = "l2w_backend_enot_api_url")]
     enot_api_url: String,
+    /**
+    Методы Enot через запятую, которые не показываются клиентам, например `qiwi,perfect_money`
+     */
+    #[serde(rename = "l2w_backend_enot_disabled_methods")]
+    #[serde(default, deserialize_with = "comma_vec_from_str")]
+    enot_disabled_methods: Vec<PaymentMethod>,
+    /**
+    Курсы CRD за единицу валюты через запятую, например `USD:90,KZT:0.19`. Валюты без курса
+    недоступны для оплаты
+     */
+    #[serde(rename = "l2w_backend_enot_currency_rates")]
+    #[serde(default, deserialize_with = "comma_vec_from_str")]
+    enot_currency_rates: Vec<CurrencyRate>,
+    /**
+    Адреса, с которых принимаются webhook платёжки. Обязательны для каждой платёжки, с
+    пустым списком сервер не запустится. Принять с любого адреса - `0.0.0.0/0,::/0`
+     */
     #[serde(rename = "l2w_backend_enot_allowed_ips")]
-    #[serde(deserialize_with = "ip_vec_from_str")]
-    enot_allowed_ips: Vec<IpAddr>,
+    #[serde(deserialize_with = "comma_vec_from_str")]
+    enot_allowed_ips: Vec<Cidr>,
 
     #[serde(rename = "l2w_backend_hotskins_shop_api_url")]
     hotskins_api_url: String,
@@ -73,6 +115,9 @@ struct MainConfig {
     hotskins_secret: String,
     #[serde(rename = "l2w_backend_hotskins_shop_public")]
     hotskins_public: String,
+    #[serde(rename = "l2w_backend_hotskins_allowed_ips")]
+    #[serde(deserialize_with = "comma_vec_from_str")]
+    hotskins_allowed_ips: Vec<Cidr>,
 
     #[serde(rename = "l2w_backend_paypalich_shop_id")]
     paypalich_shop_id: String,
@@ -80,6 +125,9 @@ struct MainConfig {
     paypalich_bearer: String,
     #[serde(rename = "l2w_backend_paypalich_api_url")]
     paypalich_api_url: String,
+    #[serde(rename = "l2w_backend_paypalich_allowed_ips")]
+    #[serde(deserialize_with = "comma_vec_from_str")]
+    paypalich_allowed_ips: Vec<Cidr>,
 
     #[serde(rename = "l2w_backend_paypalich_uk_shop_id")]
     paypalich_uk_shop_id: String,
@@ -87,21 +135,282 @@ struct MainConfig {
     paypalich_uk_bearer: String,
     #[serde(rename = "l2w_backend_paypalich_uk_api_url")]
     paypalich_uk_api_url: String,
+    #[serde(rename = "l2w_backend_paypalich_uk_allowed_ips")]
+    #[serde(deserialize_with = "comma_vec_from_str")]
+    paypalich_uk_allowed_ips: Vec<Cidr>,
+
+    /**
+    Прокси (nginx), от которых принимаются `X-Forwarded-For` и `X-Real-IP`. По умолчанию
+    локальный nginx: сервер слушает только 127.0.0.1
+     */
+    #[serde(rename = "l2w_backend_trusted_proxies")]
+    #[serde(
+        default = "default_trusted_proxies",
+        deserialize_with = "comma_vec_from_str"
+    )]
+    trusted_proxies: Vec<Cidr>,
+    /**
+    Webhook о событии старше этого возраста отклоняется (если платёжка присылает время события)
+     */
+    #[serde(rename = "l2w_backend_webhook_max_age_hours")]
+    #[serde(default = "default_webhook_max_age_hours")]
+    webhook_max_age_hours: i64,
 
     #[serde(rename = "l2w_backend_mmotop_url")]
     mmotop_url: String,
+
+    /**
+    Сколько держим товары за неоплаченным счётом. Потом счёт отменяется, а товары
+    возвращаются на склад. Столько же живёт счёт в Enot
+     */
+    #[serde(rename = "l2w_backend_cart_reservation_minutes")]
+    #[serde(default = "default_cart_reservation_minutes")]
+    cart_reservation_minutes: u32,
+
+    #[serde(rename = "l2w_backend_smtp_host")]
+    smtp_host: Option<String>,
+    #[serde(rename = "l2w_backend_smtp_port")]
+    smtp_port: Option<u16>,
+    #[serde(rename = "l2w_backend_smtp_tls")]
+    #[serde(default)]
+    smtp_tls: bool,
+    #[serde(rename = "l2w_backend_smtp_login")]
+    smtp_login: Option<String>,
+    #[serde(rename = "l2w_backend_smtp_password")]
+    smtp_password: Option<String>,
+    #[serde(rename = "l2w_backend_smtp_from")]
+    smtp_from: Option<String>,
+
+    /**
+    Публичный адрес этого инстанса, например `https://pay.la2world.ru`. Из него строятся
+    адреса возврата на страницу статуса и webhook для платёжек
+     */
+    #[serde(rename = "l2w_backend_public_url")]
+    public_url: Option<String>,
+    /**
+    Идентификатор игрового сервера в метаданных счёта. Отличает счета staging и production
+    в общем магазине
+     */
+    #[serde(rename = "l2w_backend_server_id")]
+    server_id: Option<String>,
+
+    /**
+    Токен для `/api/v1/admin/...`. Без него админские endpoint'ы отключены
+     */
+    #[serde(rename = "l2w_backend_admin_token")]
+    admin_token: Option<String>,
+
+    #[serde(rename = "l2w_backend_telegram_bot_token")]
+    telegram_bot_token: Option<String>,
+    #[serde(rename = "l2w_backend_telegram_chat_id")]
+    telegram_chat_id: Option<String>,
+    #[serde(rename = "l2w_backend_discord_webhook_url")]
+    discord_webhook_url: Option<String>,
+    #[serde(rename = "l2w_backend_notify_large_payment")]
+    #[serde(default = "default_notify_large_payment")]
+    notify_large_payment: f32,
+    #[serde(rename = "l2w_backend_notify_failed_spike_count")]
+    #[serde(default = "default_notify_failed_spike_count")]
+    notify_failed_spike_count: usize,
+    #[serde(rename = "l2w_backend_notify_failed_spike_minutes")]
+    #[serde(default = "default_notify_failed_spike_minutes")]
+    notify_failed_spike_minutes: u64,
+    #[serde(rename = "l2w_backend_notify_delivery_stuck_minutes")]
+    #[serde(default = "default_notify_delivery_stuck_minutes")]
+    notify_delivery_stuck_minutes: i64,
+
+    /**
+    Запасные платёжки через запятую, например `Enot:Paypalych,Paypalych:Enot`. Пробуются по
+    порядку, если основная не смогла создать счёт или деградировала
+     */
+    #[serde(rename = "l2w_backend_fallback_chain")]
+    #[serde(default, deserialize_with = "comma_vec_from_str")]
+    fallback_chain: Vec<FallbackRule>,
+    #[serde(rename = "l2w_backend_provider_health_window_minutes")]
+    #[serde(default = "default_provider_health_window_minutes")]
+    provider_health_window_minutes: u64,
+    /**
+    Доля ошибок создания счёта за окно, после которой платёжка считается деградировавшей
+     */
+    #[serde(rename = "l2w_backend_provider_health_max_error_rate")]
+    #[serde(default = "default_provider_health_max_error_rate")]
+    provider_health_max_error_rate: f64,
+    #[serde(rename = "l2w_backend_provider_health_max_latency_ms")]
+    #[serde(default = "default_provider_health_max_latency_ms")]
+    provider_health_max_latency_ms: u64,
+
+    #[serde(rename = "l2w_backend_http_connect_timeout_ms")]
+    #[serde(default = "default_http_connect_timeout_ms")]
+    http_connect_timeout_ms: u64,
+    /**
+    Таймаут одного запроса к платёжке целиком, вместе с чтением ответа
+     */
+    #[serde(rename = "l2w_backend_http_timeout_ms")]
+    #[serde(default = "default_http_timeout_ms")]
+    http_timeout_ms: u64,
+    /**
+    Сколько раз всего пробуем создать счёт в платёжке, включая первую попытку
+     */
+    #[serde(rename = "l2w_backend_http_retry_attempts")]
+    #[serde(default = "default_http_retry_attempts")]
+    http_retry_attempts: u32,
+    #[serde(rename = "l2w_backend_http_retry_base_ms")]
+    #[serde(default = "default_http_retry_base_ms")]
+    http_retry_base_ms: u64,
+    #[serde(rename = "l2w_backend_circuit_breaker_failures")]
+    #[serde(default = "default_circuit_breaker_failures")]
+    circuit_breaker_failures: u32,
+    #[serde(rename = "l2w_backend_circuit_breaker_cooldown_secs")]
+    #[serde(default = "default_circuit_breaker_cooldown_secs")]
+    circuit_breaker_cooldown_secs: u64,
+    /**
+    Общий лимит на создание счёта со всеми повторами и запасными платёжками
+     */
+    #[serde(rename = "l2w_backend_create_invoice_deadline_ms")]
+    #[serde(default = "default_create_invoice_deadline_ms")]
+    create_invoice_deadline_ms: u64,
+
+    /**
+    Сколько фоновых задач (выдача, парсинг голосов, рассылки) выполняется одновременно
+     */
+    #[serde(rename = "l2w_backend_job_workers")]
+    #[serde(default = "default_job_workers")]
+    job_workers: usize,
+
+    #[serde(rename = "l2w_backend_rate_limit_ip_burst")]
+    #[serde(default = "default_rate_limit_ip_burst")]
+    rate_limit_ip_burst: u32,
+    #[serde(rename = "l2w_backend_rate_limit_ip_per_minute")]
+    #[serde(default = "default_rate_limit_ip_per_minute")]
+    rate_limit_ip_per_minute: u32,
+    #[serde(rename = "l2w_backend_rate_limit_char_burst")]
+    #[serde(default = "default_rate_limit_char_burst")]
+    rate_limit_char_burst: u32,
+    #[serde(rename = "l2w_backend_rate_limit_char_per_minute")]
+    #[serde(default = "default_rate_limit_char_per_minute")]
+    rate_limit_char_per_minute: u32,
+
+    /**
+    off - без проверки, always - для каждого счёта, adaptive - только для IP,
+    израсходовавших половину лимита создания счетов
+     */
+    #[serde(rename = "l2w_backend_pow_mode")]
+    #[serde(default)]
+    pow_mode: PowMode,
+    #[serde(rename = "l2w_backend_pow_difficulty")]
+    #[serde(default = "default_pow_difficulty")]
+    pow_difficulty: u8,
+}
+
+fn default_trusted_proxies() -> Vec<Cidr> {
+    vec![
+        Cidr::from_str("127.0.0.1/32").unwrap(),
+        Cidr::from_str("::1/128").unwrap(),
+    ]
+}
+
+fn default_cart_reservation_minutes() -> u32 {
+    60
 }
 
-fn ip_vec_from_str<'de, D>(deserializer: D) -> Result<Vec<IpAddr>, D::Error>
+fn default_notify_large_payment() -> f32 {
+    10000.0
+}
+
+fn default_notify_failed_spike_count() -> usize {
+    5
+}
+
+fn default_notify_failed_spike_minutes() -> u64 {
+    10
+}
+
+fn default_notify_delivery_stuck_minutes() -> i64 {
+    15
+}
+
+fn default_provider_health_window_minutes() -> u64 {
+    10
+}
+
+fn default_provider_health_max_error_rate() -> f64 {
+    0.5
+}
+
+fn default_provider_health_max_latency_ms() -> u64 {
+    5000
+}
+
+fn default_http_connect_timeout_ms() -> u64 {
+    3000
+}
+
+fn default_http_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_http_retry_attempts() -> u32 {
+    3
+}
+
+fn default_http_retry_base_ms() -> u64 {
+    300
+}
+
+fn default_circuit_breaker_failures() -> u32 {
+    5
+}
+
+fn default_circuit_breaker_cooldown_secs() -> u64 {
+    30
+}
+
+fn default_create_invoice_deadline_ms() -> u64 {
+    25000
+}
+
+fn default_job_workers() -> usize {
+    4
+}
+
+fn default_rate_limit_ip_burst() -> u32 {
+    10
+}
+
+fn default_rate_limit_ip_per_minute() -> u32 {
+    5
+}
+
+fn default_rate_limit_char_burst() -> u32 {
+    5
+}
+
+fn default_rate_limit_char_per_minute() -> u32 {
+    2
+}
+
+fn default_pow_difficulty() -> u8 {
+    18
+}
+
+fn default_webhook_max_age_hours() -> i64 {
+    48
+}
+
+fn comma_vec_from_str<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
 where
     D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
 {
     let binding = String::deserialize(deserializer)?;
     let binding = binding.replace(' ', "");
 
-    let s: Vec<&str> = binding.split(',').collect();
-
-    Ok(s.iter().map(|v| IpAddr::from_str(v).unwrap()).collect())
+    binding
+        .split(',')
+        .filter(|v| !v.is_empty())
+        .map(|v| T::from_str(v).map_err(serde::de::Error::custom))
+        .collect()
 }
 
 pub async fn get_db() -> tokio::sync::RwLockReadGuard<'static, DatabaseConnection> {
@@ -121,6 +430,18 @@ async fn main() {
         .unwrap();
     let addr = SocketAddr::from(([127, 0, 0, 1], 14082));
 
+    for (service, allowed) in [
+        (PaymentServices::Enot, &CONFIG.enot_allowed_ips),
+        (PaymentServices::Hotskins, &CONFIG.hotskins_allowed_ips),
+        (PaymentServices::Paypalych, &CONFIG.paypalich_allowed_ips),
+        (PaymentServices::PaypalychUk, &CONFIG.paypalich_uk_allowed_ips),
+    ] {
+        // Иначе все webhook платёжки молча отклоняются и оплаченные счета не выдаются
+        if allowed.is_empty() {
+            panic!("{service} allowed ips list is empty, set 0.0.0.0/0,::/0 to allow any");
+        }
+    }
+
     let app = Router::new()
         .route("/webhook/enot/invoice", post(enot_invoice_webhook))
         .route("/webhook/hotskins/invoice", post(hotskins_invoice_webhook))
@@ -133,6 +454,27 @@ async fn main() {
             post(paypalich_uk_invoice_webhook),
         )
         .route("/api/v1/payments/create", post(create_invoice))
+        .route("/api/v1/payments/methods", get(get_pay_methods))
+        .route("/api/v1/payments/currencies", get(get_currencies))
+        .route("/api/v1/payments/providers", get(get_providers))
+        .route("/api/v1/payments/:order_id", get(get_invoice))
+        .route("/api/v1/characters/:name", get(get_character))
+        .route("/api/v1/catalog", get(get_catalog))
+        .route("/api/v1/admin/reports/revenue", get(get_revenue_report))
+        .route("/api/v1/admin/reports/conversion", get(get_conversion_report))
+        .route("/api/v1/admin/reports/top_donors", get(get_top_donors_report))
+        .route(
+            "/api/v1/admin/reconciliation",
+            get(get_reconciliation_reports).post(import_reconciliation_statement),
+        )
+        .route(
+            "/api/v1/admin/reconciliation/:report_id",
+            get(get_reconciliation_report),
+        )
+        .route(
+            "/api/v1/admin/reconciliation/:report_id/discrepancies/:discrepancy_id/resolve",
+            post(resolve_discrepancy),
+        )
         .fallback_service(get(|req: Request<Body>| async move {
             let res = ServeDir::new("./dist").oneshot(req).await.unwrap(); // serve dir is infallible
             let status = res.status();
@@ -143,7 +485,7 @@ async fn main() {
         }))
         .layer(tower_http::cors::CorsLayer::permissive());
 
-    spawn_tasks();
+    spawn_job_workers();
 
     axum_server::bind_rustls(addr, config)
         .serve(app.into_make_service_with_connect_info::<SocketAddr>())
@@ -0,0 +1,27 @@
+use async_trait::async_trait;
+use serde::Serialize;
+
+use crate::notifier::NotificationSink;
+
+pub struct DiscordSink {
+    pub webhook_url: String,
+}
+
+#[derive(Serialize)]
+struct WebhookParams<'a> {
+    content: &'a str,
+}
+
+#[async_trait]
+impl NotificationSink for DiscordSink {
+    async fn send(&self, text: &str) -> anyhow::Result<()> {
+        reqwest::Client::new()
+            .post(&self.webhook_url)
+            .json(&WebhookParams { content: text })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,366 @@
+pub mod discord;
+pub mod telegram;
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use shared::PaymentServices;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::notifier::discord::DiscordSink;
+use crate::notifier::telegram::TelegramSink;
+use crate::CONFIG;
+
+lazy_static! {
+    pub static ref NOTIFIER: Notifier = Notifier::from_config();
+}
+
+/**
+Одинаковые уведомления (с одним ключом) отправляются не чаще этого интервала
+ */
+const SAME_KEY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_PER_MINUTE: usize = 20;
+
+#[async_trait]
+pub trait NotificationSink: Send + Sync {
+    async fn send(&self, text: &str) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Clone)]
+pub enum Notification {
+    LargePayment {
+        order_id: Uuid,
+        char_name: String,
+        amount: f32,
+        service: PaymentServices,
+    },
+    FailedToCreateSpike {
+        count: usize,
+        minutes: u64,
+        last_reason: String,
+    },
+    InvalidSignature {
+        service: PaymentServices,
+    },
+    RejectedCallback {
+        service: PaymentServices,
+        reason: String,
+    },
+    DeliveryStuck {
+        order_id: Uuid,
+        char_name: String,
+        minutes: i64,
+    },
+    Refunded {
+        order_id: Uuid,
+        char_name: String,
+        amount: f32,
+        service: PaymentServices,
+        chargeback: bool,
+        delivered: bool,
+    },
+    VoteScrapperFailed {
+        error: String,
+    },
+}
+
+impl Notification {
+    fn key(&self) -> String {
+        match self {
+            Notification::LargePayment { order_id, .. } => format!("large_payment:{order_id}"),
+            Notification::FailedToCreateSpike { .. } => "failed_to_create".to_string(),
+            Notification::InvalidSignature { service } => format!("invalid_signature:{service}"),
+            Notification::RejectedCallback { service, reason } => {
+                format!("rejected_callback:{service}:{reason}")
+            }
+            Notification::DeliveryStuck { order_id, .. } => format!("delivery_stuck:{order_id}"),
+            Notification::Refunded { order_id, .. } => format!("refunded:{order_id}"),
+            Notification::VoteScrapperFailed { .. } => "vote_scrapper".to_string(),
+        }
+    }
+}
+
+impl Display for Notification {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Notification::LargePayment {
+                order_id,
+                char_name,
+                amount,
+                service,
+            } => write!(
+                f,
+                "💰 Крупный платёж: {amount} ({service}) от {char_name}, заказ {order_id}"
+            ),
+            Notification::FailedToCreateSpike {
+                count,
+                minutes,
+                last_reason,
+            } => write!(
+                f,
+                "⚠️ {count} ошибок создания счёта за {minutes} мин. Последняя: {last_reason}"
+            ),
+            Notification::InvalidSignature { service } => {
+                write!(f, "🚨 Webhook {service} с неверной подписью")
+            }
+            Notification::RejectedCallback { service, reason } => {
+                write!(f, "🚫 Отклонён webhook {service}, нужна ручная проверка: {reason}")
+            }
+            Notification::DeliveryStuck {
+                order_id,
+                char_name,
+                minutes,
+            } => write!(
+                f,
+                "⏳ Заказ {order_id} для {char_name} оплачен, но не доставлен уже {minutes} мин."
+            ),
+            Notification::Refunded {
+                order_id,
+                char_name,
+                amount,
+                service,
+                chargeback,
+                delivered,
+            } => write!(
+                f,
+                "↩️ {} {amount} ({service}) по заказу {order_id} для {char_name}{}",
+                if *chargeback { "Chargeback" } else { "Возврат" },
+                if *delivered {
+                    ", товары уже выданы - нужна ручная проверка"
+                } else {
+                    ""
+                }
+            ),
+            Notification::VoteScrapperFailed { error } => {
+                write!(f, "⚠️ Ошибка получения голосов MMOTOP: {error}")
+            }
+        }
+    }
+}
+
+struct RateLimiter {
+    same_key_interval: Duration,
+    max_per_minute: usize,
+    last_by_key: HashMap<String, Instant>,
+    sent: VecDeque<Instant>,
+}
+
+impl RateLimiter {
+    fn new(same_key_interval: Duration, max_per_minute: usize) -> Self {
+        Self {
+            same_key_interval,
+            max_per_minute,
+            last_by_key: HashMap::new(),
+            sent: VecDeque::new(),
+        }
+    }
+
+    fn check(&mut self, key: &str, now: Instant) -> bool {
+        if let Some(last) = self.last_by_key.get(key) {
+            if now.duration_since(*last) < self.same_key_interval {
+                return false;
+            }
+        }
+
+        while let Some(v) = self.sent.front() {
+            if now.duration_since(*v) < Duration::from_secs(60) {
+                break;
+            }
+            self.sent.pop_front();
+        }
+
+        if self.sent.len() >= self.max_per_minute {
+            return false;
+        }
+
+        self.last_by_key
+            .retain(|_, v| now.duration_since(*v) < self.same_key_interval);
+        self.last_by_key.insert(key.to_string(), now);
+        self.sent.push_back(now);
+
+        true
+    }
+}
+
+struct SpikeDetector {
+    window: Duration,
+    threshold: usize,
+    events: VecDeque<Instant>,
+}
+
+impl SpikeDetector {
+    fn record(&mut self, now: Instant) -> Option<usize> {
+        self.events.push_back(now);
+
+        while let Some(v) = self.events.front() {
+            if now.duration_since(*v) < self.window {
+                break;
+            }
+            self.events.pop_front();
+        }
+
+        (self.events.len() >= self.threshold).then_some(self.events.len())
+    }
+}
+
+pub struct Notifier {
+    sinks: Vec<Box<dyn NotificationSink>>,
+    limiter: Mutex<RateLimiter>,
+    create_failures: Mutex<SpikeDetector>,
+}
+
+impl Notifier {
+    fn new(
+        sinks: Vec<Box<dyn NotificationSink>>,
+        failed_spike_count: usize,
+        failed_spike_minutes: u64,
+    ) -> Self {
+        Self {
+            sinks,
+            limiter: Mutex::new(RateLimiter::new(SAME_KEY_INTERVAL, MAX_PER_MINUTE)),
+            create_failures: Mutex::new(SpikeDetector {
+                window: Duration::from_secs(failed_spike_minutes * 60),
+                threshold: failed_spike_count,
+                events: VecDeque::new(),
+            }),
+        }
+    }
+
+    fn from_config() -> Self {
+        let mut sinks: Vec<Box<dyn NotificationSink>> = vec![];
+
+        if let (Some(bot_token), Some(chat_id)) =
+            (&CONFIG.telegram_bot_token, &CONFIG.telegram_chat_id)
+        {
+            sinks.push(Box::new(TelegramSink {
+                bot_token: bot_token.clone(),
+                chat_id: chat_id.clone(),
+            }));
+        }
+
+        if let Some(webhook_url) = &CONFIG.discord_webhook_url {
+            sinks.push(Box::new(DiscordSink {
+                webhook_url: webhook_url.clone(),
+            }));
+        }
+
+        Self::new(
+            sinks,
+            CONFIG.notify_failed_spike_count,
+            CONFIG.notify_failed_spike_minutes,
+        )
+    }
+
+    pub async fn notify(&self, notification: Notification) {
+        if self.sinks.is_empty() {
+            return;
+        }
+
+        if !self
+            .limiter
+            .lock()
+            .unwrap()
+            .check(&notification.key(), Instant::now())
+        {
+            return;
+        }
+
+        let text = notification.to_string();
+
+        for sink in &self.sinks {
+            if let Err(e) = sink.send(&text).await {
+                println!("Err on send notification {e:#?}");
+            }
+        }
+    }
+
+    pub async fn record_create_failure(&self, reason: &str) {
+        let (count, minutes) = {
+            let mut create_failures = self.create_failures.lock().unwrap();
+
+            (
+                create_failures.record(Instant::now()),
+                create_failures.window.as_secs() / 60,
+            )
+        };
+
+        if let Some(count) = count {
+            self.notify(Notification::FailedToCreateSpike {
+                count,
+                minutes,
+                last_reason: reason.to_string(),
+            })
+            .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Notification, NotificationSink, Notifier};
+    use async_trait::async_trait;
+    use shared::PaymentServices;
+    use std::sync::{Arc, Mutex};
+
+    struct TestSink {
+        messages: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl NotificationSink for TestSink {
+        async fn send(&self, text: &str) -> anyhow::Result<()> {
+            self.messages.lock().unwrap().push(text.to_string());
+            Ok(())
+        }
+    }
+
+    fn test_notifier() -> (Notifier, Arc<Mutex<Vec<String>>>) {
+        let messages = Arc::new(Mutex::new(vec![]));
+
+        let notifier = Notifier::new(
+            vec![Box::new(TestSink {
+                messages: messages.clone(),
+            })],
+            3,
+            10,
+        );
+
+        (notifier, messages)
+    }
+
+    #[tokio::test]
+    async fn test_same_key_rate_limit() {
+        let (notifier, messages) = test_notifier();
+
+        for _ in 0..3 {
+            notifier
+                .notify(Notification::InvalidSignature {
+                    service: PaymentServices::Enot,
+                })
+                .await;
+        }
+
+        notifier
+            .notify(Notification::InvalidSignature {
+                service: PaymentServices::Hotskins,
+            })
+            .await;
+
+        assert_eq!(messages.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failed_to_create_spike() {
+        let (notifier, messages) = test_notifier();
+
+        notifier.record_create_failure("timeout").await;
+        notifier.record_create_failure("timeout").await;
+        assert!(messages.lock().unwrap().is_empty());
+
+        notifier.record_create_failure("timeout").await;
+        assert_eq!(messages.lock().unwrap().len(), 1);
+    }
+}
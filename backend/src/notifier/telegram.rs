@@ -0,0 +1,35 @@
+use async_trait::async_trait;
+use serde::Serialize;
+
+use crate::notifier::NotificationSink;
+
+pub struct TelegramSink {
+    pub bot_token: String,
+    pub chat_id: String,
+}
+
+#[derive(Serialize)]
+struct SendMessageParams<'a> {
+    chat_id: &'a str,
+    text: &'a str,
+}
+
+#[async_trait]
+impl NotificationSink for TelegramSink {
+    async fn send(&self, text: &str) -> anyhow::Result<()> {
+        reqwest::Client::new()
+            .post(format!(
+                "https://api.telegram.org/bot{}/sendMessage",
+                self.bot_token
+            ))
+            .json(&SendMessageParams {
+                chat_id: &self.chat_id,
+                text,
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
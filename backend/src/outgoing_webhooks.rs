@@ -0,0 +1,266 @@
+use chrono::Utc;
+use mongodb::bson::serde_helpers::uuid_1_as_binary;
+use serde::{Deserialize, Serialize};
+use shared::InvoiceInfo;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::get_db;
+use crate::invoice_handler::Invoice;
+use crate::pay_services::sign_256;
+
+const MAX_SEND_ATTEMPTS: u32 = 10;
+const RETRY_BASE_DELAY_SECS: i64 = 30;
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum InvoiceEvent {
+    #[serde(rename = "invoice.created")]
+    Created,
+    #[serde(rename = "invoice.paid")]
+    Paid,
+    #[serde(rename = "invoice.delivered")]
+    Delivered,
+    #[serde(rename = "invoice.aborted")]
+    Aborted,
+    #[serde(rename = "invoice.refunded")]
+    Refunded,
+}
+
+/**
+Подписчик на события счетов. Заводится вручную в коллекции `webhook_subscriber`
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookSubscriber {
+    #[serde(rename = "_id")]
+    #[serde(with = "uuid_1_as_binary")]
+    pub id: Uuid,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<InvoiceEvent>,
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutgoingWebhook {
+    #[serde(rename = "_id")]
+    #[serde(with = "uuid_1_as_binary")]
+    pub id: Uuid,
+    #[serde(with = "uuid_1_as_binary")]
+    pub subscriber_id: Uuid,
+    pub event: InvoiceEvent,
+    pub url: String,
+    pub body: String,
+    pub signature: String,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /**
+    Unix timestamp следующей попытки отправки
+     */
+    pub next_try_at: i64,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    event: InvoiceEvent,
+    sent_at: i64,
+    invoice: &'a InvoiceInfo,
+}
+
+/**
+Ставит событие в очередь для всех подписчиков на него
+ */
+pub async fn dispatch_invoice_event(invoice: &Invoice, event: InvoiceEvent) {
+    let subscribers = match get_db().await.get_webhook_subscribers().await {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Err on get webhook subscribers {e:#?}");
+            return;
+        }
+    };
+
+    let info = invoice.to_info();
+    let now = Utc::now().timestamp();
+
+    for subscriber in subscribers
+        .into_iter()
+        .filter(|v| v.enabled && v.events.contains(&event))
+    {
+        let webhook = build_webhook(subscriber, event, &info, now);
+
+        if let Err(e) = get_db().await.create_outgoing_webhook(webhook).await {
+            println!("Err on enqueue webhook {e:#?}");
+        }
+    }
+}
+
+fn build_webhook(
+    subscriber: WebhookSubscriber,
+    event: InvoiceEvent,
+    info: &InvoiceInfo,
+    now: i64,
+) -> OutgoingWebhook {
+    let body = serde_json::to_string(&WebhookPayload {
+        event,
+        sent_at: now,
+        invoice: info,
+    })
+    .unwrap();
+
+    OutgoingWebhook {
+        id: Uuid::new_v4(),
+        subscriber_id: subscriber.id,
+        event,
+        url: subscriber.url,
+        signature: sign_256(&subscriber.secret, &body),
+        body,
+        attempts: 0,
+        last_error: None,
+        next_try_at: now,
+    }
+}
+
+pub async fn send_pending_webhooks() {
+    let now = Utc::now().timestamp();
+
+    let webhooks = match get_db().await.get_pending_webhooks(now).await {
+        Ok(v) => v,
+        Err(e) => {
+            println!("Err on get pending webhooks {e:#?}");
+            return;
+        }
+    };
+
+    for webhook in webhooks {
+        let res = match send(&webhook).await {
+            Ok(_) => get_db().await.delete_outgoing_webhook(webhook.id).await,
+            Err(e) => {
+                let mut webhook = webhook;
+
+                if register_failure(&mut webhook, e.to_string(), now) {
+                    println!("Webhook {} moved to dead letter queue: {e}", webhook.id);
+
+                    get_db().await.move_webhook_to_dead_letter(webhook).await
+                } else {
+                    get_db().await.update_outgoing_webhook(webhook).await
+                }
+            }
+        };
+
+        if let Err(e) = res {
+            println!("Err on update webhook {e:#?}");
+        }
+    }
+}
+
+/**
+Записывает неудачную попытку и назначает следующую. `true`, если попытки кончились
+и webhook пора переносить в dead letter
+ */
+fn register_failure(webhook: &mut OutgoingWebhook, error: String, now: i64) -> bool {
+    webhook.attempts += 1;
+    webhook.last_error = Some(error);
+
+    if webhook.attempts >= MAX_SEND_ATTEMPTS {
+        return true;
+    }
+
+    webhook.next_try_at = now + RETRY_BASE_DELAY_SECS * (1 << webhook.attempts.min(10));
+
+    false
+}
+
+async fn send(webhook: &OutgoingWebhook) -> anyhow::Result<()> {
+    reqwest::Client::new()
+        .post(&webhook.url)
+        .timeout(SEND_TIMEOUT)
+        .header("Content-Type", "application/json")
+        .header("X-Event", event_name(webhook.event))
+        .header("X-Signature-256", &webhook.signature)
+        .body(webhook.body.clone())
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+fn event_name(event: InvoiceEvent) -> &'static str {
+    match event {
+        InvoiceEvent::Created => "invoice.created",
+        InvoiceEvent::Paid => "invoice.paid",
+        InvoiceEvent::Delivered => "invoice.delivered",
+        InvoiceEvent::Aborted => "invoice.aborted",
+        InvoiceEvent::Refunded => "invoice.refunded",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        build_webhook, register_failure, InvoiceEvent, OutgoingWebhook, WebhookSubscriber,
+    };
+    use crate::pay_services::sign_256;
+    use shared::{Currency, InvoiceInfo, InvoiceStatus, PaymentServices};
+    use uuid::Uuid;
+
+    fn webhook() -> OutgoingWebhook {
+        let subscriber = WebhookSubscriber {
+            id: Uuid::new_v4(),
+            url: "https://example.com/hook".to_string(),
+            secret: "key".to_string(),
+            events: vec![InvoiceEvent::Paid],
+            enabled: true,
+        };
+
+        let info = InvoiceInfo {
+            order_id: Uuid::nil().to_string(),
+            char_name: "Player".to_string(),
+            service: PaymentServices::Enot,
+            amount: 100.0,
+            currency: Currency::RUB,
+            status: InvoiceStatus::Payed,
+            error: None,
+            lines: vec![],
+        };
+
+        build_webhook(subscriber, InvoiceEvent::Paid, &info, 1700000000)
+    }
+
+    #[test]
+    fn test_signature() {
+        // Известный вектор HMAC-SHA256
+        assert_eq!(
+            sign_256("key", "The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+
+        let webhook = webhook();
+
+        assert!(webhook
+            .body
+            .starts_with(r#"{"event":"invoice.paid","sent_at":1700000000,"invoice":{"#));
+        assert_eq!(webhook.signature, sign_256("key", &webhook.body));
+    }
+
+    #[test]
+    fn test_retry_schedule() {
+        let mut webhook = webhook();
+
+        assert!(!register_failure(&mut webhook, "timeout".to_string(), 1000));
+        assert_eq!(webhook.attempts, 1);
+        assert_eq!(webhook.next_try_at, 1060);
+        assert_eq!(webhook.last_error.as_deref(), Some("timeout"));
+
+        assert!(!register_failure(&mut webhook, "timeout".to_string(), 1000));
+        assert_eq!(webhook.next_try_at, 1120);
+
+        for _ in 2..9 {
+            assert!(!register_failure(&mut webhook, "500".to_string(), 1000));
+        }
+
+        // Десятая неудача - в dead letter
+        assert!(register_failure(&mut webhook, "500".to_string(), 1000));
+        assert_eq!(webhook.attempts, 10);
+    }
+}
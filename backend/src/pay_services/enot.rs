@@ -2,18 +2,21 @@
 #![allow(clippy::upper_case_acronyms)]
 
 use anyhow::Result;
-use axum::Json;
 use chrono::{DateTime, NaiveDateTime, Utc};
-use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::str::FromStr;
 
-use serde::{Deserialize, Serialize};
-use serde_json::{Map, Value};
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Number, Value};
 use serde_with::skip_serializing_none;
+use shared::{Currency, CurrencyRate, PayMethod};
 use uuid::Uuid;
 
-use crate::pay_services::{validate_signature_256, ProceedInvoiceError};
+use crate::invoice_handler::InvoiceSettlement;
+use crate::pay_services::{
+    deserialize_metadata, validate_signature_256, InvoiceMetadata, ProceedInvoiceError,
+};
 use crate::CONFIG;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -36,8 +39,21 @@ enum PaymentCurrency {
     BTC_CASH,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-enum PaymentMethod {
+impl From<Currency> for PaymentCurrency {
+    fn from(value: Currency) -> Self {
+        match value {
+            Currency::RUB => PaymentCurrency::RUB,
+            Currency::USD => PaymentCurrency::USD,
+            Currency::EUR => PaymentCurrency::EUR,
+            Currency::UAH => PaymentCurrency::UAH,
+            Currency::KZT => PaymentCurrency::KZT,
+            Currency::USDT => PaymentCurrency::USDT_TRC20,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub(crate) enum PaymentMethod {
     /**Банковская карта*/
     #[serde(rename = "card")]
     Card,
@@ -100,8 +116,98 @@ enum PaymentMethod {
     P2PCard,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct CustomFields {}
+impl PaymentMethod {
+    /**
+    Название метода в том виде, в котором его присылает Enot
+     */
+    fn name(&self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for PaymentMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(Value::String(s.to_string()))
+            .map_err(|_| format!("Unknown Enot payment method {s}"))
+    }
+}
+
+/**
+Методы Enot, которые открываются при выборе способа оплаты на нашей странице
+ */
+fn methods_for(pay_method: PayMethod) -> &'static [PaymentMethod] {
+    match pay_method {
+        PayMethod::Card => &[PaymentMethod::Card],
+        PayMethod::Sbp => &[PaymentMethod::SBP],
+        PayMethod::Crypto => &[
+            PaymentMethod::Bitcoin,
+            PaymentMethod::Ethereum,
+            PaymentMethod::Dash,
+            PaymentMethod::Litecoin,
+            PaymentMethod::UsdtTrc20,
+            PaymentMethod::UsdtErc20,
+            PaymentMethod::Trx,
+            PaymentMethod::Ton,
+            PaymentMethod::BitcoinCash,
+            PaymentMethod::Zcash,
+        ],
+        PayMethod::YooMoney => &[PaymentMethod::YooMoney],
+    }
+}
+
+/**
+Методы Enot для `include_service`, кроме отключённых в конфиге
+ */
+fn enabled_methods_for(pay_method: PayMethod, disabled: &[PaymentMethod]) -> Vec<PaymentMethod> {
+    methods_for(pay_method)
+        .iter()
+        .filter(|v| !disabled.contains(v))
+        .copied()
+        .collect()
+}
+
+/**
+Способы оплаты, у которых остался хотя бы один включённый метод Enot
+ */
+pub fn available_pay_methods() -> Vec<PayMethod> {
+    [
+        PayMethod::Card,
+        PayMethod::Sbp,
+        PayMethod::Crypto,
+        PayMethod::YooMoney,
+    ]
+    .into_iter()
+    .filter(|v| !enabled_methods_for(*v, &CONFIG.enot_disabled_methods).is_empty())
+    .collect()
+}
+
+/**
+Валюты, в которых можно оплатить CRD через Enot. Рубли доступны всегда, остальные -
+если для них задан курс в конфиге
+ */
+pub fn available_currencies() -> Vec<CurrencyRate> {
+    currencies_with_rates(&CONFIG.enot_currency_rates)
+}
+
+fn currencies_with_rates(rates: &[CurrencyRate]) -> Vec<CurrencyRate> {
+    let mut res = vec![CurrencyRate {
+        currency: Currency::RUB,
+        crd_rate: 1.0,
+    }];
+
+    for rate in rates {
+        if !res.iter().any(|v| v.currency == rate.currency) {
+            res.push(*rate);
+        }
+    }
+
+    res
+}
 
 #[skip_serializing_none]
 #[derive(Serialize)]
@@ -146,7 +252,7 @@ struct CreateInvoiceParams {
     string JSON
     max:500
      */
-    custom_fields: Option<CustomFields>,
+    custom_fields: Option<InvoiceMetadata>,
 
     /**
     Назначение платежа (показывается клиенту при оплате)
@@ -277,7 +383,8 @@ struct RawIncomingInvoice {
     /**
     Строка, которую вы передавали в параметрах при создании платежа
      */
-    custom_fields: Option<CustomFields>,
+    #[serde(default, deserialize_with = "deserialize_metadata")]
+    custom_fields: Option<InvoiceMetadata>,
 
     /**
     Тип хуки
@@ -333,37 +440,21 @@ struct RawIncomingInvoice {
 }
 
 impl RawIncomingInvoice {
-    fn from_data(body: Json<Value>, hash: &str) -> Result<Self> {
-        let mut raw_body = String::new();
+    fn from_data(body: &[u8], hash: &str) -> Result<Self> {
+        let value: Value = serde_json::from_slice(body)
+            .map_err(|e| ProceedInvoiceError::MalformedBody(e.to_string()))?;
 
-        println!("{}", body.0);
+        let raw_body = std::str::from_utf8(body)
+            .map_err(|e| ProceedInvoiceError::MalformedBody(e.to_string()))?;
 
-        let mut c: BTreeMap<String, Value> = BTreeMap::new();
+        if !validate_signature_256(hash, &CONFIG.enot_public, raw_body)
+            && !validate_signature_256(hash, &CONFIG.enot_public, &canonical_body(body)?)
         {
-            let raw_body: Map<String, Value> = body.0.as_object().unwrap().clone();
-            for r in raw_body {
-                c.insert(r.0, r.1);
-            }
+            return Err(ProceedInvoiceError::InvalidSignature.into());
         }
 
-        raw_body.push('{');
-        for (i, v) in c.iter().enumerate() {
-            raw_body.push_str(&format!(r#""{}":{}"#, v.0, v.1));
-            if i < c.len() - 1 {
-                raw_body.push(',');
-            }
-        }
-        raw_body.push('}');
-
-        println!("{raw_body}");
-
-        if validate_signature_256(hash, &CONFIG.enot_public, &raw_body)? {
-            let s = serde_json::from_value(body.0).unwrap();
-
-            return Ok(s);
-        }
-
-        Err(ProceedInvoiceError::InvalidSignature.into())
+        Ok(serde_json::from_value(value)
+            .map_err(|e| ProceedInvoiceError::MalformedBody(e.to_string()))?)
     }
 
     pub fn into_invoice_data(self) -> Result<InvoiceUpdate> {
@@ -496,6 +587,189 @@ impl RawIncomingInvoice {
     }
 }
 
+/**
+JSON с сохранением порядка ключей, как его видит `json_decode` PHP
+ */
+#[derive(Debug, PartialEq)]
+enum OrderedJson {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+    Array(Vec<OrderedJson>),
+    Object(Vec<(String, OrderedJson)>),
+}
+
+impl<'de> Deserialize<'de> for OrderedJson {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OrderedJsonVisitor;
+
+        impl<'de> Visitor<'de> for OrderedJsonVisitor {
+            type Value = OrderedJson;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("any JSON value")
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+                Ok(OrderedJson::Null)
+            }
+
+            fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+                Ok(OrderedJson::Bool(v))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                Ok(OrderedJson::Number(v.into()))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                Ok(OrderedJson::Number(v.into()))
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                Number::from_f64(v)
+                    .map(OrderedJson::Number)
+                    .ok_or_else(|| E::custom("Invalid number"))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(OrderedJson::String(v.to_string()))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut res = vec![];
+                while let Some(v) = seq.next_element()? {
+                    res.push(v);
+                }
+                Ok(OrderedJson::Array(res))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut res = vec![];
+                while let Some(v) = map.next_entry()? {
+                    res.push(v);
+                }
+                Ok(OrderedJson::Object(res))
+            }
+        }
+
+        deserializer.deserialize_any(OrderedJsonVisitor)
+    }
+}
+
+/**
+Тело webhook в том виде, от которого Enot считает подпись:
+```php
+$hookArr = json_decode($hookJson, true);
+ksort($hookArr);
+$hookJsonSorted = json_encode($hookArr);
+```
+Сортируются только ключи верхнего уровня, вложенные объекты сохраняют порядок
+ */
+fn canonical_body(body: &[u8]) -> Result<String, ProceedInvoiceError> {
+    let OrderedJson::Object(mut object) = serde_json::from_slice(body)
+        .map_err(|e| ProceedInvoiceError::MalformedBody(e.to_string()))?
+    else {
+        return Err(ProceedInvoiceError::MalformedBody(
+            "Expected JSON object".to_string(),
+        ));
+    };
+
+    object.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut out = String::new();
+    php_json_encode(&OrderedJson::Object(object), &mut out);
+
+    Ok(out)
+}
+
+/**
+`json_encode` PHP с флагами по умолчанию
+ */
+fn php_json_encode(value: &OrderedJson, out: &mut String) {
+    match value {
+        OrderedJson::Null => out.push_str("null"),
+        OrderedJson::Bool(v) => out.push_str(if *v { "true" } else { "false" }),
+        OrderedJson::Number(v) => php_encode_number(v, out),
+        OrderedJson::String(v) => php_encode_str(v, out),
+        OrderedJson::Array(v) => {
+            out.push('[');
+            for (i, v) in v.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                php_json_encode(v, out);
+            }
+            out.push(']');
+        }
+        // json_decode($json, true) превращает пустой объект в пустой массив
+        OrderedJson::Object(v) if v.is_empty() => out.push_str("[]"),
+        OrderedJson::Object(v) => {
+            out.push('{');
+            for (i, (k, v)) in v.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                php_encode_str(k, out);
+                out.push(':');
+                php_json_encode(v, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn php_encode_number(v: &Number, out: &mut String) {
+    if v.is_f64() {
+        // PHP (serialize_precision = -1) печатает float кратчайшим представлением,
+        // но всегда с дробной частью и с явным знаком экспоненты: 100.0, 1.0e+25
+        let f = v.as_f64().unwrap_or_default();
+        let repr = format!("{f:?}");
+
+        match repr.split_once('e') {
+            Some((mantissa, exp)) => {
+                out.push_str(mantissa);
+                if !mantissa.contains('.') {
+                    out.push_str(".0");
+                }
+                out.push('e');
+                if !exp.starts_with('-') {
+                    out.push('+');
+                }
+                out.push_str(exp);
+            }
+            None => out.push_str(&repr),
+        }
+    } else {
+        out.push_str(&v.to_string());
+    }
+}
+
+fn php_encode_str(v: &str, out: &mut String) {
+    out.push('"');
+    for c in v.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '/' => out.push_str("\\/"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0c}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || !c.is_ascii() => {
+                let mut buf = [0u16; 2];
+                for unit in c.encode_utf16(&mut buf) {
+                    out.push_str(&format!("\\u{unit:04x}"));
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
 #[derive(Debug)]
 enum InvoiceUpdate {
     SucceedPayment(SucceedPayment),
@@ -539,7 +813,7 @@ struct SucceedPayment {
     /**
     Строка, которую вы передавали в параметрах при создании платежа
      */
-    custom_fields: Option<CustomFields>,
+    custom_fields: Option<InvoiceMetadata>,
 
     /**
     Сумма зачисленная вам на баланс (В рублях) (В случае успеха)
@@ -552,6 +826,30 @@ struct SucceedPayment {
     pay_time: NaiveDateTime,
 }
 
+impl SucceedPayment {
+    fn settlement(&self) -> InvoiceSettlement {
+        let gross_currency = match self.currency {
+            PaymentCurrency::RUB => None,
+            ref v => Some(format!("{v:?}")),
+        };
+
+        InvoiceSettlement {
+            gross: self.amount,
+            gross_currency,
+            // Курс конвертации Enot не присылает, комиссию считаем только для рублей
+            fee: match self.currency {
+                PaymentCurrency::RUB => self.amount - self.credited,
+                _ => 0.0,
+            },
+            net: self.credited,
+            // credited всегда в рублях
+            currency: "RUB".to_string(),
+            pay_method: self.pay_service.name(),
+            pay_time: self.pay_time.and_utc(),
+        }
+    }
+}
+
 #[derive(Debug)]
 enum CloseStatus {
     /**
@@ -589,7 +887,7 @@ struct RejectedPayment {
     /**
     Строка, которую вы передавали в параметрах при создании платежа
      */
-    custom_fields: Option<CustomFields>,
+    custom_fields: Option<InvoiceMetadata>,
 
     /**
     Статус код
@@ -631,7 +929,7 @@ struct SucceedRefund {
     /**
     Строка, которую вы передавали в параметрах при создании платежа
      */
-    custom_fields: Option<CustomFields>,
+    custom_fields: Option<InvoiceMetadata>,
 
     /**
     Сумма возврата (В случае возврата)
@@ -685,7 +983,7 @@ struct RejectedRefund {
     /**
     Строка, которую вы передавали в параметрах при создании платежа
      */
-    custom_fields: Option<CustomFields>,
+    custom_fields: Option<InvoiceMetadata>,
 }
 
 pub(crate) mod handler {
@@ -694,45 +992,64 @@ pub(crate) mod handler {
         PaymentServiceCreateInvoiceResponse,
     };
     use crate::pay_services::enot::{
-        CreateInvoiceParams, CreateInvoiceResponse, InvoiceUpdate, PaymentCurrency,
-        RawIncomingInvoice, ResponseWrapper,
+        enabled_methods_for, CreateInvoiceParams, CreateInvoiceResponse, InvoiceUpdate,
+        PaymentCurrency, RawIncomingInvoice, ResponseWrapper,
     };
+    use crate::pay_services::http::HTTP_CLIENT;
+    use crate::pay_services::{hook_url, return_url, CreateInvoiceError, InvoiceMetadata};
     use crate::CONFIG;
 
     use anyhow::Result;
-    use axum::Json;
     use reqwest::header::HeaderMap;
     use reqwest::{RequestBuilder, Response, StatusCode};
-    use serde_json::Value;
+    use shared::{Currency, PayMethod, PaymentServices};
     use uuid::Uuid;
 
     pub struct EnotInvoiceHandler {}
 
     impl EnotInvoiceHandler {
-        pub fn create_invoice_request(&self, amount: f32, order_id: Uuid) -> RequestBuilder {
+        pub fn create_invoice_request(
+            &self,
+            amount: f32,
+            order_id: Uuid,
+            currency: Currency,
+            pay_method: Option<PayMethod>,
+            metadata: InvoiceMetadata,
+        ) -> RequestBuilder {
+            let disabled = &CONFIG.enot_disabled_methods;
+
+            // Выбранный способ открывается сразу, иначе показываются все, кроме отключённых
+            let include_service = pay_method
+                .map(|v| enabled_methods_for(v, disabled))
+                .filter(|v| !v.is_empty());
+            let exclude_service = (include_service.is_none() && !disabled.is_empty())
+                .then(|| disabled.clone());
+
+            // Резерв склада держат только корзины, пополнение CRD живёт срок Enot по умолчанию
+            let expire =
+                (!metadata.product_ids.is_empty()).then_some(CONFIG.cart_reservation_minutes);
+
             let params = CreateInvoiceParams {
                 amount,
                 order_id,
-                currency: Some(PaymentCurrency::RUB),
+                currency: Some(PaymentCurrency::from(currency)),
                 shop_id: CONFIG.enot_shop_id,
-                hook_url: None,
-                custom_fields: None,
+                hook_url: hook_url("/webhook/enot/invoice"),
+                custom_fields: Some(metadata),
                 comment: None,
-                fail_url: None,
-                success_url: None,
-                expire: None,
-                include_service: None,
-                exclude_service: None,
+                fail_url: return_url(order_id, false),
+                success_url: return_url(order_id, true),
+                expire,
+                include_service,
+                exclude_service,
             };
 
-            let client = reqwest::Client::new();
-
             let mut headers = HeaderMap::new();
             headers.insert("Accept", "application/json".parse().unwrap());
             headers.insert("Content-Type", "application/json".parse().unwrap());
             headers.insert("x-api-key", CONFIG.enot_secret.parse().unwrap());
 
-            client
+            HTTP_CLIENT
                 .post(&CONFIG.enot_api_url)
                 .headers(headers)
                 .body(serde_json::to_string(&params).unwrap())
@@ -740,7 +1057,7 @@ pub(crate) mod handler {
 
         pub(crate) fn parse_invoice_status_update(
             &self,
-            body: Json<Value>,
+            body: &[u8],
             hash: &str,
         ) -> Result<InvoiceStatusUpdate> {
             let data = RawIncomingInvoice::from_data(body, hash)?.into_invoice_data();
@@ -755,27 +1072,43 @@ pub(crate) mod handler {
                 InvoiceUpdate::SucceedPayment(v) => Ok(InvoiceStatusUpdate {
                     order_id: v.order_id,
                     external_id: v.invoice_id.to_string(),
+                    transaction_id: v.invoice_id.to_string(),
+                    event_time: Some(v.pay_time.and_utc()),
+                    settlement: Some(v.settlement()),
+                    metadata: v.custom_fields,
                     data: InvoiceStatusUpdateData::Payed,
+                    ..Default::default()
                 }),
 
                 InvoiceUpdate::RejectedPayment(v) => Ok(InvoiceStatusUpdate {
                     order_id: v.order_id,
                     external_id: v.invoice_id.to_string(),
+                    transaction_id: v.invoice_id.to_string(),
+                    metadata: v.custom_fields,
+                    event_time: Some(v.reject_time.and_utc()),
                     data: InvoiceStatusUpdateData::Aborted {
                         reason: format!("{:#?}", v.close_status),
                     },
+                    ..Default::default()
                 }),
 
                 InvoiceUpdate::SucceedRefund(v) => Ok(InvoiceStatusUpdate {
                     order_id: v.order_id,
                     external_id: v.invoice_id.to_string(),
+                    transaction_id: v.invoice_id.to_string(),
+                    metadata: v.custom_fields,
+                    event_time: Some(v.refund_time),
                     data: InvoiceStatusUpdateData::None,
+                    ..Default::default()
                 }),
 
                 InvoiceUpdate::RejectedRefund(v) => Ok(InvoiceStatusUpdate {
                     order_id: v.order_id,
                     external_id: v.invoice_id.to_string(),
+                    transaction_id: v.invoice_id.to_string(),
+                    metadata: v.custom_fields,
                     data: InvoiceStatusUpdateData::None,
+                    ..Default::default()
                 }),
             }
         }
@@ -797,37 +1130,21 @@ pub(crate) mod handler {
                             response: PaymentServiceCreateInvoiceResponse::Enot(body.data),
                         },
 
-                        Err(err) => InvoiceData::FailedToCreate {
-                            reason: format!("Can't deserialize response: {err}"),
-                        },
+                        Err(err) => CreateInvoiceError::bad_response(PaymentServices::Enot, err).into(),
                     }
                 }
 
-                StatusCode::UNAUTHORIZED => InvoiceData::FailedToCreate {
-                    reason: "Ошибка авторизации (неверный shop_id или секретный ключ)".to_string(),
-                },
-
-                StatusCode::FORBIDDEN => InvoiceData::FailedToCreate {
-                    reason: "Ошибка доступа (Неверная сумма по сервису, неактивный магазин)"
-                        .to_string(),
-                },
-
-                StatusCode::NOT_FOUND => InvoiceData::FailedToCreate {
-                    reason: "Объект не найден (Не найден тариф для вывода, или он выключен)"
-                        .to_string(),
-                },
-
-                StatusCode::UNPROCESSABLE_ENTITY => InvoiceData::FailedToCreate {
-                    reason: "Ошибка валидации".to_string(),
-                },
-
-                StatusCode::INTERNAL_SERVER_ERROR => InvoiceData::FailedToCreate {
-                    reason: "Внутренняя ошибка системы".to_string(),
-                },
+                code => {
+                    let detail = match code {
+                        StatusCode::UNAUTHORIZED => "wrong shop_id or secret key",
+                        StatusCode::FORBIDDEN => "wrong amount for the method or inactive shop",
+                        StatusCode::NOT_FOUND => "tariff not found or disabled",
+                        StatusCode::UNPROCESSABLE_ENTITY => "validation failed",
+                        _ => "unexpected response",
+                    };
 
-                code => InvoiceData::FailedToCreate {
-                    reason: format!("Unsupported response code: {code}"),
-                },
+                    CreateInvoiceError::from_status(PaymentServices::Enot, code, detail).into()
+                }
             }
         }
     }
@@ -835,14 +1152,194 @@ pub(crate) mod handler {
 
 #[cfg(test)]
 mod tests {
+    use crate::pay_services::enot::{
+        canonical_body, currencies_with_rates, enabled_methods_for, php_encode_str,
+        PaymentCurrency, PaymentMethod, SucceedPayment,
+    };
+    use chrono::NaiveDateTime;
+    use uuid::Uuid;
     use crate::pay_services::validate_signature_256;
+    use proptest::prelude::*;
+    use serde_json::Value;
+    use shared::{Currency, CurrencyRate, PayMethod};
+    use std::str::FromStr;
+
+    const SIGNED_BODY: &str = r#"{"amount":"100.00","credited":"95.50","custom_fields":{"user":1},"invoice_id":"a3e9ff6f-c5c1-3bcd-854e-4bc995b1ae7a","order_id":"c78d8fe9-ab44-3f21-a37a-ce4ca269cb47","pay_service":"card","pay_time":"2023-04-06 16:27:59","payer_details":"553691******1279","status":"success","type":1}"#;
+    const SECRET: &str = "example";
+    const SIGN: &str = "e582b14dd13f8111711e3cb66a982fd7bff28a0ddece8bde14a34a5bb4449136";
+
+    #[test]
+    fn test_enabled_methods() {
+        let disabled = vec![
+            PaymentMethod::from_str("qiwi").unwrap(),
+            PaymentMethod::from_str("bitcoin").unwrap(),
+        ];
+
+        assert!(PaymentMethod::from_str("nonsense").is_err());
+        assert_eq!(
+            enabled_methods_for(PayMethod::Card, &disabled),
+            vec![PaymentMethod::Card]
+        );
+        assert!(!enabled_methods_for(PayMethod::Crypto, &disabled).contains(&PaymentMethod::Bitcoin));
+        assert!(enabled_methods_for(PayMethod::Sbp, &[PaymentMethod::SBP]).is_empty());
+    }
+
+    #[test]
+    fn test_currencies() {
+        let rates = vec![
+            CurrencyRate::from_str("KZT:0.19").unwrap(),
+            CurrencyRate::from_str("RUB:2").unwrap(),
+            CurrencyRate::from_str("KZT:0.5").unwrap(),
+        ];
+
+        assert!(CurrencyRate::from_str("KZT").is_err());
+        assert!(CurrencyRate::from_str("BTC:1").is_err());
+        assert!(CurrencyRate::from_str("USD:-1").is_err());
+
+        // Рубль всегда 1:1, повторы в конфиге игнорируются
+        assert_eq!(
+            currencies_with_rates(&rates),
+            vec![
+                CurrencyRate {
+                    currency: Currency::RUB,
+                    crd_rate: 1.0
+                },
+                CurrencyRate {
+                    currency: Currency::KZT,
+                    crd_rate: 0.19
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_settlement() {
+        let mut payment = SucceedPayment {
+            invoice_id: Uuid::new_v4(),
+            amount: 100.0,
+            currency: PaymentCurrency::RUB,
+            order_id: Uuid::new_v4(),
+            pay_service: PaymentMethod::Card,
+            payer_details: String::new(),
+            custom_fields: None,
+            credited: 95.5,
+            pay_time: NaiveDateTime::default(),
+        };
+
+        let settlement = payment.settlement();
+        assert_eq!(settlement.gross, 100.0);
+        assert_eq!(settlement.gross_currency, None);
+        assert_eq!(settlement.fee, 4.5);
+        assert_eq!(settlement.currency, "RUB");
+
+        // Оплата в тенге, зачисление в рублях
+        payment.amount = 5000.0;
+        payment.currency = PaymentCurrency::KZT;
+        payment.credited = 950.0;
+
+        let settlement = payment.settlement();
+        assert_eq!(settlement.gross, 5000.0);
+        assert_eq!(settlement.gross_currency.as_deref(), Some("KZT"));
+        assert_eq!(settlement.fee, 0.0);
+        assert_eq!(settlement.net, 950.0);
+        assert_eq!(settlement.currency, "RUB");
+    }
 
     #[test]
     fn test_sign_validation() {
-        let body = r#"{"amount":"100.00","credited":"95.50","custom_fields":{"user":1},"invoice_id":"a3e9ff6f-c5c1-3bcd-854e-4bc995b1ae7a","order_id":"c78d8fe9-ab44-3f21-a37a-ce4ca269cb47","pay_service":"card","pay_time":"2023-04-06 16:27:59","payer_details":"553691******1279","status":"success","type":1}"#;
-        let secret = "example";
-        let sign = "e582b14dd13f8111711e3cb66a982fd7bff28a0ddece8bde14a34a5bb4449136";
+        assert!(validate_signature_256(SIGN, SECRET, SIGNED_BODY));
+        assert!(!validate_signature_256(&SIGN.replace('e', "f"), SECRET, SIGNED_BODY));
+        assert!(!validate_signature_256("not hex", SECRET, SIGNED_BODY));
+    }
+
+    #[test]
+    fn test_canonical_body() {
+        let shuffled = r#"{
+            "type": 1, "status": "success", "payer_details": "553691******1279",
+            "pay_time": "2023-04-06 16:27:59", "pay_service": "card",
+            "order_id": "c78d8fe9-ab44-3f21-a37a-ce4ca269cb47",
+            "invoice_id": "a3e9ff6f-c5c1-3bcd-854e-4bc995b1ae7a",
+            "custom_fields": {"user": 1}, "credited": "95.50", "amount": "100.00"
+        }"#;
+
+        let canonical = canonical_body(shuffled.as_bytes()).unwrap();
+
+        assert_eq!(canonical, SIGNED_BODY);
+        assert!(validate_signature_256(SIGN, SECRET, &canonical));
+
+        assert_eq!(
+            canonical_body(r#"{"b": {"z": 1, "a": 2.0}, "a": "Оп/1", "c": {}}"#.as_bytes())
+                .unwrap(),
+            r#"{"a":"\u041e\u043f\/1","b":{"z":1,"a":2.0},"c":[]}"#
+        );
+
+        assert!(canonical_body(b"[1, 2]").is_err());
+        assert!(canonical_body(b"not json").is_err());
+    }
+
+    fn json_leaf() -> impl Strategy<Value = Value> {
+        prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::Bool),
+            any::<i64>().prop_map(Value::from),
+            any::<String>().prop_map(Value::String),
+        ]
+    }
+
+    fn json_fields() -> impl Strategy<Value = Vec<(String, Value)>> {
+        prop::collection::btree_map("[a-z_]{1,12}", json_leaf(), 1..12)
+            .prop_map(|v| v.into_iter().collect())
+    }
+
+    fn to_body(fields: &[(String, Value)], separator: &str) -> String {
+        let fields: Vec<String> = fields
+            .iter()
+            .map(|(k, v)| format!("{}: {v}", Value::from(k.as_str())))
+            .collect();
+
+        format!("{{{}}}", fields.join(separator))
+    }
+
+    proptest! {
+        #[test]
+        fn prop_canonical_ignores_key_order_and_whitespace(
+            fields in json_fields(),
+            rotate in any::<usize>(),
+            reversed in any::<bool>(),
+        ) {
+            let mut shuffled = fields.clone();
+            let len = shuffled.len();
+            shuffled.rotate_left(rotate % len);
+            if reversed {
+                shuffled.reverse();
+            }
+
+            prop_assert_eq!(
+                canonical_body(to_body(&fields, ",").as_bytes()).unwrap(),
+                canonical_body(to_body(&shuffled, ",\n    ").as_bytes()).unwrap()
+            );
+        }
 
-        assert!(validate_signature_256(sign, secret, body).unwrap())
+        #[test]
+        fn prop_canonical_roundtrip(fields in json_fields()) {
+            let body = to_body(&fields, ",");
+            let canonical = canonical_body(body.as_bytes()).unwrap();
+
+            prop_assert!(canonical.is_ascii());
+            prop_assert_eq!(
+                serde_json::from_str::<Value>(&canonical).unwrap(),
+                serde_json::from_str::<Value>(&body).unwrap()
+            );
+        }
+
+        #[test]
+        fn prop_php_string_escaping(v in any::<String>()) {
+            let mut out = String::new();
+            php_encode_str(&v, &mut out);
+
+            prop_assert!(out.is_ascii());
+            prop_assert!(!out.replace("\\\\", "").replace("\\/", "").contains('/'));
+            prop_assert_eq!(serde_json::from_str::<String>(&out).unwrap(), v);
+        }
     }
 }
@@ -1,23 +1,12 @@
-#![allow(clippy::upper_case_acronyms)]
+use std::fmt::Debug;
 
-use std::fmt::{Debug, Display, Formatter};
-
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[allow(non_camel_case_types)]
-enum PaymentCurrency {
-    RUB,
-}
-
-impl Display for PaymentCurrency {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str(match self {
-            PaymentCurrency::RUB => "RUB",
-        })
-    }
-}
+/**
+Единственная валюта, в которой мы принимаем пополнения
+ */
+const SUPPORTED_CURRENCY: &str = "RUB";
 
 #[derive(Deserialize, Debug)]
 pub struct InvoiceUpdate {
@@ -41,13 +30,13 @@ pub struct InvoiceUpdate {
     #[serde(rename = "transaction_id")]
     invoice_id: String,
     /**
-        – сумма купленных скинов у плательщика
+        – сумма купленных скинов у плательщика. Строкой, как пришла: от неё считается подпись
     */
-    amount: f32,
+    amount: String,
     /**
         - код валюты, в которой номинировано значение поля amount
     */
-    currency: PaymentCurrency,
+    currency: String,
     /**
         – подпись запроса
     */
@@ -56,27 +45,28 @@ pub struct InvoiceUpdate {
 
 pub(crate) mod handler {
     use crate::invoice_handler::{
-        InvoiceData, InvoiceStatusUpdate, InvoiceStatusUpdateData,
+        InvoiceData, InvoiceSettlement, InvoiceStatusUpdate, InvoiceStatusUpdateData,
         PaymentServiceCreateInvoiceResponse,
     };
 
-    use crate::pay_services::hotskins::InvoiceUpdate;
+    use crate::pay_services::hotskins::{InvoiceUpdate, SUPPORTED_CURRENCY};
     use crate::pay_services::{validate_signature_1, ProceedInvoiceError};
     use crate::CONFIG;
     use anyhow::Result;
+    use chrono::Utc;
+    use std::str::FromStr;
     use uuid::Uuid;
 
     pub struct HotSkinsInvoiceHandler {}
 
-    static HOTSKINS_EXTERNAL_ID: &str = "hotskins_krivie_uebani";
-
     impl HotSkinsInvoiceHandler {
         /**
         https://hotskins.io/help/category/1
          */
         pub fn create_invoice(&self, order_id: Uuid) -> InvoiceData {
+            // Id транзакции Hotskins становится известен только из webhook
             InvoiceData::WaitingForPayment {
-                external_id: HOTSKINS_EXTERNAL_ID.to_string(),
+                external_id: String::new(),
                 payment_url: format!(
                     "{}/{}/_/_/{}",
                     CONFIG.hotskins_api_url, CONFIG.hotskins_public, order_id
@@ -101,16 +91,49 @@ pub(crate) mod handler {
                 )
             };
 
-            if !validate_signature_1(&data.sign, &CONFIG.hotskins_secret, &body)? {
+            if !validate_signature_1(&data.sign, &CONFIG.hotskins_secret, &body) {
                 return Err(ProceedInvoiceError::InvalidSignature.into());
             }
 
+            if data.currency != SUPPORTED_CURRENCY {
+                return Err(ProceedInvoiceError::UnsupportedCurrency {
+                    currency: data.currency,
+                    order_id: data.order_id,
+                }
+                .into());
+            }
+
+            let amount = match f32::from_str(&data.amount) {
+                Ok(v) if v.is_finite() && v > 0.0 => v,
+                _ => {
+                    return Err(ProceedInvoiceError::WrongFieldType {
+                        field: "amount".to_string(),
+                        field_type: "positive f32".to_string(),
+                    }
+                    .into())
+                }
+            };
+
             Ok(InvoiceStatusUpdate {
                 order_id: data.order_id,
-                external_id: HOTSKINS_EXTERNAL_ID.to_string(),
+                external_id: data.invoice_id.clone(),
+                transaction_id: data.invoice_id,
+                payer_steam_id: data.steam_id,
+                // Комиссию Hotskins не сообщает, на баланс приходит сумма из webhook
+                settlement: Some(InvoiceSettlement {
+                    gross: amount,
+                    gross_currency: None,
+                    fee: 0.0,
+                    net: amount,
+                    currency: SUPPORTED_CURRENCY.to_string(),
+                    pay_method: "skins".to_string(),
+                    pay_time: Utc::now(),
+                }),
                 data: InvoiceStatusUpdateData::PayedWithChangedSum {
-                    new_amount: data.amount,
+                    new_amount: amount,
+                    scale: 1.0,
                 },
+                ..Default::default()
             })
         }
     }
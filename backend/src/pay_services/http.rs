@@ -0,0 +1,232 @@
+use lazy_static::lazy_static;
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use shared::{PaymentError, PaymentServices};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::pay_services::CreateInvoiceError;
+use crate::CONFIG;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /**
+    Общий клиент для API платёжек: один пул соединений и таймауты из конфига
+     */
+    pub static ref HTTP_CLIENT: Client = Client::builder()
+        .connect_timeout(Duration::from_millis(CONFIG.http_connect_timeout_ms))
+        .timeout(Duration::from_millis(CONFIG.http_timeout_ms))
+        .build()
+        .unwrap();
+
+    pub static ref CIRCUIT_BREAKER: CircuitBreaker = CircuitBreaker::new(
+        CONFIG.circuit_breaker_failures,
+        Duration::from_secs(CONFIG.circuit_breaker_cooldown_secs),
+    );
+}
+
+/**
+Отправляет запрос создания счёта с повторами. Повтор безопасен: `order_id` уникален,
+и платёжка не создаст второй счёт на тот же заказ. Повторяются только сетевые ошибки,
+429 и 5xx, остальные ответы разбирает платёжка
+ */
+pub async fn send_with_retry(
+    service: PaymentServices,
+    request: RequestBuilder,
+) -> Result<Response, CreateInvoiceError> {
+    if !CIRCUIT_BREAKER.allow(service, Instant::now()) {
+        return Err(CreateInvoiceError::new(
+            service,
+            PaymentError::ProviderUnavailable,
+            "Circuit breaker is open",
+        ));
+    }
+
+    let attempts = CONFIG.http_retry_attempts.max(1);
+    let mut attempt = 0;
+
+    let res = loop {
+        let Some(request) = request.try_clone() else {
+            break request.send().await;
+        };
+
+        let res = request.send().await;
+
+        attempt += 1;
+
+        let retryable = match &res {
+            Ok(v) => is_failure_status(v.status()),
+            Err(e) => e.is_connect() || e.is_timeout(),
+        };
+
+        if !retryable || attempt >= attempts {
+            break res;
+        }
+
+        let delay = backoff(attempt, CONFIG.http_retry_base_ms, jitter());
+
+        println!("{service} request failed, retry {attempt}/{attempts} in {delay:?}");
+
+        tokio::time::sleep(delay).await;
+    };
+
+    let failed = match &res {
+        Ok(v) => is_failure_status(v.status()),
+        Err(_) => true,
+    };
+
+    CIRCUIT_BREAKER.record(service, !failed, Instant::now());
+
+    res.map_err(|e| CreateInvoiceError::connection(service, e))
+}
+
+fn is_failure_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/**
+Экспоненциальная задержка с полным джиттером: случайная от 0 до `base * 2^(attempt-1)`
+ */
+fn backoff(attempt: u32, base_ms: u64, jitter: f64) -> Duration {
+    let max = Duration::from_millis(base_ms.saturating_mul(1 << (attempt - 1).min(10)));
+
+    max.min(MAX_BACKOFF).mul_f64(jitter)
+}
+
+/**
+Случайное число от 0 до 1. Отдельный генератор ради джиттера не нужен
+ */
+fn jitter() -> f64 {
+    (Uuid::new_v4().as_u128() % 1000) as f64 / 1000.0
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum CircuitState {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /**
+    Пауза прошла, пропускается один пробный запрос. Если за `cooldown` он так и не
+    завершился (например, его отменил общий таймаут), пропускается следующий
+     */
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+/**
+После `max_failures` неудачных запросов подряд платёжка не вызывается `cooldown`,
+счета сразу уходят в запасную
+ */
+pub struct CircuitBreaker {
+    max_failures: u32,
+    cooldown: Duration,
+    states: Mutex<HashMap<PaymentServices, CircuitState>>,
+}
+
+impl CircuitBreaker {
+    fn new(max_failures: u32, cooldown: Duration) -> Self {
+        Self {
+            max_failures: max_failures.max(1),
+            cooldown,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn allow(&self, service: PaymentServices, now: Instant) -> bool {
+        let mut states = self.states.lock().unwrap();
+        let state = states
+            .entry(service)
+            .or_insert(CircuitState::Closed { failures: 0 });
+
+        match *state {
+            CircuitState::Closed { .. } => true,
+            CircuitState::Open { until } if now >= until => {
+                *state = CircuitState::HalfOpen { since: now };
+                true
+            }
+            CircuitState::HalfOpen { since } if now >= since + self.cooldown => {
+                *state = CircuitState::HalfOpen { since: now };
+                true
+            }
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => false,
+        }
+    }
+
+    fn record(&self, service: PaymentServices, ok: bool, now: Instant) {
+        let mut states = self.states.lock().unwrap();
+        let state = states
+            .entry(service)
+            .or_insert(CircuitState::Closed { failures: 0 });
+
+        *state = match (*state, ok) {
+            (_, true) => CircuitState::Closed { failures: 0 },
+            (CircuitState::Closed { failures }, false) if failures + 1 < self.max_failures => {
+                CircuitState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (_, false) => {
+                println!("Circuit breaker opened for {service}");
+
+                CircuitState::Open {
+                    until: now + self.cooldown,
+                }
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{backoff, CircuitBreaker};
+    use shared::PaymentServices;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1, 200, 1.0), Duration::from_millis(200));
+        assert_eq!(backoff(3, 200, 1.0), Duration::from_millis(800));
+        assert_eq!(backoff(3, 200, 0.5), Duration::from_millis(400));
+        assert_eq!(backoff(3, 200, 0.0), Duration::ZERO);
+        assert_eq!(backoff(30, 200, 1.0), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+        let enot = PaymentServices::Enot;
+
+        assert!(breaker.allow(enot, now));
+        breaker.record(enot, false, now);
+        assert!(breaker.allow(enot, now));
+        breaker.record(enot, false, now);
+
+        assert!(!breaker.allow(enot, now));
+        assert!(breaker.allow(PaymentServices::Paypalych, now));
+
+        // Один пробный запрос после паузы
+        let later = now + Duration::from_secs(31);
+        assert!(breaker.allow(enot, later));
+        assert!(!breaker.allow(enot, later));
+
+        // Пробный запрос потерялся: после паузы пропускается новый
+        assert!(breaker.allow(enot, later + Duration::from_secs(30)));
+        assert!(!breaker.allow(enot, later + Duration::from_secs(31)));
+
+        breaker.record(enot, false, later);
+        assert!(!breaker.allow(enot, later + Duration::from_secs(29)));
+
+        let later = later + Duration::from_secs(31);
+        assert!(breaker.allow(enot, later));
+        breaker.record(enot, true, later);
+        assert!(breaker.allow(enot, later));
+        assert!(breaker.allow(enot, later));
+    }
+}
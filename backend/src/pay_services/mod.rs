@@ -1,54 +1,197 @@
 pub mod enot;
 pub mod hotskins;
+pub mod http;
 pub mod paypalich;
 
 use hmac::{Hmac, Mac};
-use serde::{de, Deserialize, Deserializer};
+use reqwest::StatusCode;
+use serde::{de, Deserialize, Deserializer, Serialize};
 use serde_json::Value;
+use serde_with::skip_serializing_none;
 use sha1::Sha1;
+use shared::{PaymentError, PaymentServices};
 use sha2::Sha256;
 use thiserror::Error;
+use uuid::Uuid;
+
+use crate::catalog::InvoiceLine;
+use crate::invoice_handler::{Invoice, InvoiceData};
+use crate::CONFIG;
 
 type HmacSha256 = Hmac<Sha256>;
 type HmacSha1 = Hmac<Sha1>;
 
-const USD_RATE: u32 = 90;
-fn validate_signature_256(
-    provided_signature: &str,
-    secret: &str,
-    body: &str,
-) -> anyhow::Result<bool> {
+pub const USD_RATE: u32 = 90;
+
+/**
+Куда платёжка вернёт игрока: страница статуса его счёта. `None`, если публичный адрес
+не задан - тогда действуют настройки магазина
+ */
+pub fn return_url(order_id: Uuid, success: bool) -> Option<String> {
+    CONFIG
+        .public_url
+        .as_deref()
+        .map(|base| build_return_url(base, order_id, success))
+}
+
+/**
+Адрес webhook этого инстанса, например `/webhook/enot/invoice`. Позволяет staging и
+production работать с одним магазином
+ */
+pub fn hook_url(path: &str) -> Option<String> {
+    CONFIG
+        .public_url
+        .as_deref()
+        .map(|base| format!("{}{path}", base.trim_end_matches('/')))
+}
+
+/**
+Сколько товаров корзины попадает в метаданные: у Enot лимит 500 символов на поле
+ */
+const METADATA_MAX_PRODUCTS: usize = 8;
+
+/**
+Метаданные счёта, которые уходят в произвольное поле платёжки (`custom_fields` у Enot,
+`custom` у Paypalych) и возвращаются в webhook. Сверяются с сохранённым счётом, а если
+счёт в базе потерян - позволяют его восстановить вручную
+ */
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct InvoiceMetadata {
+    pub char_id: Option<i32>,
+    pub server_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub product_ids: Vec<String>,
+    pub promo_code: Option<String>,
+}
+
+impl InvoiceMetadata {
+    pub fn new(char_id: i32, lines: &[InvoiceLine], promo_code: Option<String>) -> Self {
+        Self {
+            char_id: Some(char_id),
+            server_id: CONFIG.server_id.clone(),
+            product_ids: product_ids(lines),
+            promo_code,
+        }
+    }
+
+    /**
+    Описание первого расхождения с сохранённым счётом. Поля, которых нет в webhook,
+    не сверяются: так проходят счета, созданные до появления метаданных
+     */
+    pub fn mismatch(&self, invoice: &Invoice) -> Option<String> {
+        if let Some(char_id) = self.char_id {
+            if char_id != invoice.char_id {
+                return Some(format!("char_id {char_id} != {}", invoice.char_id));
+            }
+        }
+
+        if let Some(server_id) = &self.server_id {
+            if CONFIG.server_id.as_ref() != Some(server_id) {
+                return Some(format!("server_id {server_id} != {:?}", CONFIG.server_id));
+            }
+        }
+
+        if !self.product_ids.is_empty() && self.product_ids != product_ids(&invoice.lines) {
+            return Some(format!("product_ids {:?}", self.product_ids));
+        }
+
+        if self.promo_code.is_some() && self.promo_code != invoice.promo_code {
+            return Some(format!("promo_code {:?}", self.promo_code));
+        }
+
+        None
+    }
+}
+
+fn product_ids(lines: &[InvoiceLine]) -> Vec<String> {
+    lines
+        .iter()
+        .take(METADATA_MAX_PRODUCTS)
+        .map(|v| v.product_id.simple().to_string())
+        .collect()
+}
+
+/**
+Метаданные приходят объектом (Enot) или JSON-строкой (Paypalych, form-urlencoded).
+Чужой формат не ломает разбор webhook: неизвестные поля пропускаются
+ */
+fn deserialize_metadata<'de, D>(deserializer: D) -> Result<Option<InvoiceMetadata>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<Value>::deserialize(deserializer)?;
+
+    let value = match value {
+        None | Some(Value::Null) => return Ok(None),
+        Some(Value::String(v)) if v.is_empty() => return Ok(None),
+        Some(Value::String(v)) => serde_json::from_str(&v).unwrap_or(Value::Null),
+        Some(v) => v,
+    };
+
+    match value {
+        Value::Object(_) => serde_json::from_value(value)
+            .map(Some)
+            .map_err(de::Error::custom),
+        _ => Ok(None),
+    }
+}
+
+fn build_return_url(base: &str, order_id: Uuid, success: bool) -> String {
+    format!(
+        "{}/?order={order_id}&result={}",
+        base.trim_end_matches('/'),
+        if success { "success" } else { "fail" }
+    )
+}
+fn hmac_256(secret: &str, body: &str) -> Vec<u8> {
     let mut mac =
         HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
 
     mac.update(body.as_bytes());
 
-    let res = mac.finalize().into_bytes();
+    mac.finalize().into_bytes().to_vec()
+}
 
-    println!("{res:02x}\n{provided_signature}");
+pub fn sign_256(secret: &str, body: &str) -> String {
+    hex::encode(hmac_256(secret, body))
+}
 
-    let decoded = hex::decode(provided_signature)?;
+/**
+Сравнение за постоянное время, чтобы по времени ответа нельзя было подобрать подпись
+ */
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
 
-    Ok(res[..] == decoded[..])
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
-fn validate_signature_1(
-    provided_signature: &str,
-    secret: &str,
-    body: &str,
-) -> anyhow::Result<bool> {
+fn validate_signature_256(provided_signature: &str, secret: &str, body: &str) -> bool {
+    let Ok(decoded) = hex::decode(provided_signature.trim()) else {
+        return false;
+    };
+
     let mut mac =
-        HmacSha1::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
 
     mac.update(body.as_bytes());
 
-    let res = mac.finalize().into_bytes();
+    mac.verify_slice(&decoded).is_ok()
+}
+
+fn validate_signature_1(provided_signature: &str, secret: &str, body: &str) -> bool {
+    let Ok(decoded) = hex::decode(provided_signature.trim()) else {
+        return false;
+    };
 
-    println!("{res:02x}\n{provided_signature}");
+    let mut mac =
+        HmacSha1::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
 
-    let decoded = hex::decode(provided_signature)?;
+    mac.update(body.as_bytes());
 
-    Ok(res[..] == decoded[..])
+    mac.verify_slice(&decoded).is_ok()
 }
 
 fn boolean<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
@@ -71,6 +214,27 @@ pub enum ProceedInvoiceError {
     #[error("Invalid signature")]
     InvalidSignature,
 
+    #[error("Malformed body: {0}")]
+    MalformedBody(String),
+
+    #[error("Stale callback")]
+    StaleCallback,
+
+    #[error("Unsupported currency {currency:?} for order {order_id}")]
+    UnsupportedCurrency { currency: String, order_id: Uuid },
+
+    #[error("Order {0} is already paid")]
+    AlreadyPaid(Uuid),
+
+    #[error("Order {order_id} belongs to {service}")]
+    WrongService {
+        order_id: Uuid,
+        service: PaymentServices,
+    },
+
+    #[error("Metadata mismatch for order {order_id}: {reason}")]
+    MetadataMismatch { order_id: Uuid, reason: String },
+
     #[error("Wrong status code: {code:?} for state {state:?}")]
     WrongStatusCode { code: i32, state: String },
 
@@ -80,3 +244,168 @@ pub enum ProceedInvoiceError {
     #[error("Field {field:?} should have type {field_type:?}")]
     WrongFieldType { field: String, field_type: String },
 }
+
+/**
+Платёжка не создала счёт. `kind` видит игрок, `detail` - только мы
+ */
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{service} {kind:?}: {detail}")]
+pub struct CreateInvoiceError {
+    pub service: PaymentServices,
+    pub kind: PaymentError,
+    pub detail: String,
+}
+
+impl CreateInvoiceError {
+    pub fn new(service: PaymentServices, kind: PaymentError, detail: impl Into<String>) -> Self {
+        Self {
+            service,
+            kind,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn connection(service: PaymentServices, err: reqwest::Error) -> Self {
+        Self::new(
+            service,
+            PaymentError::ProviderUnavailable,
+            format!("Can't connect: {err}"),
+        )
+    }
+
+    pub fn bad_response(service: PaymentServices, err: impl std::fmt::Display) -> Self {
+        Self::new(
+            service,
+            PaymentError::Internal,
+            format!("Can't deserialize response: {err}"),
+        )
+    }
+
+    /**
+    Общий разбор кодов ответа. Платёжки уточняют `detail` своими расшифровками
+     */
+    pub fn from_status(service: PaymentServices, code: StatusCode, detail: &str) -> Self {
+        let kind = match code {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => PaymentError::ProviderAuth,
+            StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND | StatusCode::UNPROCESSABLE_ENTITY => {
+                PaymentError::Validation
+            }
+            StatusCode::TOO_MANY_REQUESTS => PaymentError::RateLimited,
+            code if code.is_server_error() => PaymentError::ProviderUnavailable,
+            _ => PaymentError::Internal,
+        };
+
+        Self::new(service, kind, format!("{code}: {detail}"))
+    }
+}
+
+impl From<CreateInvoiceError> for InvoiceData {
+    fn from(value: CreateInvoiceError) -> Self {
+        InvoiceData::FailedToCreate {
+            reason: value.to_string(),
+            error: value.kind,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_return_url, deserialize_metadata, CreateInvoiceError, InvoiceMetadata};
+    use crate::invoice_handler::{Invoice, InvoiceData};
+    use reqwest::StatusCode;
+    use serde::Deserialize;
+    use shared::{PaymentError, PaymentServices};
+    use uuid::Uuid;
+
+    #[derive(Deserialize)]
+    struct Holder {
+        #[serde(default, deserialize_with = "deserialize_metadata")]
+        custom: Option<InvoiceMetadata>,
+    }
+
+    fn parse(json: &str) -> Option<InvoiceMetadata> {
+        serde_json::from_str::<Holder>(json).unwrap().custom
+    }
+
+    #[test]
+    fn test_metadata() {
+        let expected = InvoiceMetadata {
+            char_id: Some(42),
+            promo_code: Some("SUMMER".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            parse(r#"{"custom": {"char_id": 42, "promo_code": "SUMMER"}}"#),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            parse(r#"{"custom": "{\"char_id\":42,\"promo_code\":\"SUMMER\"}"}"#),
+            Some(expected.clone())
+        );
+        assert_eq!(parse(r#"{"custom": "not json"}"#), None);
+        assert_eq!(parse(r#"{}"#), None);
+        // Чужие поля не мешают
+        assert_eq!(parse(r#"{"custom": {"user": 1}}"#), Some(Default::default()));
+
+        let mut invoice = Invoice::test_new(100.0);
+        invoice.char_id = 42;
+        invoice.promo_code = Some("SUMMER".to_string());
+
+        assert_eq!(expected.mismatch(&invoice), None);
+        assert_eq!(InvoiceMetadata::default().mismatch(&invoice), None);
+
+        invoice.char_id = 7;
+        assert_eq!(
+            expected.mismatch(&invoice),
+            Some("char_id 42 != 7".to_string())
+        );
+    }
+
+    #[test]
+    fn test_return_url() {
+        let order_id = Uuid::nil();
+
+        assert_eq!(
+            build_return_url("https://pay.la2world.ru/", order_id, true),
+            format!("https://pay.la2world.ru/?order={order_id}&result=success")
+        );
+        assert_eq!(
+            build_return_url("https://staging.la2world.ru", order_id, false),
+            format!("https://staging.la2world.ru/?order={order_id}&result=fail")
+        );
+    }
+
+    #[test]
+    fn test_create_invoice_error() {
+        let kind = |code| CreateInvoiceError::from_status(PaymentServices::Enot, code, "").kind;
+
+        assert_eq!(kind(StatusCode::UNAUTHORIZED), PaymentError::ProviderAuth);
+        assert_eq!(kind(StatusCode::UNPROCESSABLE_ENTITY), PaymentError::Validation);
+        assert_eq!(kind(StatusCode::TOO_MANY_REQUESTS), PaymentError::RateLimited);
+        assert_eq!(kind(StatusCode::BAD_GATEWAY), PaymentError::ProviderUnavailable);
+        assert_eq!(kind(StatusCode::IM_A_TEAPOT), PaymentError::Internal);
+
+        let data: InvoiceData = CreateInvoiceError::from_status(
+            PaymentServices::Paypalych,
+            StatusCode::FORBIDDEN,
+            "inactive shop",
+        )
+        .into();
+
+        assert!(matches!(
+            data,
+            InvoiceData::FailedToCreate { ref reason, error: PaymentError::ProviderAuth }
+                if reason == "Paypalych ProviderAuth: 403 Forbidden: inactive shop"
+        ));
+
+        // Счета до появления типов ошибок
+        let old: InvoiceData =
+            serde_json::from_str(r#"{"FailedToCreate": {"reason": "timeout"}}"#).unwrap();
+
+        assert!(matches!(
+            old,
+            InvoiceData::FailedToCreate { error: PaymentError::Internal, .. }
+        ));
+    }
+}
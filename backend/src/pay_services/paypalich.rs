@@ -12,7 +12,9 @@ use serde_repr::{Deserialize_repr, Serialize_repr};
 use serde_with::skip_serializing_none;
 use uuid::Uuid;
 
-use crate::pay_services::ProceedInvoiceError;
+use crate::pay_services::{
+    constant_time_eq, deserialize_metadata, InvoiceMetadata, ProceedInvoiceError,
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[allow(non_camel_case_types)]
@@ -22,9 +24,6 @@ enum PaymentCurrency {
     EUR,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct CustomFields {}
-
 #[skip_serializing_none]
 #[derive(Serialize)]
 /**
@@ -65,7 +64,7 @@ struct CreateInvoiceParams {
     /**
     Произвольное поле. Будет возвращено в postback.
     */
-    custom: Option<CustomFields>,
+    custom: Option<String>,
 
     /**
     Параметр, который указывает на то, кто будет оплачивать комиссию за входящий платёж.
@@ -179,7 +178,8 @@ pub struct InvoiceUpdate {
     Произвольное поле, переданное при формировании счета
      */
     #[serde(rename = "custom")]
-    custom: Option<CustomFields>,
+    #[serde(default, deserialize_with = "deserialize_metadata")]
+    custom: Option<InvoiceMetadata>,
 
     /**
     Метод оплаты
@@ -225,27 +225,26 @@ pub struct InvoiceUpdate {
 }
 
 impl InvoiceUpdate {
-    pub fn validate_signature(&self, token: &str, two_digits: bool) -> Result<(), ProceedInvoiceError> {
-        let mut hasher = Md5::new();
-        hasher.update(
-            if two_digits {
-                format!("{:.2}:{}:{}", self.amount, self.order_id, token)
-            } else {
-                format!("{}:{}:{}", self.amount, self.order_id, token)
-            }
-        );
-
-        let hash = hasher.finalize();
-
-        let mut res: Vec<u8> = Vec::new();
-        res.extend_from_slice(&hash[..]);
-        let c = hex::encode(res).to_uppercase();
-        println!("{c}\n{}", self.signature_value);
-
-        if c == self.signature_value {
+    /**
+    Подпись считается от суммы как с двумя знаками после точки, так и без форматирования
+     */
+    pub fn validate_signature(&self, token: &str) -> Result<(), ProceedInvoiceError> {
+        let Ok(provided) = hex::decode(self.signature_value.trim()) else {
+            return Err(ProceedInvoiceError::InvalidSignature);
+        };
+
+        let variants = [
+            format!("{:.2}:{}:{}", self.amount, self.order_id, token),
+            format!("{}:{}:{}", self.amount, self.order_id, token),
+        ];
+
+        if variants
+            .iter()
+            .any(|v| constant_time_eq(&Md5::digest(v.as_bytes()), &provided))
+        {
             Ok(())
         } else {
-            self.validate_signature(token, false)
+            Err(ProceedInvoiceError::InvalidSignature)
         }
     }
 }
@@ -256,23 +255,49 @@ enum PaymentStatus {
     UNDERPAID,
     OVERPAID,
     FAIL,
+    /**
+    Возврат платежа по запросу магазина
+     */
+    REFUND,
+    /**
+    Оспаривание платежа плательщиком через банк
+     */
+    CHARGEBACK,
+}
+
+/**
+Суммы из postback для бухгалтерии. Сохраняется каждый postback по счёту
+ */
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PaypalichAccounting {
+    pub status: String,
+    pub trs_id: String,
+    pub out_sum: f32,
+    pub currency_in: String,
+    pub commission: f32,
+    pub balance_amount: f32,
+    pub balance_currency: String,
+    pub account_type: String,
 }
 
 pub(crate) mod handler {
     use crate::invoice_handler::{
-        InvoiceData, InvoiceStatusUpdate, InvoiceStatusUpdateData,
+        InvoiceData, InvoiceSettlement, InvoiceStatusUpdate, InvoiceStatusUpdateData,
         PaymentServiceCreateInvoiceResponse,
     };
 
     use crate::pay_services::paypalich::{
         CommissionPayer, CreateInvoiceParams, CreateInvoiceResponse, InvoiceUpdate,
-        PaymentCurrency, PaymentStatus, PaymentType,
+        PaymentCurrency, PaymentStatus, PaymentType, PaypalichAccounting,
     };
     use anyhow::Result;
+    use chrono::Utc;
     use reqwest::header::HeaderMap;
     use reqwest::{RequestBuilder, Response, StatusCode};
+    use shared::PaymentServices;
     use uuid::Uuid;
-    use crate::pay_services::USD_RATE;
+    use crate::pay_services::http::HTTP_CLIENT;
+    use crate::pay_services::{return_url, CreateInvoiceError, InvoiceMetadata, USD_RATE};
 
     pub struct PaypalichInvoiceHandler {
         pub api_url: String,
@@ -282,7 +307,13 @@ pub(crate) mod handler {
     }
 
     impl PaypalichInvoiceHandler {
-        pub fn create_invoice_request(&self, amount: f32, order_id: Uuid, rub: bool) -> RequestBuilder {
+        pub fn create_invoice_request(
+            &self,
+            amount: f32,
+            order_id: Uuid,
+            rub: bool,
+            metadata: InvoiceMetadata,
+        ) -> RequestBuilder {
             let params = CreateInvoiceParams {
                 amount,
                 order_id,
@@ -290,15 +321,13 @@ pub(crate) mod handler {
                 payment_type: PaymentType::Normal,
                 shop_id: self.shop_id.clone(),
                 currency_in: if rub {Some(PaymentCurrency::RUB)} else {Some(PaymentCurrency::USD)},
-                custom: None,
+                custom: serde_json::to_string(&metadata).ok(),
                 payer_pays_commission: Some(CommissionPayer::Client),
                 name: Some("La2World Donation".to_string()),
-                fail_url: None,
-                success_url: None,
+                fail_url: return_url(order_id, false),
+                success_url: return_url(order_id, true),
             };
 
-            let client = reqwest::Client::new();
-
             let mut headers = HeaderMap::new();
             headers.insert("Accept", "application/json".parse().unwrap());
             headers.insert("Content-Type", "application/json".parse().unwrap());
@@ -307,7 +336,7 @@ pub(crate) mod handler {
                 format!("Bearer {}", self.bearer).parse().unwrap(),
             );
 
-            client
+            HTTP_CLIENT
                 .post(&self.api_url)
                 .headers(headers)
                 .body(serde_json::to_string(&params).unwrap())
@@ -317,34 +346,72 @@ pub(crate) mod handler {
             &self,
             data: InvoiceUpdate,
         ) -> Result<InvoiceStatusUpdate> {
-            data.validate_signature(&self.bearer, true)?;
-
-            match data.status {
-                PaymentStatus::SUCCESS => Ok(InvoiceStatusUpdate {
-                    order_id: data.order_id,
-                    external_id: data.invoice_id.to_string(),
-                    data: if self.is_usd_price {
-                        InvoiceStatusUpdateData::PayedWithScaleSum {
-                            scale: USD_RATE as f32
-                        }
-                    } else { InvoiceStatusUpdateData::Payed },
-                }),
-
-                PaymentStatus::UNDERPAID | PaymentStatus::OVERPAID => Ok(InvoiceStatusUpdate {
-                    order_id: data.order_id,
-                    external_id: data.invoice_id.to_string(),
-                    data: InvoiceStatusUpdateData::PayedWithChangedSum {
+            data.validate_signature(&self.bearer)?;
+
+            // Суммы в postback в валюте счёта, CRD начисляются в рублях
+            let scale = if self.is_usd_price { USD_RATE as f32 } else { 1.0 };
+
+            let accounting = PaypalichAccounting {
+                status: format!("{:?}", data.status),
+                trs_id: data.invoice_id.clone(),
+                out_sum: data.amount,
+                currency_in: format!("{:?}", data.currency),
+                commission: data.commission,
+                balance_amount: data.balance_amount,
+                balance_currency: format!("{:?}", data.balance_currency),
+                account_type: data.account_type.clone(),
+            };
+
+            let settlement = match data.status {
+                PaymentStatus::SUCCESS | PaymentStatus::UNDERPAID | PaymentStatus::OVERPAID => {
+                    // Время оплаты Paypalych не присылает, берём время получения postback
+                    Some(InvoiceSettlement {
+                        gross: data.amount,
+                        gross_currency: (accounting.currency_in != accounting.balance_currency)
+                            .then(|| accounting.currency_in.clone()),
+                        fee: data.commission,
+                        net: data.balance_amount,
+                        currency: accounting.balance_currency.clone(),
+                        pay_method: data.account_type.clone(),
+                        pay_time: Utc::now(),
+                    })
+                }
+                _ => None,
+            };
+
+            let status_data = match data.status {
+                PaymentStatus::SUCCESS if self.is_usd_price => {
+                    InvoiceStatusUpdateData::PayedWithScaleSum { scale }
+                }
+                PaymentStatus::SUCCESS => InvoiceStatusUpdateData::Payed,
+                PaymentStatus::UNDERPAID | PaymentStatus::OVERPAID => {
+                    InvoiceStatusUpdateData::PayedWithChangedSum {
                         new_amount: data.amount,
-                    },
-                }),
-                PaymentStatus::FAIL => Ok(InvoiceStatusUpdate {
-                    order_id: data.order_id,
-                    external_id: data.invoice_id,
-                    data: InvoiceStatusUpdateData::Aborted {
-                        reason: format!("{:#?}, {:#?}", data.error_code, data.error_message),
-                    },
-                }),
-            }
+                        scale,
+                    }
+                }
+                PaymentStatus::FAIL => InvoiceStatusUpdateData::Aborted {
+                    reason: format!("{:#?}, {:#?}", data.error_code, data.error_message),
+                },
+                PaymentStatus::REFUND | PaymentStatus::CHARGEBACK => {
+                    InvoiceStatusUpdateData::Refunded {
+                        amount: data.amount,
+                        chargeback: matches!(data.status, PaymentStatus::CHARGEBACK),
+                        reason: data.error_message.clone().unwrap_or_default(),
+                    }
+                }
+            };
+
+            Ok(InvoiceStatusUpdate {
+                order_id: data.order_id,
+                transaction_id: data.invoice_id.clone(),
+                external_id: data.invoice_id,
+                paypalich_accounting: Some(accounting),
+                metadata: data.custom,
+                settlement,
+                data: status_data,
+                ..Default::default()
+            })
         }
 
         pub(crate) async fn proceed_create_invoice_response(
@@ -362,27 +429,28 @@ pub(crate) mod handler {
                             response: PaymentServiceCreateInvoiceResponse::Paypalich(body),
                         },
 
-                        Err(err) => InvoiceData::FailedToCreate {
-                            reason: format!("Can't deserialize response: {err}"),
-                        },
+                        Err(err) => CreateInvoiceError::bad_response(self.service(), err).into(),
                     }
                 }
 
-                StatusCode::UNAUTHORIZED => InvoiceData::FailedToCreate {
-                    reason: "Ошибка авторизации (неверный shop_id или секретный ключ)".to_string(),
-                },
+                code => {
+                    let detail = match code {
+                        StatusCode::UNAUTHORIZED => "wrong shop_id or bearer token",
+                        StatusCode::FORBIDDEN => "inactive shop",
+                        StatusCode::UNPROCESSABLE_ENTITY => "validation failed",
+                        _ => "unexpected response",
+                    };
 
-                StatusCode::FORBIDDEN => InvoiceData::FailedToCreate {
-                    reason: "Ошибка доступа (неактивный магазин)".to_string(),
-                },
-
-                StatusCode::UNPROCESSABLE_ENTITY => InvoiceData::FailedToCreate {
-                    reason: "Ошибка валидации".to_string(),
-                },
+                    CreateInvoiceError::from_status(self.service(), code, detail).into()
+                }
+            }
+        }
 
-                code => InvoiceData::FailedToCreate {
-                    reason: format!("Unsupported response code: {code}"),
-                },
+        pub fn service(&self) -> PaymentServices {
+            if self.is_usd_price {
+                PaymentServices::PaypalychUk
+            } else {
+                PaymentServices::Paypalych
             }
         }
     }
@@ -390,7 +458,157 @@ pub(crate) mod handler {
 
 #[cfg(test)]
 mod tests {
+    use super::handler::PaypalichInvoiceHandler;
+    use super::InvoiceUpdate;
+    use crate::invoice_handler::InvoiceStatusUpdateData;
     use md5::{Digest, Md5};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    fn shop(is_usd_price: bool) -> PaypalichInvoiceHandler {
+        PaypalichInvoiceHandler {
+            api_url: String::new(),
+            shop_id: "shop".to_string(),
+            bearer: "token".to_string(),
+            is_usd_price,
+        }
+    }
+
+    fn postback(status: &str, amount: f32, currency: &str) -> InvoiceUpdate {
+        let order_id = Uuid::nil();
+        let signature = hex::encode(Md5::digest(format!("{amount:.2}:{order_id}:token")));
+
+        serde_json::from_value(json!({
+            "InvId": order_id,
+            "OutSum": amount,
+            "Commission": 0.5,
+            "TrsId": "trs1",
+            "Status": status,
+            "CurrencyIn": currency,
+            "AccountType": "card",
+            "AccountNumber": "553691******1279",
+            "BalanceAmount": amount - 0.5,
+            "BalanceCurrency": currency,
+            "SignatureValue": signature,
+            "ErrorCode": null,
+            "ErrorMessage": "Bank",
+        }))
+        .unwrap()
+    }
+
+    fn parse(is_usd_price: bool, status: &str, amount: f32) -> InvoiceStatusUpdateData {
+        let currency = if is_usd_price { "USD" } else { "RUB" };
+
+        shop(is_usd_price)
+            .parse_invoice_status_update(postback(status, amount, currency))
+            .unwrap()
+            .data
+    }
+
+    #[test]
+    fn test_rub_statuses() {
+        assert_eq!(parse(false, "SUCCESS", 100.0), InvoiceStatusUpdateData::Payed);
+        assert_eq!(
+            parse(false, "UNDERPAID", 90.0),
+            InvoiceStatusUpdateData::PayedWithChangedSum {
+                new_amount: 90.0,
+                scale: 1.0
+            }
+        );
+        assert_eq!(
+            parse(false, "OVERPAID", 110.0),
+            InvoiceStatusUpdateData::PayedWithChangedSum {
+                new_amount: 110.0,
+                scale: 1.0
+            }
+        );
+        assert!(matches!(
+            parse(false, "FAIL", 100.0),
+            InvoiceStatusUpdateData::Aborted { .. }
+        ));
+        assert_eq!(
+            parse(false, "REFUND", 100.0),
+            InvoiceStatusUpdateData::Refunded {
+                amount: 100.0,
+                chargeback: false,
+                reason: "Bank".to_string()
+            }
+        );
+        assert_eq!(
+            parse(false, "CHARGEBACK", 100.0),
+            InvoiceStatusUpdateData::Refunded {
+                amount: 100.0,
+                chargeback: true,
+                reason: "Bank".to_string()
+            }
+        );
+
+        let update = shop(false)
+            .parse_invoice_status_update(postback("SUCCESS", 100.0, "RUB"))
+            .unwrap();
+        let settlement = update.settlement.unwrap();
+
+        assert_eq!(update.external_id, "trs1");
+        assert_eq!(settlement.gross, 100.0);
+        assert_eq!(settlement.net, 99.5);
+
+        let update = shop(false)
+            .parse_invoice_status_update(postback("FAIL", 100.0, "RUB"))
+            .unwrap();
+        assert!(update.settlement.is_none());
+    }
+
+    #[test]
+    fn test_usd_statuses() {
+        // CRD начисляются в рублях по курсу USD_RATE
+        assert_eq!(
+            parse(true, "SUCCESS", 10.0),
+            InvoiceStatusUpdateData::PayedWithScaleSum { scale: 90.0 }
+        );
+        assert_eq!(
+            parse(true, "UNDERPAID", 9.0),
+            InvoiceStatusUpdateData::PayedWithChangedSum {
+                new_amount: 9.0,
+                scale: 90.0
+            }
+        );
+        assert_eq!(
+            parse(true, "OVERPAID", 11.0),
+            InvoiceStatusUpdateData::PayedWithChangedSum {
+                new_amount: 11.0,
+                scale: 90.0
+            }
+        );
+        assert!(matches!(
+            parse(true, "FAIL", 10.0),
+            InvoiceStatusUpdateData::Aborted { .. }
+        ));
+        // Сумма возврата остаётся в валюте счёта
+        assert_eq!(
+            parse(true, "REFUND", 10.0),
+            InvoiceStatusUpdateData::Refunded {
+                amount: 10.0,
+                chargeback: false,
+                reason: "Bank".to_string()
+            }
+        );
+        assert_eq!(
+            parse(true, "CHARGEBACK", 10.0),
+            InvoiceStatusUpdateData::Refunded {
+                amount: 10.0,
+                chargeback: true,
+                reason: "Bank".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_wrong_signature() {
+        let mut update = postback("REFUND", 100.0, "RUB");
+        update.amount = 1000.0;
+
+        assert!(shop(false).parse_invoice_status_update(update).is_err());
+    }
 
     #[test]
     fn test_sign_validation() {
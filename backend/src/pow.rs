@@ -0,0 +1,202 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use sha2::Sha256;
+use shared::pow::is_solved;
+use shared::{PowChallenge, PowSolution};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::rate_limiter::INVOICE_RATE_LIMITER;
+use crate::CONFIG;
+
+/**
+Сколько секунд задача остаётся действительной
+ */
+const CHALLENGE_TTL_SECS: i64 = 5 * 60;
+/**
+В адаптивном режиме задача выдаётся, когда IP израсходовал хотя бы эту долю лимита
+ */
+const ADAPTIVE_PRESSURE: f64 = 0.5;
+
+lazy_static! {
+    pub static ref POW_GUARD: PowGuard = PowGuard::new(
+        format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()),
+        CONFIG.pow_difficulty
+    );
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PowMode {
+    #[default]
+    Off,
+    Always,
+    Adaptive,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum PowError {
+    #[error("Malformed challenge")]
+    Malformed,
+    #[error("Invalid challenge signature")]
+    InvalidSignature,
+    #[error("Challenge expired")]
+    Expired,
+    #[error("Challenge difficulty is too low")]
+    TooEasy,
+    #[error("Wrong nonce")]
+    WrongNonce,
+    #[error("Challenge already used")]
+    Replayed,
+}
+
+/**
+Выдаёт и проверяет proof-of-work задачи. Задачи не хранятся: время выдачи и сложность
+подписаны секретом процесса, в памяти держатся только уже решённые задачи до истечения их срока
+ */
+pub struct PowGuard {
+    secret: String,
+    difficulty: u8,
+    used: Mutex<HashMap<String, i64>>,
+}
+
+impl PowGuard {
+    fn new(secret: String, difficulty: u8) -> Self {
+        Self {
+            secret,
+            difficulty,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_required(&self, ip: IpAddr) -> bool {
+        match CONFIG.pow_mode {
+            PowMode::Off => false,
+            PowMode::Always => true,
+            PowMode::Adaptive => INVOICE_RATE_LIMITER.ip_pressure(ip) >= ADAPTIVE_PRESSURE,
+        }
+    }
+
+    pub fn issue(&self) -> PowChallenge {
+        self.issue_at(Utc::now().timestamp())
+    }
+
+    pub fn verify(&self, solution: &PowSolution) -> Result<(), PowError> {
+        self.verify_at(solution, Utc::now().timestamp())
+    }
+
+    fn issue_at(&self, now: i64) -> PowChallenge {
+        let payload = format!("{now}.{}.{}", self.difficulty, Uuid::new_v4().simple());
+
+        PowChallenge {
+            challenge: format!(
+                "{payload}.{}",
+                hex::encode(self.mac(&payload).finalize().into_bytes())
+            ),
+            difficulty: self.difficulty,
+        }
+    }
+
+    fn verify_at(&self, solution: &PowSolution, now: i64) -> Result<(), PowError> {
+        let (payload, signature) = solution
+            .challenge
+            .rsplit_once('.')
+            .ok_or(PowError::Malformed)?;
+
+        let signature = hex::decode(signature).map_err(|_| PowError::Malformed)?;
+
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| PowError::InvalidSignature)?;
+
+        let mut parts = payload.split('.');
+
+        let issued_at: i64 = parts
+            .next()
+            .and_then(|v| v.parse().ok())
+            .ok_or(PowError::Malformed)?;
+        let difficulty: u8 = parts
+            .next()
+            .and_then(|v| v.parse().ok())
+            .ok_or(PowError::Malformed)?;
+
+        if issued_at > now || now - issued_at > CHALLENGE_TTL_SECS {
+            return Err(PowError::Expired);
+        }
+
+        if difficulty < self.difficulty {
+            return Err(PowError::TooEasy);
+        }
+
+        if !is_solved(&solution.challenge, solution.nonce, difficulty) {
+            return Err(PowError::WrongNonce);
+        }
+
+        let mut used = self.used.lock().unwrap();
+
+        used.retain(|_, issued_at| now - *issued_at <= CHALLENGE_TTL_SECS);
+
+        if used.insert(solution.challenge.clone(), issued_at).is_some() {
+            return Err(PowError::Replayed);
+        }
+
+        Ok(())
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PowError, PowGuard, CHALLENGE_TTL_SECS};
+    use shared::pow::solve_range;
+    use shared::PowSolution;
+
+    #[test]
+    fn test_pow_challenge() {
+        let guard = PowGuard::new("secret".to_string(), 8);
+        let now = 1_700_000_000;
+
+        let challenge = guard.issue_at(now);
+        let nonce = solve_range(&challenge.challenge, challenge.difficulty, 0, u64::MAX).unwrap();
+
+        let solution = PowSolution {
+            challenge: challenge.challenge.clone(),
+            nonce,
+        };
+
+        assert_eq!(
+            guard.verify_at(&solution, now + CHALLENGE_TTL_SECS + 1),
+            Err(PowError::Expired)
+        );
+
+        let tampered = PowSolution {
+            challenge: challenge
+                .challenge
+                .replacen(&now.to_string(), &(now + 1).to_string(), 1),
+            nonce,
+        };
+        assert_eq!(
+            guard.verify_at(&tampered, now),
+            Err(PowError::InvalidSignature)
+        );
+
+        assert_eq!(guard.verify_at(&solution, now + 1), Ok(()));
+        assert_eq!(guard.verify_at(&solution, now + 1), Err(PowError::Replayed));
+
+        let other = PowGuard::new("other".to_string(), 8);
+        assert_eq!(
+            other.verify_at(&solution, now),
+            Err(PowError::InvalidSignature)
+        );
+    }
+}
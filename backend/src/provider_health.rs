@@ -0,0 +1,281 @@
+use lazy_static::lazy_static;
+use serde_json::Value;
+use shared::{PaymentError, PaymentServices, ProviderStatus};
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::CONFIG;
+
+/**
+Меньше попыток за окно - слишком мало данных, провайдер считается здоровым
+ */
+const MIN_SAMPLES: usize = 3;
+const MAX_SAMPLES: usize = 50;
+
+lazy_static! {
+    pub static ref PROVIDER_HEALTH: ProviderHealth = ProviderHealth::new(
+        Duration::from_secs(CONFIG.provider_health_window_minutes * 60),
+        CONFIG.provider_health_max_error_rate,
+        Duration::from_millis(CONFIG.provider_health_max_latency_ms),
+    );
+}
+
+/**
+Запасная платёжка: `Enot:Paypalych` - если Enot лежит, счёт создаётся в Paypalych
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FallbackRule {
+    pub from: PaymentServices,
+    pub to: PaymentServices,
+}
+
+impl FromStr for FallbackRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((from, to)) = s.split_once(':') else {
+            return Err(format!("Expected FROM:TO, got {s}"));
+        };
+
+        let service = |v: &str| {
+            serde_json::from_value::<PaymentServices>(Value::String(v.to_string()))
+                .map_err(|_| format!("Unknown payment service {v}"))
+        };
+
+        let rule = FallbackRule {
+            from: service(from)?,
+            to: service(to)?,
+        };
+
+        // Сумма Hotskins зависит от скинов, туда нельзя перенаправить счёт на сумму
+        if rule.to == PaymentServices::Hotskins || rule.from == rule.to {
+            return Err(format!("Wrong fallback {s}"));
+        }
+
+        Ok(rule)
+    }
+}
+
+/**
+Ошибки, в которых виновата платёжка. Из-за них она считается деградировавшей и счёт
+уходит в запасную
+ */
+pub fn is_provider_fault(error: PaymentError) -> bool {
+    matches!(
+        error,
+        PaymentError::ProviderUnavailable
+            | PaymentError::ProviderAuth
+            | PaymentError::RateLimited
+            | PaymentError::Internal
+    )
+}
+
+/**
+Ошибки, после которых счёт можно создать в запасной платёжке: запрос до платёжки не
+дошёл, истёк таймаут или она ответила 5xx. После `Internal` (например, непонятного ответа
+2xx) счёт мог уже создаться, второй счёт на тот же заказ создавать нельзя
+ */
+pub fn is_failover_error(error: PaymentError) -> bool {
+    error == PaymentError::ProviderUnavailable
+}
+
+struct Attempt {
+    at: Instant,
+    ok: bool,
+    latency: Duration,
+}
+
+pub struct ProviderHealth {
+    window: Duration,
+    max_error_rate: f64,
+    max_latency: Duration,
+    attempts: Mutex<HashMap<PaymentServices, VecDeque<Attempt>>>,
+}
+
+impl ProviderHealth {
+    fn new(window: Duration, max_error_rate: f64, max_latency: Duration) -> Self {
+        Self {
+            window,
+            max_error_rate,
+            max_latency,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, service: PaymentServices, ok: bool, latency: Duration) {
+        self.record_at(service, ok, latency, Instant::now());
+    }
+
+    fn record_at(&self, service: PaymentServices, ok: bool, latency: Duration, now: Instant) {
+        let mut attempts = self.attempts.lock().unwrap();
+        let attempts = attempts.entry(service).or_default();
+
+        attempts.push_back(Attempt {
+            at: now,
+            ok,
+            latency,
+        });
+
+        if attempts.len() > MAX_SAMPLES {
+            attempts.pop_front();
+        }
+    }
+
+    /**
+    Платёжка деградировала, если за окно слишком много ошибок или она слишком долго отвечает
+     */
+    pub fn is_degraded(&self, service: PaymentServices) -> bool {
+        self.is_degraded_at(service, Instant::now())
+    }
+
+    fn is_degraded_at(&self, service: PaymentServices, now: Instant) -> bool {
+        let attempts = self.attempts.lock().unwrap();
+
+        let recent: Vec<&Attempt> = attempts
+            .get(&service)
+            .map(|v| {
+                v.iter()
+                    .filter(|v| now.duration_since(v.at) < self.window)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if recent.len() < MIN_SAMPLES {
+            return false;
+        }
+
+        let errors = recent.iter().filter(|v| !v.ok).count();
+        let latency = recent.iter().map(|v| v.latency).sum::<Duration>() / recent.len() as u32;
+
+        errors as f64 / recent.len() as f64 >= self.max_error_rate || latency >= self.max_latency
+    }
+
+    /**
+    Платёжки, в которых пробуем создать счёт: выбранная игроком всегда первая, за ней её
+    запасные из конфига с той же валютой. Деградировавшие запасные уходят в конец списка
+     */
+    pub fn candidates(
+        &self,
+        service: PaymentServices,
+        chain: &[FallbackRule],
+    ) -> Vec<PaymentServices> {
+        let mut res = vec![service];
+
+        for rule in chain {
+            if rule.from == service
+                && rule.to.currency() == service.currency()
+                && !res.contains(&rule.to)
+            {
+                res.push(rule.to);
+            }
+        }
+
+        res[1..].sort_by_key(|v| self.is_degraded(*v));
+
+        res
+    }
+
+    pub fn status(&self, service: PaymentServices, chain: &[FallbackRule]) -> ProviderStatus {
+        let degraded = self.is_degraded(service);
+
+        ProviderStatus {
+            service,
+            degraded,
+            fallback: degraded
+                .then(|| {
+                    self.candidates(service, chain)
+                        .into_iter()
+                        .find(|v| *v != service && !self.is_degraded(*v))
+                })
+                .flatten(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_failover_error, FallbackRule, ProviderHealth};
+    use shared::{PaymentError, PaymentServices};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_degraded() {
+        let health = ProviderHealth::new(Duration::from_secs(600), 0.5, Duration::from_secs(5));
+        let now = Instant::now();
+        let fast = Duration::from_millis(300);
+
+        health.record_at(PaymentServices::Enot, false, fast, now);
+        health.record_at(PaymentServices::Enot, false, fast, now);
+        // Мало данных
+        assert!(!health.is_degraded_at(PaymentServices::Enot, now));
+
+        health.record_at(PaymentServices::Enot, true, fast, now);
+        assert!(health.is_degraded_at(PaymentServices::Enot, now));
+        // Ошибки вышли из окна
+        assert!(!health.is_degraded_at(PaymentServices::Enot, now + Duration::from_secs(601)));
+
+        for _ in 0..3 {
+            health.record_at(
+                PaymentServices::Paypalych,
+                true,
+                Duration::from_secs(6),
+                now,
+            );
+        }
+        assert!(health.is_degraded_at(PaymentServices::Paypalych, now));
+    }
+
+    #[test]
+    fn test_candidates() {
+        let chain = vec![
+            FallbackRule::from_str("Enot:Paypalych").unwrap(),
+            FallbackRule::from_str("Enot:PaypalychUk").unwrap(),
+            FallbackRule::from_str("Paypalych:Enot").unwrap(),
+        ];
+
+        assert!(FallbackRule::from_str("Enot:Hotskins").is_err());
+        assert!(FallbackRule::from_str("Enot").is_err());
+        assert!(FallbackRule::from_str("Qiwi:Enot").is_err());
+
+        let health = ProviderHealth::new(Duration::from_secs(600), 0.5, Duration::from_secs(5));
+
+        // Счёт в рублях не уходит в долларовый PaypalychUk
+        assert_eq!(
+            health.candidates(PaymentServices::Enot, &chain),
+            vec![PaymentServices::Enot, PaymentServices::Paypalych]
+        );
+        assert_eq!(
+            health.candidates(PaymentServices::Hotskins, &chain),
+            vec![PaymentServices::Hotskins]
+        );
+
+        for _ in 0..3 {
+            health.record(PaymentServices::Enot, false, Duration::ZERO);
+        }
+
+        // Выбор игрока не переставляется, даже если платёжка деградировала
+        assert_eq!(
+            health.candidates(PaymentServices::Enot, &chain),
+            vec![PaymentServices::Enot, PaymentServices::Paypalych]
+        );
+
+        let status = health.status(PaymentServices::Enot, &chain);
+        assert!(status.degraded);
+        assert_eq!(status.fallback, Some(PaymentServices::Paypalych));
+        assert_eq!(
+            health.status(PaymentServices::Paypalych, &chain).fallback,
+            None
+        );
+    }
+
+    #[test]
+    fn test_failover_errors() {
+        assert!(is_failover_error(PaymentError::ProviderUnavailable));
+        // Счёт мог создаться, второй в другой платёжке создавать нельзя
+        assert!(!is_failover_error(PaymentError::Internal));
+        assert!(!is_failover_error(PaymentError::Validation));
+    }
+}
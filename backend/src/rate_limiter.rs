@@ -0,0 +1,167 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::CONFIG;
+
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+lazy_static! {
+    pub static ref INVOICE_RATE_LIMITER: InvoiceRateLimiter = InvoiceRateLimiter {
+        by_ip: TokenBucketLimiter::new(
+            CONFIG.rate_limit_ip_burst,
+            CONFIG.rate_limit_ip_per_minute
+        ),
+        by_char: TokenBucketLimiter::new(
+            CONFIG.rate_limit_char_burst,
+            CONFIG.rate_limit_char_per_minute
+        ),
+    };
+}
+
+pub struct InvoiceRateLimiter {
+    by_ip: TokenBucketLimiter<IpAddr>,
+    by_char: TokenBucketLimiter<String>,
+}
+
+impl InvoiceRateLimiter {
+    pub fn check(&self, ip: IpAddr, char_name: &str) -> bool {
+        let now = Instant::now();
+
+        self.by_ip.check(ip, now) && self.by_char.check(char_name.to_lowercase(), now)
+    }
+
+    /**
+    Доля израсходованных токенов IP: 0 - запросов не было, 1 - лимит исчерпан
+     */
+    pub fn ip_pressure(&self, ip: IpAddr) -> f64 {
+        1.0 - self.by_ip.fill_ratio(&ip, Instant::now())
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+pub struct TokenBucketLimiter<K> {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K: Hash + Eq + Clone> TokenBucketLimiter<K> {
+    pub fn new(burst: u32, per_minute: u32) -> Self {
+        Self {
+            capacity: burst.max(1) as f64,
+            refill_per_sec: per_minute as f64 / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /**
+    Забирает один токен из корзины `key`. `false`, если корзина пуста
+     */
+    pub fn check(&self, key: K, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= MAX_TRACKED_KEYS {
+            let capacity = self.capacity;
+            let refill_per_sec = self.refill_per_sec;
+
+            buckets.retain(|_, v| {
+                v.tokens + now.duration_since(v.updated_at).as_secs_f64() * refill_per_sec
+                    < capacity
+            });
+
+            // Все корзины активны: вытесняем самую давнюю, иначе карта растёт без предела
+            if buckets.len() >= MAX_TRACKED_KEYS && !buckets.contains_key(&key) {
+                let oldest = buckets
+                    .iter()
+                    .min_by_key(|(_, v)| v.updated_at)
+                    .map(|(k, _)| k.clone());
+
+                if let Some(oldest) = oldest {
+                    buckets.remove(&oldest);
+                }
+            }
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.capacity,
+            updated_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.updated_at = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+
+        true
+    }
+
+    pub fn fill_ratio(&self, key: &K, now: Instant) -> f64 {
+        let buckets = self.buckets.lock().unwrap();
+
+        let Some(bucket) = buckets.get(key) else {
+            return 1.0;
+        };
+
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+
+        (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity) / self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TokenBucketLimiter, MAX_TRACKED_KEYS};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = TokenBucketLimiter::new(2, 6);
+        let now = Instant::now();
+
+        assert!(limiter.check("a", now));
+        assert!(limiter.check("a", now));
+        assert!(!limiter.check("a", now));
+        assert!(limiter.check("b", now));
+
+        // 6 в минуту - один токен раз в 10 секунд
+        assert!(!limiter.check("a", now + Duration::from_secs(5)));
+        assert!(limiter.check("a", now + Duration::from_secs(10)));
+        assert!(!limiter.check("a", now + Duration::from_secs(10)));
+
+        assert!(limiter.check("a", now + Duration::from_secs(600)));
+        assert!(limiter.check("a", now + Duration::from_secs(600)));
+        assert!(!limiter.check("a", now + Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn test_tracked_keys_limit() {
+        let limiter = TokenBucketLimiter::new(0, 6);
+        let now = Instant::now();
+
+        // Нулевой burst не ломает деление в fill_ratio
+        assert!(limiter.check(0, now));
+        assert_eq!(limiter.fill_ratio(&0, now), 0.0);
+
+        for key in 1..MAX_TRACKED_KEYS + 100 {
+            limiter.check(key, now + Duration::from_millis(key as u64));
+        }
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_TRACKED_KEYS);
+        assert!(!buckets.contains_key(&0));
+        assert!(buckets.contains_key(&(MAX_TRACKED_KEYS + 99)));
+    }
+}
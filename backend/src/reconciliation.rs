@@ -0,0 +1,540 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use mongodb::bson::serde_helpers::uuid_1_as_binary;
+use serde::{Deserialize, Serialize};
+use shared::PaymentServices;
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::get_db;
+use crate::invoice_handler::{Invoice, InvoiceData};
+use crate::reporting::ReportRange;
+
+/**
+Расхождение суммы меньше копейки считается совпадением
+ */
+const AMOUNT_TOLERANCE: f32 = 0.01;
+
+/**
+Возможные названия колонок в выгрузках. Сравниваются без учёта регистра
+ */
+struct StatementColumns {
+    external_id: &'static [&'static str],
+    order_id: &'static [&'static str],
+    amount: &'static [&'static str],
+    status: &'static [&'static str],
+}
+
+const ENOT_COLUMNS: StatementColumns = StatementColumns {
+    external_id: &["invoice_id", "id", "id транзакции", "id счета", "id счёта"],
+    order_id: &["order_id", "id заказа", "номер заказа"],
+    amount: &["amount", "сумма", "сумма заказа"],
+    status: &["status", "статус"],
+};
+
+const PAYPALICH_COLUMNS: StatementColumns = StatementColumns {
+    external_id: &["trsid", "trs_id", "id", "id платежа", "id транзакции"],
+    order_id: &["invid", "inv_id", "order_id", "номер заказа"],
+    amount: &["outsum", "out_sum", "amount", "сумма"],
+    status: &["status", "статус"],
+};
+
+/**
+Статусы строк выгрузки, которые считаются оплатой
+ */
+const PAID_STATUSES: &[&str] = &[
+    "success",
+    "succeeded",
+    "paid",
+    "underpaid",
+    "overpaid",
+    "успешно",
+    "оплачен",
+    "оплачено",
+];
+
+#[derive(Error, Debug, PartialEq)]
+pub enum StatementError {
+    #[error("Statements are not supported for {0}")]
+    UnsupportedService(PaymentServices),
+    #[error("Statement is empty")]
+    Empty,
+    #[error("Column {0} not found")]
+    MissingColumn(&'static str),
+    #[error("Line {line}: wrong amount {value}")]
+    WrongAmount { line: usize, value: String },
+    #[error("Line {line}: wrong column count")]
+    WrongColumnCount { line: usize },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementRow {
+    pub external_id: String,
+    pub order_id: Option<Uuid>,
+    pub amount: f32,
+    pub paid: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum DiscrepancyKind {
+    /**
+    Оплачен у платёжки, но у нас счёт не найден или не в статусе `Payed`
+     */
+    MissingInDb,
+    /**
+    Оплачен у нас, но отсутствует в выгрузке
+     */
+    MissingAtProvider,
+    AmountMismatch,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Discrepancy {
+    #[serde(with = "uuid_1_as_binary")]
+    pub id: Uuid,
+    pub kind: DiscrepancyKind,
+    pub external_id: Option<String>,
+    pub order_id: Option<String>,
+    pub provider_amount: Option<f32>,
+    pub our_amount: Option<f32>,
+    /**
+    Статус счёта у нас, если он найден
+     */
+    pub our_status: Option<String>,
+    pub resolved: bool,
+    pub resolution_note: Option<String>,
+    pub resolved_at: Option<DateTime<Utc>>,
+}
+
+/**
+Результат сверки выгрузки платёжки со счетами. Хранится в коллекции `reconciliation_report`
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReconciliationReport {
+    #[serde(rename = "_id")]
+    #[serde(with = "uuid_1_as_binary")]
+    pub id: Uuid,
+    pub service: PaymentServices,
+    pub period_from: Option<NaiveDate>,
+    pub period_to: Option<NaiveDate>,
+    pub created_at: DateTime<Utc>,
+    pub statement_rows: usize,
+    pub matched: usize,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl ReconciliationReport {
+    /**
+    `false`, если расхождения с таким id нет
+     */
+    pub fn resolve(&mut self, discrepancy_id: Uuid, note: String) -> bool {
+        let Some(discrepancy) = self
+            .discrepancies
+            .iter_mut()
+            .find(|v| v.id == discrepancy_id)
+        else {
+            return false;
+        };
+
+        discrepancy.resolved = true;
+        discrepancy.resolution_note = Some(note);
+        discrepancy.resolved_at = Some(Utc::now());
+
+        true
+    }
+}
+
+pub fn parse_statement(
+    service: PaymentServices,
+    statement: &str,
+) -> Result<Vec<StatementRow>, StatementError> {
+    let columns = match service {
+        PaymentServices::Enot => &ENOT_COLUMNS,
+        PaymentServices::Paypalych | PaymentServices::PaypalychUk => &PAYPALICH_COLUMNS,
+        PaymentServices::Hotskins => return Err(StatementError::UnsupportedService(service)),
+    };
+
+    let statement = statement.trim_start_matches('\u{feff}');
+
+    let header_line = statement.lines().next().ok_or(StatementError::Empty)?;
+
+    // Выгрузки из русского Excel разделены точкой с запятой
+    let delimiter = if header_line.matches(';').count() > header_line.matches(',').count() {
+        ';'
+    } else {
+        ','
+    };
+
+    let mut records = parse_csv(statement, delimiter).into_iter();
+
+    let header: Vec<String> = records
+        .next()
+        .ok_or(StatementError::Empty)?
+        .iter()
+        .map(|v| v.trim().to_lowercase())
+        .collect();
+
+    let find = |names: &[&str]| header.iter().position(|v| names.contains(&v.as_str()));
+
+    let external_id_col = find(columns.external_id).ok_or(StatementError::MissingColumn("id"))?;
+    let amount_col = find(columns.amount).ok_or(StatementError::MissingColumn("amount"))?;
+    let order_id_col = find(columns.order_id);
+    let status_col = find(columns.status);
+
+    let mut rows = vec![];
+
+    for (i, record) in records.enumerate() {
+        // Строка заголовка - первая
+        let line = i + 2;
+
+        if record.iter().all(|v| v.trim().is_empty()) {
+            continue;
+        }
+
+        if record.len() < header.len() {
+            return Err(StatementError::WrongColumnCount { line });
+        }
+
+        let amount_raw = record[amount_col].trim();
+        let amount = parse_amount(amount_raw).ok_or_else(|| StatementError::WrongAmount {
+            line,
+            value: amount_raw.to_string(),
+        })?;
+
+        rows.push(StatementRow {
+            external_id: record[external_id_col].trim().to_string(),
+            order_id: order_id_col.and_then(|c| Uuid::parse_str(record[c].trim()).ok()),
+            amount,
+            paid: status_col
+                .map(|c| PAID_STATUSES.contains(&record[c].trim().to_lowercase().as_str()))
+                .unwrap_or(true),
+        });
+    }
+
+    Ok(rows)
+}
+
+/**
+Суммы бывают с пробелами между разрядами и запятой вместо точки: `1 500,00`
+ */
+fn parse_amount(value: &str) -> Option<f32> {
+    let value: String = value
+        .chars()
+        .filter(|v| !v.is_whitespace())
+        .map(|v| if v == ',' { '.' } else { v })
+        .collect();
+
+    value.parse::<f32>().ok().filter(|v| v.is_finite())
+}
+
+/**
+Разбор CSV по RFC 4180: поля в кавычках могут содержать разделитель и переводы строк
+ */
+fn parse_csv(input: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => in_quotes = false,
+                _ => field.push(c),
+            }
+
+            continue;
+        }
+
+        match c {
+            '"' => in_quotes = true,
+            '\r' => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c if c == delimiter => record.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    records
+}
+
+/**
+Разбирает выгрузку, сверяет её со счетами за период и сохраняет отчёт
+ */
+pub async fn import_statement(
+    service: PaymentServices,
+    range: ReportRange,
+    statement: &str,
+) -> anyhow::Result<ReconciliationReport> {
+    let rows = parse_statement(service, statement)?;
+
+    let db = get_db().await;
+
+    let period_invoices = db.get_paid_invoices(service, range).await?;
+    let period_paid: HashSet<Uuid> = period_invoices.iter().map(|v| v.id).collect();
+
+    let referenced = db
+        .get_invoices_by_external_ids(
+            service,
+            rows.iter().map(|v| v.external_id.clone()).collect(),
+            rows.iter().filter_map(|v| v.order_id).collect(),
+        )
+        .await?;
+
+    let mut invoices = period_invoices;
+    invoices.extend(
+        referenced
+            .into_iter()
+            .filter(|v| !period_paid.contains(&v.id)),
+    );
+
+    let (matched, discrepancies) = reconcile(&rows, &invoices, &period_paid);
+
+    let report = ReconciliationReport {
+        id: Uuid::new_v4(),
+        service,
+        period_from: range.from,
+        period_to: range.to,
+        created_at: Utc::now(),
+        statement_rows: rows.len(),
+        matched,
+        discrepancies,
+    };
+
+    db.create_reconciliation_report(&report).await?;
+
+    Ok(report)
+}
+
+/**
+Сумма, которую должна показать платёжка
+ */
+fn expected_amount(invoice: &Invoice) -> f32 {
+    invoice
+        .settlement
+        .as_ref()
+        .map(|v| v.gross)
+        .unwrap_or(invoice.charged_amount())
+}
+
+fn status_name(data: &InvoiceData) -> &'static str {
+    match data {
+        InvoiceData::WaitingForPayment { .. } => "WaitingForPayment",
+        InvoiceData::FailedToCreate { .. } => "FailedToCreate",
+        InvoiceData::Aborted { .. } => "Aborted",
+        InvoiceData::Payed { .. } => "Payed",
+        InvoiceData::Refunded { .. } => "Refunded",
+    }
+}
+
+/**
+Сверяет оплаченные строки выгрузки со счетами. В `invoices` должны быть все оплаченные
+у нас счета за период выгрузки и счета, на которые ссылается выгрузка.
+Возвращает количество совпавших строк и расхождения
+ */
+pub fn reconcile(
+    rows: &[StatementRow],
+    invoices: &[Invoice],
+    period_paid: &HashSet<Uuid>,
+) -> (usize, Vec<Discrepancy>) {
+    let by_external_id: HashMap<&str, &Invoice> = invoices
+        .iter()
+        .filter_map(|v| v.data.external_id().map(|id| (id, v)))
+        .filter(|(id, _)| !id.is_empty())
+        .collect();
+    let by_order_id: HashMap<Uuid, &Invoice> = invoices.iter().map(|v| (v.id, v)).collect();
+
+    let mut matched = 0;
+    let mut seen = HashSet::new();
+    let mut discrepancies = vec![];
+
+    let discrepancy = |kind, row: Option<&StatementRow>, invoice: Option<&Invoice>| Discrepancy {
+        id: Uuid::new_v4(),
+        kind,
+        external_id: row
+            .map(|v| v.external_id.clone())
+            .or_else(|| invoice.and_then(|v| v.data.external_id().map(str::to_string))),
+        order_id: row
+            .and_then(|v| v.order_id)
+            .or(invoice.map(|v| v.id))
+            .map(|v| v.to_string()),
+        provider_amount: row.map(|v| v.amount),
+        our_amount: invoice.map(expected_amount),
+        our_status: invoice.map(|v| status_name(&v.data).to_string()),
+        resolved: false,
+        resolution_note: None,
+        resolved_at: None,
+    };
+
+    for row in rows.iter().filter(|v| v.paid) {
+        let invoice = by_external_id
+            .get(row.external_id.as_str())
+            .or_else(|| row.order_id.and_then(|v| by_order_id.get(&v)))
+            .copied();
+
+        let Some(invoice) = invoice else {
+            discrepancies.push(discrepancy(DiscrepancyKind::MissingInDb, Some(row), None));
+            continue;
+        };
+
+        seen.insert(invoice.id);
+
+        if !matches!(invoice.data, InvoiceData::Payed { .. }) {
+            discrepancies.push(discrepancy(
+                DiscrepancyKind::MissingInDb,
+                Some(row),
+                Some(invoice),
+            ));
+        } else if (expected_amount(invoice) - row.amount).abs() >= AMOUNT_TOLERANCE {
+            discrepancies.push(discrepancy(
+                DiscrepancyKind::AmountMismatch,
+                Some(row),
+                Some(invoice),
+            ));
+        } else {
+            matched += 1;
+        }
+    }
+
+    for invoice in invoices
+        .iter()
+        .filter(|v| period_paid.contains(&v.id) && !seen.contains(&v.id))
+    {
+        discrepancies.push(discrepancy(
+            DiscrepancyKind::MissingAtProvider,
+            None,
+            Some(invoice),
+        ));
+    }
+
+    (matched, discrepancies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_statement, reconcile, DiscrepancyKind, StatementError, StatementRow};
+    use crate::invoice_handler::{Invoice, InvoiceData};
+    use shared::PaymentServices;
+    use std::collections::HashSet;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_parse_statement() {
+        let order_id = Uuid::new_v4();
+        let statement = format!(
+            "\u{feff}ID;Номер заказа;Сумма;Статус\r\n\
+            a1;{order_id};\"1 500,50\";Успешно\r\n\
+            \"a;2\";;100;expired\r\n\
+            \r\n"
+        );
+
+        assert_eq!(
+            parse_statement(PaymentServices::Enot, &statement),
+            Ok(vec![
+                StatementRow {
+                    external_id: "a1".to_string(),
+                    order_id: Some(order_id),
+                    amount: 1500.5,
+                    paid: true,
+                },
+                StatementRow {
+                    external_id: "a;2".to_string(),
+                    order_id: None,
+                    amount: 100.0,
+                    paid: false,
+                },
+            ])
+        );
+
+        assert_eq!(
+            parse_statement(PaymentServices::Paypalych, "TrsId,OutSum\nx,abc\n"),
+            Err(StatementError::WrongAmount {
+                line: 2,
+                value: "abc".to_string()
+            })
+        );
+        assert_eq!(
+            parse_statement(PaymentServices::Paypalych, "foo,bar\n"),
+            Err(StatementError::MissingColumn("id"))
+        );
+    }
+
+    fn invoice(external_id: &str, amount: f32, data: fn(String) -> InvoiceData) -> Invoice {
+        let mut invoice = Invoice::test_new(amount);
+        invoice.data = data(external_id.to_string());
+        invoice
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let payed = |external_id| InvoiceData::Payed {
+            stored_in_l2_db: true,
+            external_id,
+        };
+        let aborted = |external_id| InvoiceData::Aborted {
+            reason: String::new(),
+            external_id,
+        };
+
+        let ok = invoice("ok", 100.0, payed);
+        let wrong_sum = invoice("sum", 100.0, payed);
+        let not_paid = invoice("aborted", 50.0, aborted);
+        let missing_at_provider = invoice("lost", 10.0, payed);
+
+        let row = |external_id: &str, amount| StatementRow {
+            external_id: external_id.to_string(),
+            order_id: None,
+            amount,
+            paid: true,
+        };
+
+        let rows = vec![
+            row("ok", 100.0),
+            row("sum", 90.0),
+            row("aborted", 50.0),
+            row("unknown", 5.0),
+            StatementRow {
+                paid: false,
+                ..row("ignored", 1.0)
+            },
+        ];
+
+        let period_paid: HashSet<Uuid> = [ok.id, wrong_sum.id, missing_at_provider.id].into();
+
+        let (matched, discrepancies) = reconcile(
+            &rows,
+            &[ok, wrong_sum, not_paid, missing_at_provider],
+            &period_paid,
+        );
+
+        assert_eq!(matched, 1);
+
+        let kinds: Vec<(DiscrepancyKind, Option<String>)> = discrepancies
+            .into_iter()
+            .map(|v| (v.kind, v.external_id))
+            .collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                (DiscrepancyKind::AmountMismatch, Some("sum".to_string())),
+                (DiscrepancyKind::MissingInDb, Some("aborted".to_string())),
+                (DiscrepancyKind::MissingInDb, Some("unknown".to_string())),
+                (DiscrepancyKind::MissingAtProvider, Some("lost".to_string())),
+            ]
+        );
+    }
+}
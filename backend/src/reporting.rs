@@ -0,0 +1,399 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
+
+use crate::pay_services::USD_RATE;
+
+/**
+Счёт считается оплаченным, пока он в статусе `Payed`. Возвраты в выручку не попадают
+ */
+const PAID_FILTER: &str = "data.Payed";
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportPeriod {
+    #[default]
+    Day,
+    /**
+    Неделя по ISO 8601, например 2024-W05
+     */
+    Week,
+    Month,
+}
+
+impl ReportPeriod {
+    fn date_format(&self) -> &'static str {
+        match self {
+            ReportPeriod::Day => "%Y-%m-%d",
+            ReportPeriod::Week => "%G-W%V",
+            ReportPeriod::Month => "%Y-%m",
+        }
+    }
+}
+
+/**
+Границы отчёта в UTC, `to` не включается
+ */
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ReportRange {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+impl ReportRange {
+    /**
+    Даты в базе хранятся строками RFC 3339, поэтому сравниваются как строки
+     */
+    pub(crate) fn to_filter(self, field: &str) -> Document {
+        let mut range = Document::new();
+
+        if let Some(from) = self.from {
+            range.insert("$gte", date_to_bson(from));
+        }
+
+        if let Some(to) = self.to {
+            range.insert("$lt", date_to_bson(to));
+        }
+
+        if range.is_empty() {
+            Document::new()
+        } else {
+            doc! { field: range }
+        }
+    }
+}
+
+fn date_to_bson(date: NaiveDate) -> Bson {
+    let date: DateTime<Utc> = date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+
+    Bson::String(date.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+}
+
+/**
+Строковая дата из базы в BSON Date. Дробная часть секунд отбрасывается: chrono пишет
+наносекунды, которые `$dateFromString` не разбирает
+ */
+fn parse_date(field: &str) -> Document {
+    doc! {
+        "$dateFromString": {
+            "dateString": {"$concat": [{"$substrBytes": [field, 0, 19]}, "Z"]}
+        }
+    }
+}
+
+fn period_key(field: &str, period: ReportPeriod) -> Document {
+    doc! {
+        "$dateToString": {"format": period.date_format(), "date": parse_date(field)}
+    }
+}
+
+/**
+То же, что `Invoice::currency`
+ */
+fn invoice_currency() -> Document {
+    doc! {
+        "$ifNull": [
+            "$currency_rate.currency",
+            {"$cond": [{"$eq": ["$service", "PaypalychUk"]}, "USD", "RUB"]}
+        ]
+    }
+}
+
+/**
+То же, что `Invoice::amount_rub`
+ */
+fn amount_rub() -> Document {
+    doc! {
+        "$cond": [
+            {"$and": [
+                {"$eq": ["$service", "PaypalychUk"]},
+                {"$gt": [{"$size": {"$ifNull": ["$lines", []]}}, 0]}
+            ]},
+            {"$multiply": ["$amount", USD_RATE as f64]},
+            {"$multiply": ["$amount", {"$ifNull": ["$currency_rate.crd_rate", 1.0]}]}
+        ]
+    }
+}
+
+/**
+То же, что `Invoice::charged_amount` для оплаченного счёта: сумма в валюте `invoice_currency`.
+У CRD-счетов PaypalychUk `amount` после оплаты пересчитан в рубли
+ */
+fn charged_amount() -> Document {
+    doc! {
+        "$cond": [
+            {"$and": [
+                {"$eq": ["$service", "PaypalychUk"]},
+                {"$eq": [{"$size": {"$ifNull": ["$lines", []]}}, 0]},
+                {"$eq": [{"$ifNull": ["$currency_rate", null]}, null]}
+            ]},
+            {"$divide": ["$amount", USD_RATE as f64]},
+            "$amount"
+        ]
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RevenueRow {
+    pub period: String,
+    pub service: String,
+    pub currency: String,
+    pub pay_method: String,
+    pub count: i64,
+    pub volume: f64,
+    pub average: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConversionRow {
+    pub period: String,
+    pub service: String,
+    pub created: i64,
+    pub paid: i64,
+    pub conversion: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TopDonorRow {
+    pub char_name: String,
+    pub count: i64,
+    pub volume_rub: f64,
+}
+
+/**
+Выручка по оплаченным счетам. Период считается по времени оплаты, если оно известно,
+иначе по времени создания счёта
+ */
+pub fn revenue_pipeline(period: ReportPeriod, range: ReportRange) -> Vec<Document> {
+    vec![
+        doc! {"$match": {PAID_FILTER: {"$exists": true}}},
+        doc! {"$addFields": {"report_time": {"$ifNull": ["$settlement.pay_time", "$created_at"]}}},
+        doc! {"$match": range.to_filter("report_time")},
+        doc! {
+            "$group": {
+                "_id": {
+                    "period": period_key("$report_time", period),
+                    "service": "$service",
+                    "currency": invoice_currency(),
+                    "pay_method": {"$ifNull": ["$settlement.pay_method", "unknown"]},
+                },
+                "count": {"$sum": 1},
+                "volume": {"$sum": charged_amount()},
+                "average": {"$avg": charged_amount()},
+            }
+        },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "period": "$_id.period",
+                "service": "$_id.service",
+                "currency": "$_id.currency",
+                "pay_method": "$_id.pay_method",
+                "count": {"$toLong": "$count"},
+                "volume": {"$toDouble": "$volume"},
+                "average": {"$toDouble": "$average"},
+            }
+        },
+        doc! {"$sort": {"period": 1, "service": 1, "currency": 1, "pay_method": 1}},
+    ]
+}
+
+/**
+Доля созданных счетов, дошедших до оплаты. Период считается по времени создания
+ */
+pub fn conversion_pipeline(period: ReportPeriod, range: ReportRange) -> Vec<Document> {
+    vec![
+        doc! {"$match": range.to_filter("created_at")},
+        doc! {
+            "$group": {
+                "_id": {
+                    "period": period_key("$created_at", period),
+                    "service": "$service",
+                },
+                "created": {"$sum": 1},
+                "paid": {"$sum": {"$cond": [{"$ifNull": [format!("${PAID_FILTER}"), false]}, 1, 0]}},
+            }
+        },
+        doc! {
+            "$project": {
+                "_id": 0,
+                "period": "$_id.period",
+                "service": "$_id.service",
+                "created": {"$toLong": "$created"},
+                "paid": {"$toLong": "$paid"},
+                "conversion": {"$divide": ["$paid", "$created"]},
+            }
+        },
+        doc! {"$sort": {"period": 1, "service": 1}},
+    ]
+}
+
+/**
+Персонажи с наибольшей суммой оплат. Суммы в разных валютах приводятся к рублям
+ */
+pub fn top_donors_pipeline(range: ReportRange, limit: i64) -> Vec<Document> {
+    vec![
+        doc! {"$match": {PAID_FILTER: {"$exists": true}}},
+        doc! {"$match": range.to_filter("created_at")},
+        doc! {
+            "$group": {
+                "_id": "$char_name",
+                "count": {"$sum": 1},
+                "volume_rub": {"$sum": amount_rub()},
+            }
+        },
+        doc! {"$sort": {"volume_rub": -1, "_id": 1}},
+        doc! {"$limit": limit},
+        doc! {
+            "$project": {
+                "_id": 0,
+                "char_name": "$_id",
+                "count": {"$toLong": "$count"},
+                "volume_rub": {"$toDouble": "$volume_rub"},
+            }
+        },
+    ]
+}
+
+/**
+Строки отчёта для выгрузки в CSV
+ */
+pub trait CsvRow {
+    fn header() -> &'static [&'static str];
+    fn fields(&self) -> Vec<String>;
+}
+
+impl CsvRow for RevenueRow {
+    fn header() -> &'static [&'static str] {
+        &[
+            "period",
+            "service",
+            "currency",
+            "pay_method",
+            "count",
+            "volume",
+            "average",
+        ]
+    }
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.period.clone(),
+            self.service.clone(),
+            self.currency.clone(),
+            self.pay_method.clone(),
+            self.count.to_string(),
+            format!("{:.2}", self.volume),
+            format!("{:.2}", self.average),
+        ]
+    }
+}
+
+impl CsvRow for ConversionRow {
+    fn header() -> &'static [&'static str] {
+        &["period", "service", "created", "paid", "conversion"]
+    }
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.period.clone(),
+            self.service.clone(),
+            self.created.to_string(),
+            self.paid.to_string(),
+            format!("{:.4}", self.conversion),
+        ]
+    }
+}
+
+impl CsvRow for TopDonorRow {
+    fn header() -> &'static [&'static str] {
+        &["char_name", "count", "volume_rub"]
+    }
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.char_name.clone(),
+            self.count.to_string(),
+            format!("{:.2}", self.volume_rub),
+        ]
+    }
+}
+
+pub fn to_csv<T: CsvRow>(rows: &[T]) -> String {
+    let mut res = T::header().join(",");
+    res.push_str("\r\n");
+
+    for row in rows {
+        let fields: Vec<String> = row.fields().iter().map(|v| csv_escape(v)).collect();
+
+        res.push_str(&fields.join(","));
+        res.push_str("\r\n");
+    }
+
+    res
+}
+
+/**
+Экранирование по RFC 4180. Значения, начинающиеся с `=+-@`, дополнительно экранируются,
+чтобы Excel не выполнил их как формулу
+ */
+fn csv_escape(value: &str) -> String {
+    let value = match value.chars().next() {
+        Some('=' | '+' | '@') => format!("'{value}"),
+        Some('-') if value.parse::<f64>().is_err() => format!("'{value}"),
+        _ => value.to_string(),
+    };
+
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_csv, ReportRange, TopDonorRow};
+    use chrono::NaiveDate;
+    use mongodb::bson::doc;
+
+    #[test]
+    fn test_report_range_filter() {
+        let range = ReportRange {
+            from: NaiveDate::from_ymd_opt(2024, 1, 1),
+            to: NaiveDate::from_ymd_opt(2024, 2, 1),
+        };
+
+        assert_eq!(
+            range.to_filter("created_at"),
+            doc! {"created_at": {"$gte": "2024-01-01T00:00:00Z", "$lt": "2024-02-01T00:00:00Z"}}
+        );
+        assert_eq!(ReportRange::default().to_filter("created_at"), doc! {});
+    }
+
+    #[test]
+    fn test_csv() {
+        let rows = vec![
+            TopDonorRow {
+                char_name: "Simple".to_string(),
+                count: 2,
+                volume_rub: 1500.0,
+            },
+            TopDonorRow {
+                char_name: "Qu\"ote,d".to_string(),
+                count: 1,
+                volume_rub: 99.999,
+            },
+            TopDonorRow {
+                char_name: "=HYPERLINK(1)".to_string(),
+                count: 1,
+                volume_rub: -5.0,
+            },
+        ];
+
+        assert_eq!(
+            to_csv(&rows),
+            "char_name,count,volume_rub\r\nSimple,2,1500.00\r\n\"Qu\"\"ote,d\",1,100.00\r\n'=HYPERLINK(1),1,-5.00\r\n"
+        );
+    }
+}
@@ -1,42 +1,47 @@
 use crate::database_connection::DbResponse;
-use crate::invoice_handler::InvoiceData;
+use crate::invoice_handler::{Invoice, InvoiceData};
+use crate::jobs::enqueue_delivery;
+use crate::mailer::{enqueue_receipt, ReceiptKind};
+use crate::notifier::{Notification, NOTIFIER};
+use crate::outgoing_webhooks::{dispatch_invoice_event, InvoiceEvent};
+use crate::CONFIG;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
 use crate::vote_services::mmotop::MmotopScrapper;
-use crate::{get_db, get_db_mut};
-use std::time::Duration;
-use tokio::time::sleep;
+use crate::get_db;
+use uuid::Uuid;
 
-pub fn spawn_tasks() {
-    tokio::spawn(async move {
-        loop {
-            sleep(Duration::from_secs(10)).await;
+/**
+Аренда счёта на выдачу. Дольше таймаута задачи, чтобы второй воркер не забрал счёт,
+пока первый ещё выдаёт
+ */
+const DELIVERY_LEASE_SECS: i64 = 300;
 
-            get_db_mut().await.validate_connections().await;
-
-            give_crd().await;
-
-            give_votes().await;
-        }
-    });
-}
-
-async fn give_votes() {
+pub async fn give_votes() -> Result<()> {
     let options = get_db().await.get_vote_options().await;
 
     let mut scrapper = MmotopScrapper {
         last_id: options.last_mmotop_id,
     };
 
-    let Ok(records) = scrapper.scrap().await else {
-        return;
+    let records = match scrapper.scrap().await {
+        Ok(v) => v,
+        Err(e) => {
+            NOTIFIER
+                .notify(Notification::VoteScrapperFailed {
+                    error: e.to_string(),
+                })
+                .await;
+
+            return Err(e);
+        }
     };
 
     let mut changed = false;
 
     for record in records {
         let Ok(char_id) = get_db().await.get_char_id_by_name(&record.name).await else {
-            println!("Err on get char name");
-
-            return;
+            return Err(anyhow!("Err on get char name"));
         };
 
         let DbResponse::NotFound(char_id) = char_id else {
@@ -63,42 +68,102 @@ async fn give_votes() {
             .update_last_mmotop_id(options.id, scrapper.last_id.0)
             .await;
     }
+
+    Ok(())
 }
 
-async fn give_crd() {
+/**
+Выдаёт персонажу покупку по оплаченному счёту. Счёт забирается под выдачу атомарно, а
+товары по одному счёту кладутся в `items_delayed` не больше одного раза, поэтому повтор
+задачи и второй воркер не выдадут покупку дважды
+ */
+pub async fn deliver_invoice(invoice_id: Uuid) -> Result<()> {
+    let now = Utc::now().timestamp();
+
+    let claimed = get_db()
+        .await
+        .claim_invoice_delivery(invoice_id, now, now + DELIVERY_LEASE_SECS)
+        .await?;
+
+    let Some(invoice) = claimed else {
+        let invoice = get_db().await.get_invoice_by_id(invoice_id).await;
+
+        return match invoice.map(|v| v.data) {
+            Some(InvoiceData::Payed {
+                stored_in_l2_db: false,
+                ..
+            }) => Err(anyhow!("Invoice {invoice_id} is being delivered by another worker")),
+            _ => Ok(()),
+        };
+    };
+
+    let res = store_invoice_items(&invoice).await;
+
+    if res.is_err() {
+        if let Err(e) = get_db().await.release_invoice_delivery(invoice_id).await {
+            println!("Err on release delivery {invoice_id} {e:#?}");
+        }
+    }
+
+    if !res? {
+        println!("Invoice {invoice_id} was refunded during delivery, items are already given");
+
+        return Ok(());
+    }
+
+    enqueue_receipt(&invoice, ReceiptKind::Delivered).await;
+    dispatch_invoice_event(&invoice, InvoiceEvent::Delivered).await;
+
+    Ok(())
+}
+
+/**
+Кладёт товары в игру и отмечает счёт выданным. `false` - счёт вернули во время выдачи
+ */
+async fn store_invoice_items(invoice: &Invoice) -> Result<bool> {
+    let Some(items) = invoice.delayed_items() else {
+        return Err(anyhow!("Item count overflow in invoice {}", invoice.id));
+    };
+
+    let stored = get_db()
+        .await
+        .add_items_to_delayed(
+            invoice.char_id,
+            &invoice.delayed_description(),
+            &items,
+            invoice.id,
+            &invoice.service.to_string(),
+        )
+        .await?;
+
+    if !stored {
+        println!("Items of invoice {} are already in items_delayed", invoice.id);
+    }
+
+    get_db().await.finish_invoice_delivery(invoice.id).await
+}
+
+/**
+Подстраховка для счетов, выдача которых не попала в очередь или ушла в `job_dead_letter`
+ */
+pub async fn check_undelivered() -> Result<()> {
     let invoices = get_db().await.get_unfinished_payed_invoices().await;
 
     for invoice in &invoices {
-        if let InvoiceData::Payed {
-            external_id,
-            stored_in_l2_db,
-        } = &invoice.data
-        {
-            if !stored_in_l2_db
-                && get_db()
-                    .await
-                    .add_crd_to_delayed(
-                        invoice.char_id,
-                        &invoice.char_name,
-                        invoice.amount as u32,
-                        invoice.id,
-                        &invoice.service.to_string(),
-                    )
-                    .await
-                    .is_ok()
-            {
-                get_db()
-                    .await
-                    .update_invoice_data(
-                        invoice.id,
-                        InvoiceData::Payed {
-                            stored_in_l2_db: true,
-                            external_id: external_id.clone(),
-                        },
-                    )
-                    .await
-                    .unwrap();
-            }
+        enqueue_delivery(invoice.id).await;
+
+        let minutes = (Utc::now() - invoice.updated_at).num_minutes();
+
+        if minutes >= CONFIG.notify_delivery_stuck_minutes {
+            NOTIFIER
+                .notify(Notification::DeliveryStuck {
+                    order_id: invoice.id,
+                    char_name: invoice.char_name.clone(),
+                    minutes,
+                })
+                .await;
         }
     }
+
+    Ok(())
 }
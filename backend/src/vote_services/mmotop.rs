@@ -28,6 +28,7 @@ impl MmotopScrapper {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct MmotopRecord {
     pub record_id: u32,
@@ -1,7 +1,10 @@
 use anyhow::Result;
 
 use gloo_net::http::Request;
-use shared::{CreateInvoice, InvoiceCreationResponse, PaymentServices};
+use shared::{
+    CatalogProduct, CharacterInfoResponse, CreateInvoice, CurrencyRate, InvoiceCreationResponse,
+    InvoiceInfoResponse, PayMethod, ProviderStatus,
+};
 
 const BACKEND_API_URL: &str = "https://pay.la2world.ru/api/v1";
 // const BACKEND_API_URL: &str = "http://127.0.0.1:14082/api/v1";
@@ -9,17 +12,7 @@ const BACKEND_API_URL: &str = "https://pay.la2world.ru/api/v1";
 pub struct BackendApi {}
 
 impl BackendApi {
-    pub async fn create_invoice(
-        char_name: String,
-        crd: u32,
-        payment_service: PaymentServices,
-    ) -> Result<InvoiceCreationResponse> {
-        let params = CreateInvoice {
-            amount: crd as f32,
-            char_name,
-            service: payment_service,
-        };
-
+    pub async fn create_invoice(params: CreateInvoice) -> Result<InvoiceCreationResponse> {
         let resp = Request::post(&format!("{BACKEND_API_URL}/payments/create"))
             .header("Content-Type", "application/json")
             .body(serde_json::to_string(&params).unwrap())?
@@ -28,4 +21,52 @@ impl BackendApi {
 
         Ok(resp.json::<InvoiceCreationResponse>().await?)
     }
+
+    pub async fn get_invoice(order_id: &str) -> Result<InvoiceInfoResponse> {
+        let resp = Request::get(&format!("{BACKEND_API_URL}/payments/{order_id}"))
+            .send()
+            .await?;
+
+        Ok(resp.json::<InvoiceInfoResponse>().await?)
+    }
+
+    pub async fn get_character(char_name: &str) -> Result<CharacterInfoResponse> {
+        let resp = Request::get(&format!("{BACKEND_API_URL}/characters/{char_name}"))
+            .send()
+            .await?;
+
+        Ok(resp.json::<CharacterInfoResponse>().await?)
+    }
+
+    pub async fn get_catalog() -> Result<Vec<CatalogProduct>> {
+        let resp = Request::get(&format!("{BACKEND_API_URL}/catalog"))
+            .send()
+            .await?;
+
+        Ok(resp.json::<Vec<CatalogProduct>>().await?)
+    }
+
+    pub async fn get_pay_methods() -> Result<Vec<PayMethod>> {
+        let resp = Request::get(&format!("{BACKEND_API_URL}/payments/methods"))
+            .send()
+            .await?;
+
+        Ok(resp.json::<Vec<PayMethod>>().await?)
+    }
+
+    pub async fn get_providers() -> Result<Vec<ProviderStatus>> {
+        let resp = Request::get(&format!("{BACKEND_API_URL}/payments/providers"))
+            .send()
+            .await?;
+
+        Ok(resp.json::<Vec<ProviderStatus>>().await?)
+    }
+
+    pub async fn get_currencies() -> Result<Vec<CurrencyRate>> {
+        let resp = Request::get(&format!("{BACKEND_API_URL}/payments/currencies"))
+            .send()
+            .await?;
+
+        Ok(resp.json::<Vec<CurrencyRate>>().await?)
+    }
 }
@@ -0,0 +1,74 @@
+use shared::{CartLine, CatalogProduct, Currency};
+use yew::prelude::*;
+
+#[derive(Clone, PartialEq, Properties)]
+pub struct CartProps {
+    pub products: Vec<CatalogProduct>,
+    pub cart: Vec<CartLine>,
+    pub currency: Currency,
+    pub on_change: Callback<(String, i32)>,
+}
+
+pub struct Cart {}
+
+impl Component for Cart {
+    type Message = ();
+    type Properties = CartProps;
+
+    fn create(_ctx: &Context<Self>) -> Self {
+        Self {}
+    }
+
+    fn view(&self, ctx: &Context<Self>) -> Html {
+        let props = ctx.props();
+
+        if props.cart.is_empty() {
+            return html! {};
+        }
+
+        let mut total = 0.0;
+
+        let lines: Vec<Html> = props
+            .cart
+            .iter()
+            .map(|line| {
+                let product = props.products.iter().find(|v| v.id == line.product_id);
+                let price = product.and_then(|v| v.prices.get(&props.currency)).copied();
+
+                total += price.unwrap_or_default() * line.quantity as f32;
+
+                let on_change = props.on_change.clone();
+                let id = line.product_id.clone();
+                let on_dec = Callback::from(move |_| on_change.emit((id.clone(), -1)));
+
+                let on_change = props.on_change.clone();
+                let id = line.product_id.clone();
+                let on_inc = Callback::from(move |_| on_change.emit((id.clone(), 1)));
+
+                html! {
+                    <div class="cart_line">
+                        <div class="cart_name">{ product.map(|v| v.name.clone()).unwrap_or_default() }</div>
+                        <button class="cart_btn" onclick={on_dec}>{ "-" }</button>
+                        <div class="cart_qty">{ line.quantity }</div>
+                        <button class="cart_btn" onclick={on_inc}>{ "+" }</button>
+                        <div class="cart_sum">
+                        {
+                            match price {
+                                Some(price) => format!("{} {}", price * line.quantity as f32, props.currency),
+                                None => "Недоступно".to_string(),
+                            }
+                        }
+                        </div>
+                    </div>
+                }
+            })
+            .collect();
+
+        html! {
+            <div class="cart">
+                { for lines }
+                <div class="cart_total">{ format!("Итого: {total} {}", props.currency) }</div>
+            </div>
+        }
+    }
+}
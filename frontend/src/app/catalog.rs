@@ -0,0 +1,73 @@
+use shared::{CartLine, CatalogProduct, Currency};
+use yew::prelude::*;
+
+#[derive(Clone, PartialEq, Properties)]
+pub struct CatalogProps {
+    pub products: Vec<CatalogProduct>,
+    pub cart: Vec<CartLine>,
+    pub currency: Currency,
+    pub on_add: Callback<String>,
+}
+
+pub struct Catalog {}
+
+impl Component for Catalog {
+    type Message = ();
+    type Properties = CatalogProps;
+
+    fn create(_ctx: &Context<Self>) -> Self {
+        Self {}
+    }
+
+    fn view(&self, ctx: &Context<Self>) -> Html {
+        let props = ctx.props();
+
+        if props.products.is_empty() {
+            return html! {
+                <div class="dlg_r_hs">{ "Магазин пуст" }</div>
+            };
+        }
+
+        html! {
+            <div class="catalog">
+            {
+                for props.products.iter().map(|product| {
+                    let id = product.id.clone();
+                    let on_add = props.on_add.clone();
+                    let onclick = Callback::from(move |_| on_add.emit(id.clone()));
+
+                    let is_selected = props.cart.iter().any(|v| v.product_id == product.id);
+
+                    html! {
+                        <div class={classes!("catalog_item", is_selected.then_some("catalog_item_selected"))} {onclick}>
+                            {
+                                if let Some(image) = &product.image {
+                                    html!{ <img class="catalog_img" src={image.clone()} alt={product.name.clone()} /> }
+                                } else {
+                                    html!{}
+                                }
+                            }
+                            <div class="catalog_name">{ &product.name }</div>
+                            {
+                                if let Some(description) = &product.description {
+                                    html!{ <div class="catalog_desc">{ description }</div> }
+                                } else {
+                                    html!{}
+                                }
+                            }
+                            <div class="catalog_price">
+                            {
+                                match product.prices.get(&props.currency) {
+                                    Some(price) => format!("{price} {}", props.currency),
+                                    None => "Недоступно".to_string(),
+                                }
+                            }
+                            </div>
+                        </div>
+                    }
+                })
+            }
+            </div>
+        }
+    }
+}
@@ -1,47 +1,307 @@
 use crate::app::api::BackendApi;
-use crate::app::util::{get_value_from_event, get_value_from_input_event};
+use crate::app::cart::Cart;
+use crate::app::catalog::Catalog;
+use crate::app::status::InvoiceStatusPage;
+use crate::app::util::{
+    get_browser_locale, get_checked_from_event, get_query_param, get_value_from_event,
+    get_value_from_input_event, payment_error_message,
+};
 use gloo_console::log;
-use shared::{InvoiceCreationResponse, PaymentServices};
+use gloo_timers::future::TimeoutFuture;
+use shared::pow::solve_range;
+use shared::{
+    CartLine, CatalogProduct, CharacterInfoResponse, CreateInvoice, Currency, CurrencyRate,
+    GiftDetails, InvoiceCreationResponse, PayMethod, PaymentServices, PowChallenge, PowSolution,
+    ProviderStatus,
+};
 use std::str::FromStr;
 use yew::prelude::*;
 
 mod api;
+mod cart;
+mod catalog;
+mod status;
 mod util;
 
 const MIN_CRD: u32 = 20;
+const NICK_CHECK_DELAY_MS: u32 = 500;
+const GIFT_MESSAGE_MAX_LEN: u32 = 200;
+/**
+Сколько nonce перебирается между передачами управления браузеру
+ */
+const POW_CHUNK: u64 = 20_000;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Page {
+    Crd,
+    Shop,
+}
 
 pub enum PaymentMsg {
+    SwitchPage(Page),
+    CatalogLoaded(Vec<CatalogProduct>),
+    PayMethodsLoaded(Vec<PayMethod>),
+    CurrenciesLoaded(Vec<CurrencyRate>),
+    ProvidersLoaded(Vec<ProviderStatus>),
+    AddToCart(String),
+    ChangeQuantity(String, i32),
     UpdateNick(String),
+    CheckNick(u32),
+    NickChecked(u32, CharacterInfoResponse),
+    ToggleGift(bool),
+    UpdatePayerNick(String),
+    UpdatePayerEmail(String),
+    UpdateGiftMessage(String),
     UpdateCrd(String),
     UpdatePaymentMethod(String),
+    SwitchToFallback,
+    UpdatePayMethod(String),
+    UpdateCurrency(String),
     TryPayment,
+    ChallengeRequired(PowChallenge),
+    ChallengeSolved(PowSolution),
     LinkOk(String),
     LinkErr(String),
 }
 
 pub struct App {
+    page: Page,
+    catalog: Vec<CatalogProduct>,
+    cart: Vec<CartLine>,
     current_nick: String,
+    nick_check_id: u32,
+    nick_status: Option<CharacterInfoResponse>,
+    is_gift: bool,
+    payer_nick: String,
+    payer_email: String,
+    gift_message: String,
     warn_message: Option<String>,
     crd_amount: u32,
     payment_method: PaymentServices,
+    /**
+    Способы оплаты Enot, включённые на бэкенде
+     */
+    pay_methods: Vec<PayMethod>,
+    pay_method: Option<PayMethod>,
+    /**
+    Валюты Enot для оплаты CRD с курсами, первой всегда идут рубли
+     */
+    currencies: Vec<CurrencyRate>,
+    currency: Currency,
+    /**
+    Здоровье платёжек: деградировавшие помечаются в списке и заменяются запасными
+     */
+    providers: Vec<ProviderStatus>,
+    pow: Option<PowSolution>,
+    is_solving: bool,
+    /**
+    Счёт из адреса возврата платёжки: показываем его статус вместо формы оплаты
+     */
+    status_order_id: Option<String>,
+    /**
+    Промокод из ссылки партнёра `?promo=`
+     */
+    promo_code: Option<String>,
+}
+
+impl App {
+    /**
+    Курс выбранной валюты, если CRD оплачиваются через Enot не в рублях
+     */
+    fn currency_rate(&self) -> Option<CurrencyRate> {
+        if self.payment_method != PaymentServices::Enot || self.page != Page::Crd {
+            return None;
+        }
+
+        self.currencies
+            .iter()
+            .find(|v| v.currency == self.currency && v.currency != Currency::RUB)
+            .copied()
+    }
+
+    fn provider_label(&self, service: PaymentServices, name: &str) -> String {
+        let degraded = self
+            .providers
+            .iter()
+            .any(|v| v.service == service && v.degraded);
+
+        if degraded {
+            format!("{name} (сбои)")
+        } else {
+            name.to_string()
+        }
+    }
+
+    /**
+    Здоровая замена выбранной платёжки, если та деградировала
+     */
+    fn selected_fallback(&self) -> Option<PaymentServices> {
+        self.providers
+            .iter()
+            .find(|v| v.service == self.payment_method && v.degraded)
+            .and_then(|v| v.fallback)
+    }
+
+    /**
+    Если платёжка по умолчанию деградировала и у неё есть здоровая замена - выбирает замену.
+    Явный выбор игрока не трогаем, замену ему только предлагаем
+     */
+    fn switch_to_fallback(&mut self) {
+        if let Some(fallback) = self.selected_fallback() {
+            self.warn_message = Some(format!(
+                "{} сейчас работает со сбоями, выбран {fallback}",
+                self.payment_method
+            ));
+            self.payment_method = fallback;
+        }
+    }
 }
 
 impl Component for App {
     type Message = PaymentMsg;
     type Properties = ();
 
-    fn create(_ctx: &Context<Self>) -> Self {
+    fn create(ctx: &Context<Self>) -> Self {
+        ctx.link().send_future(async move {
+            match BackendApi::get_catalog().await {
+                Ok(v) => PaymentMsg::CatalogLoaded(v),
+                Err(e) => {
+                    log!(format!("{e:#?}"));
+                    PaymentMsg::CatalogLoaded(vec![])
+                }
+            }
+        });
+
+        ctx.link().send_future(async move {
+            match BackendApi::get_pay_methods().await {
+                Ok(v) => PaymentMsg::PayMethodsLoaded(v),
+                Err(e) => {
+                    log!(format!("{e:#?}"));
+                    PaymentMsg::PayMethodsLoaded(vec![])
+                }
+            }
+        });
+
+        ctx.link().send_future(async move {
+            match BackendApi::get_currencies().await {
+                Ok(v) => PaymentMsg::CurrenciesLoaded(v),
+                Err(e) => {
+                    log!(format!("{e:#?}"));
+                    PaymentMsg::CurrenciesLoaded(vec![])
+                }
+            }
+        });
+
+        ctx.link().send_future(async move {
+            match BackendApi::get_providers().await {
+                Ok(v) => PaymentMsg::ProvidersLoaded(v),
+                Err(e) => {
+                    log!(format!("{e:#?}"));
+                    PaymentMsg::ProvidersLoaded(vec![])
+                }
+            }
+        });
+
         Self {
+            page: Page::Crd,
+            catalog: vec![],
+            cart: vec![],
             current_nick: "".to_string(),
+            nick_check_id: 0,
+            nick_status: None,
+            is_gift: false,
+            payer_nick: "".to_string(),
+            payer_email: "".to_string(),
+            gift_message: "".to_string(),
             warn_message: None,
             crd_amount: MIN_CRD,
             payment_method: PaymentServices::Enot,
+            pay_methods: vec![],
+            pay_method: None,
+            currencies: vec![],
+            currency: Currency::RUB,
+            providers: vec![],
+            pow: None,
+            is_solving: false,
+            status_order_id: get_query_param("order"),
+            promo_code: get_query_param("promo"),
         }
     }
 
     fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
         match msg {
-            PaymentMsg::UpdateNick(v) => self.current_nick = v,
+            PaymentMsg::SwitchPage(v) => {
+                self.page = v;
+                self.warn_message = None;
+            }
+            PaymentMsg::CatalogLoaded(v) => self.catalog = v,
+            PaymentMsg::PayMethodsLoaded(v) => self.pay_methods = v,
+            PaymentMsg::CurrenciesLoaded(v) => self.currencies = v,
+            PaymentMsg::ProvidersLoaded(v) => {
+                self.providers = v;
+                self.switch_to_fallback();
+            }
+            PaymentMsg::AddToCart(product_id) => {
+                match self.cart.iter_mut().find(|v| v.product_id == product_id) {
+                    Some(line) => line.quantity += 1,
+                    None => self.cart.push(CartLine {
+                        product_id,
+                        quantity: 1,
+                    }),
+                }
+            }
+            PaymentMsg::ChangeQuantity(product_id, delta) => {
+                if let Some(line) = self.cart.iter_mut().find(|v| v.product_id == product_id) {
+                    line.quantity = line.quantity.saturating_add_signed(delta);
+                }
+
+                self.cart.retain(|v| v.quantity > 0);
+            }
+            PaymentMsg::UpdateNick(v) => {
+                self.nick_check_id += 1;
+                self.nick_status = None;
+
+                if !v.is_empty() {
+                    let check_id = self.nick_check_id;
+
+                    ctx.link().send_future(async move {
+                        TimeoutFuture::new(NICK_CHECK_DELAY_MS).await;
+                        PaymentMsg::CheckNick(check_id)
+                    });
+                }
+
+                self.current_nick = v;
+            }
+            PaymentMsg::CheckNick(check_id) => {
+                if check_id != self.nick_check_id {
+                    return false;
+                }
+
+                let name = self.current_nick.clone();
+
+                ctx.link().send_future(async move {
+                    match BackendApi::get_character(&name).await {
+                        Ok(resp) => PaymentMsg::NickChecked(check_id, resp),
+                        Err(e) => {
+                            log!(format!("{e:#?}"));
+                            PaymentMsg::NickChecked(check_id, CharacterInfoResponse::Err)
+                        }
+                    }
+                });
+
+                return false;
+            }
+            PaymentMsg::NickChecked(check_id, resp) => {
+                if check_id != self.nick_check_id {
+                    return false;
+                }
+
+                self.nick_status = Some(resp);
+            }
+            PaymentMsg::ToggleGift(v) => self.is_gift = v,
+            PaymentMsg::UpdatePayerNick(v) => self.payer_nick = v,
+            PaymentMsg::UpdatePayerEmail(v) => self.payer_email = v,
+            PaymentMsg::UpdateGiftMessage(v) => self.gift_message = v,
             PaymentMsg::UpdateCrd(v) => {
                 if v.is_empty() {
                     self.crd_amount = 0
@@ -59,11 +319,57 @@ impl Component for App {
                 } else if v == "hotskins" {
                     self.payment_method = PaymentServices::Hotskins;
                 }
+
+                self.warn_message = None;
+            }
+            PaymentMsg::SwitchToFallback => {
+                if let Some(fallback) = self.selected_fallback() {
+                    self.payment_method = fallback;
+                    self.warn_message = None;
+                }
+            }
+            PaymentMsg::UpdatePayMethod(v) => {
+                self.pay_method = self
+                    .pay_methods
+                    .iter()
+                    .find(|method| format!("{method:?}") == v)
+                    .copied();
+            }
+            PaymentMsg::UpdateCurrency(v) => {
+                if let Ok(v) = Currency::from_str(&v) {
+                    self.currency = v;
+                }
             }
             PaymentMsg::TryPayment => {
+                if self.is_solving {
+                    return false;
+                }
+
                 let mut is_ok = true;
 
-                if self.crd_amount < MIN_CRD && self.payment_method == PaymentServices::PaypalychUk {
+                if self.page == Page::Shop {
+                    let currency = self.payment_method.currency();
+                    let all_priced = self.cart.iter().all(|line| {
+                        self.catalog
+                            .iter()
+                            .find(|v| v.id == line.product_id)
+                            .is_some_and(|v| v.prices.contains_key(&currency))
+                    });
+
+                    if self.cart.is_empty() {
+                        self.warn_message = Some("Корзина пуста!".to_string());
+                        is_ok = false;
+                    } else if !all_priced {
+                        self.warn_message =
+                            Some("Товар недоступен для этого способа оплаты!".to_string());
+                        is_ok = false;
+                    }
+                } else if let Some(rate) = self.currency_rate() {
+                    if rate.to_crd(self.crd_amount as f32) < MIN_CRD as f32 {
+                        self.warn_message = Some(format!("Минимум {MIN_CRD} CRD!"));
+                        is_ok = false;
+                    }
+                } else if self.crd_amount < MIN_CRD && self.payment_method == PaymentServices::PaypalychUk {
                     self.warn_message = Some(format!("Минимум {MIN_CRD} $"));
                     is_ok = false;
                 } else if self.crd_amount < MIN_CRD && self.payment_method != PaymentServices::Hotskins {
@@ -71,6 +377,18 @@ impl Component for App {
                     is_ok = false;
                 }
 
+                match &self.nick_status {
+                    Some(CharacterInfoResponse::NotFound) => {
+                        self.warn_message = Some("Неверное имя персонажа!".to_string());
+                        is_ok = false;
+                    }
+                    Some(CharacterInfoResponse::Ok(info)) if info.banned => {
+                        self.warn_message = Some("Персонаж заблокирован!".to_string());
+                        is_ok = false;
+                    }
+                    _ => {}
+                }
+
                 if self.current_nick.is_empty() {
                     self.warn_message = Some("Введите имя персонажа!".to_string());
                     is_ok = false;
@@ -79,20 +397,65 @@ impl Component for App {
                 if is_ok {
                     self.warn_message = None;
 
-                    let name = self.current_nick.clone();
-                    let amount = self.crd_amount;
-                    let method = self.payment_method;
+                    let params = CreateInvoice {
+                        amount: self.crd_amount as f32,
+                        char_name: self.current_nick.clone(),
+                        service: self.payment_method,
+                        cart: match self.page {
+                            Page::Crd => vec![],
+                            Page::Shop => self.cart.clone(),
+                        },
+                        payer_email: (!self.payer_email.is_empty())
+                            .then(|| self.payer_email.clone()),
+                        gift: self.is_gift.then(|| GiftDetails {
+                            payer_char_name: (!self.payer_nick.is_empty())
+                                .then(|| self.payer_nick.clone()),
+                            message: (!self.gift_message.is_empty())
+                                .then(|| self.gift_message.clone()),
+                        }),
+                        locale: get_browser_locale(),
+                        pow: self.pow.take(),
+                        pay_method: self
+                            .pay_method
+                            .filter(|_| self.payment_method == PaymentServices::Enot),
+                        promo_code: self.promo_code.clone(),
+                        currency: self.currency_rate().map(|v| v.currency),
+                    };
 
                     ctx.link().send_future(async move {
-                        match BackendApi::create_invoice(name, amount, method).await {
+                        match BackendApi::create_invoice(params).await {
                             Ok(resp) => match resp {
                                 InvoiceCreationResponse::Ok(v) => PaymentMsg::LinkOk(v),
                                 InvoiceCreationResponse::WrongNick => {
                                     PaymentMsg::LinkErr("Неверное имя персонажа!".to_string())
                                 }
-                                InvoiceCreationResponse::Err => {
-                                    PaymentMsg::LinkErr("Network error".to_string())
+                                InvoiceCreationResponse::WrongPayerNick => {
+                                    PaymentMsg::LinkErr("Неверное имя отправителя!".to_string())
                                 }
+                                InvoiceCreationResponse::WrongEmail => {
+                                    PaymentMsg::LinkErr("Неверный email!".to_string())
+                                }
+                                InvoiceCreationResponse::Banned => {
+                                    PaymentMsg::LinkErr("Персонаж заблокирован!".to_string())
+                                }
+                                InvoiceCreationResponse::ProductUnavailable => {
+                                    PaymentMsg::LinkErr("Товар недоступен!".to_string())
+                                }
+                                InvoiceCreationResponse::RateLimited => PaymentMsg::LinkErr(
+                                    "Слишком много попыток, подождите минуту".to_string(),
+                                ),
+                                InvoiceCreationResponse::ChallengeRequired(v) => {
+                                    PaymentMsg::ChallengeRequired(v)
+                                }
+                                InvoiceCreationResponse::PayMethodUnavailable => {
+                                    PaymentMsg::LinkErr("Способ оплаты недоступен!".to_string())
+                                }
+                                InvoiceCreationResponse::CurrencyUnavailable => {
+                                    PaymentMsg::LinkErr("Валюта недоступна!".to_string())
+                                }
+                                InvoiceCreationResponse::Failed(e) => PaymentMsg::LinkErr(
+                                    payment_error_message(e, get_browser_locale()).to_string(),
+                                ),
                             },
                             Err(e) => {
                                 log!(format!("{e:#?}"));
@@ -102,6 +465,35 @@ impl Component for App {
                     });
                 }
             }
+            PaymentMsg::ChallengeRequired(challenge) => {
+                self.is_solving = true;
+                self.warn_message = Some("Проверка браузера, подождите...".to_string());
+
+                ctx.link().send_future(async move {
+                    let mut from = 0;
+
+                    loop {
+                        if let Some(nonce) =
+                            solve_range(&challenge.challenge, challenge.difficulty, from, POW_CHUNK)
+                        {
+                            return PaymentMsg::ChallengeSolved(PowSolution {
+                                challenge: challenge.challenge,
+                                nonce,
+                            });
+                        }
+
+                        from += POW_CHUNK;
+                        TimeoutFuture::new(0).await;
+                    }
+                });
+            }
+            PaymentMsg::ChallengeSolved(solution) => {
+                self.is_solving = false;
+                self.warn_message = None;
+                self.pow = Some(solution);
+
+                ctx.link().send_message(PaymentMsg::TryPayment);
+            }
             PaymentMsg::LinkOk(url) => {
                 web_sys::window().unwrap().location().replace(&url).unwrap();
             }
@@ -112,21 +504,72 @@ impl Component for App {
     }
 
     fn view(&self, ctx: &Context<Self>) -> Html {
+        if let Some(order_id) = &self.status_order_id {
+            let returned_success = get_query_param("result").map(|v| v == "success");
+
+            return html! {
+                <>
+                <div class="sep_b">
+                </div>
+                <InvoiceStatusPage
+                    order_id={order_id.clone()}
+                    {returned_success}
+                    on_back={ctx.link().callback(|_| PaymentMsg::LinkOk("/".to_string()))}
+                />
+                </>
+            };
+        }
+
         let on_nick_change = ctx.link().callback(PaymentMsg::UpdateNick);
         let on_nick_input = Callback::from(move |input_event: InputEvent| {
             on_nick_change.emit(get_value_from_input_event(input_event));
         });
 
+        let on_gift_change = ctx.link().callback(PaymentMsg::ToggleGift);
+        let on_gift_toggle = Callback::from(move |event: Event| {
+            on_gift_change.emit(get_checked_from_event(event));
+        });
+
+        let on_payer_nick_change = ctx.link().callback(PaymentMsg::UpdatePayerNick);
+        let on_payer_nick_input = Callback::from(move |input_event: InputEvent| {
+            on_payer_nick_change.emit(get_value_from_input_event(input_event));
+        });
+
+        let on_payer_email_change = ctx.link().callback(PaymentMsg::UpdatePayerEmail);
+        let on_payer_email_input = Callback::from(move |input_event: InputEvent| {
+            on_payer_email_change.emit(get_value_from_input_event(input_event));
+        });
+
+        let on_gift_message_change = ctx.link().callback(PaymentMsg::UpdateGiftMessage);
+        let on_gift_message_input = Callback::from(move |input_event: InputEvent| {
+            on_gift_message_change.emit(get_value_from_input_event(input_event));
+        });
+
         let on_payment_provider_change = ctx.link().callback(PaymentMsg::UpdatePaymentMethod);
         let on_payment_provider_input = Callback::from(move |event: Event| {
             on_payment_provider_change.emit(get_value_from_event(event));
         });
 
+        let on_pay_method_change = ctx.link().callback(PaymentMsg::UpdatePayMethod);
+        let on_pay_method_input = Callback::from(move |event: Event| {
+            on_pay_method_change.emit(get_value_from_event(event));
+        });
+
+        let on_currency_change = ctx.link().callback(PaymentMsg::UpdateCurrency);
+        let on_currency_input = Callback::from(move |event: Event| {
+            on_currency_change.emit(get_value_from_event(event));
+        });
+
         let on_crd_change = ctx.link().callback(PaymentMsg::UpdateCrd);
         let on_crd_input = Callback::from(move |input_event: InputEvent| {
             on_crd_change.emit(get_value_from_input_event(input_event));
         });
 
+        let on_product_add = ctx.link().callback(PaymentMsg::AddToCart);
+        let on_quantity_change = ctx
+            .link()
+            .callback(|(product_id, delta)| PaymentMsg::ChangeQuantity(product_id, delta));
+
         let r = html! {
             <>
             <div class="sep_b">
@@ -140,18 +583,113 @@ impl Component for App {
                         </div>
                         <div class= "dragon"></div>
                     </div>
+                    <div class="dlg_tabs">
+                        <div class={classes!("dlg_tab", (self.page == Page::Crd).then_some("dlg_tab_active"))}
+                            onclick={ctx.link().callback(|_| PaymentMsg::SwitchPage(Page::Crd))}>
+                            { "CRD" }
+                        </div>
+                        <div class={classes!("dlg_tab", (self.page == Page::Shop).then_some("dlg_tab_active"))}
+                            onclick={ctx.link().callback(|_| PaymentMsg::SwitchPage(Page::Shop))}>
+                            { "Магазин" }
+                        </div>
+                    </div>
                     <div class="sep_sm"></div>
                     <div class="dlg_r_a">
                         <div class="dlg_r_b">
-                            { "Ник:" }
+                            { if self.is_gift { "Кому:" } else { "Ник:" } }
                         </div>
                         <div class="dlg_r_c">
                             <input placeholder="Введите имя персонажа" type="text" id="nick" name="Ник" class="dlg_r_i" oninput={on_nick_input} value={self.current_nick.clone()}/>
                         </div>
                     </div>
+                    {
+                        match &self.nick_status {
+                            Some(CharacterInfoResponse::Ok(info)) if info.banned => html!{
+                                <div class="dlg_r_nick_err">{ "Персонаж заблокирован" }</div>
+                            },
+                            Some(CharacterInfoResponse::Ok(info)) => html!{
+                                <div class="dlg_r_nick">
+                                    {
+                                        format!(
+                                            "{} ур.{}, {}",
+                                            info.level,
+                                            info.clan.as_ref().map(|v| format!(", клан {v}")).unwrap_or_default(),
+                                            if info.online { "в игре" } else { "не в игре" }
+                                        )
+                                    }
+                                </div>
+                            },
+                            Some(CharacterInfoResponse::NotFound) => html!{
+                                <div class="dlg_r_nick_err">{ "Персонаж не найден" }</div>
+                            },
+                            _ => html!{},
+                        }
+                    }
+                    <div class="sep_sm"></div>
+                    <div class="dlg_r_a">
+                        <div class="dlg_r_b">
+                            { "Email:" }
+                        </div>
+                        <div class="dlg_r_c">
+                            <input placeholder="Для чека (необязательно)" type="email" class="dlg_r_i" oninput={on_payer_email_input} value={self.payer_email.clone()}/>
+                        </div>
+                    </div>
+                    <div class="dlg_r_a">
+                        <label class="dlg_r_gift">
+                            <input type="checkbox" checked={self.is_gift} onchange={on_gift_toggle}/>
+                            { "Подарок другому игроку" }
+                        </label>
+                    </div>
+                    {
+                        if self.is_gift {
+                            html!{
+                                <div>
+                                    <div class="sep_sm"></div>
+                                    <div class="dlg_r_a">
+                                        <div class="dlg_r_b">
+                                            { "От:" }
+                                        </div>
+                                        <div class="dlg_r_c">
+                                            <input placeholder="Ваш персонаж (необязательно)" type="text" class="dlg_r_i" oninput={on_payer_nick_input} value={self.payer_nick.clone()}/>
+                                        </div>
+                                    </div>
+                                    <div class="sep_sm"></div>
+                                    <div class="dlg_r_a">
+                                        <div class="dlg_r_b">
+                                            { "Текст:" }
+                                        </div>
+                                        <div class="dlg_r_c">
+                                            <input placeholder="Поздравление" type="text" class="dlg_r_i" maxlength={GIFT_MESSAGE_MAX_LEN.to_string()} oninput={on_gift_message_input} value={self.gift_message.clone()}/>
+                                        </div>
+                                    </div>
+                                </div>
+                            }
+                        } else {
+                            html!{}
+                        }
+                    }
                     <div class="sep_sm"></div>
                     {
-                        if self.payment_method == PaymentServices::PaypalychUk {
+                        if self.page == Page::Shop {
+                            html!{
+                                <div>
+                                    <Catalog
+                                        products={self.catalog.clone()}
+                                        cart={self.cart.clone()}
+                                        currency={self.payment_method.currency()}
+                                        on_add={on_product_add}
+                                    />
+                                    <Cart
+                                        products={self.catalog.clone()}
+                                        cart={self.cart.clone()}
+                                        currency={self.payment_method.currency()}
+                                        on_change={on_quantity_change}
+                                    />
+                                    <div class="sep_sm"></div>
+                                </div>
+                            }
+                        }
+                        else if self.payment_method == PaymentServices::PaypalychUk {
                             html!{
                                 <div>
                                     <div class="dlg_r_a">
@@ -169,6 +707,24 @@ impl Component for App {
                                 </div>
                             }
                         } 
+                        else if let Some(rate) = self.currency_rate() {
+                            html!{
+                                <div>
+                                    <div class="dlg_r_a">
+                                        <div class="dlg_r_b2">
+                                            { format!("{}:", rate.currency) }
+                                        </div>
+                                        <div class="dlg_r_c">
+                                            <input placeholder={format!("Сумма в {}", rate.currency)} id="crd" name="CRD" class="dlg_r_i2" oninput={on_crd_input} value={self.crd_amount.to_string()}/>
+                                        </div>
+                                        <div class="dlg_r_b22">
+                                        { format!("= {} CRD", rate.to_crd(self.crd_amount as f32) as u32) }
+                                    </div>
+                                    </div>
+                                    <div class="sep_sm"></div>
+                                </div>
+                            }
+                        }
                         else if self.payment_method != PaymentServices::Hotskins {
                             html!{
                                 <div>
@@ -203,16 +759,81 @@ impl Component for App {
                         </div>
                         <div class="dlg_r_slct">
                             <select name="payments" id="payments" onchange={on_payment_provider_input}>
-                                <option value="enot" selected={self.payment_method == PaymentServices::Enot}>{ "Enot" }</option>
-                                <option value="paypalich" selected={self.payment_method == PaymentServices::Paypalych}>{ "Paypalych" }</option>
-                                <option value="paypalich_uk" selected={self.payment_method == PaymentServices::PaypalychUk}>{ "Paypalych UA" }</option>
-                                <option value="hotskins" selected={self.payment_method == PaymentServices::Hotskins}>{ "Hot Skins" }</option>
+                                <option value="enot" selected={self.payment_method == PaymentServices::Enot}>{ self.provider_label(PaymentServices::Enot, "Enot") }</option>
+                                <option value="paypalich" selected={self.payment_method == PaymentServices::Paypalych}>{ self.provider_label(PaymentServices::Paypalych, "Paypalych") }</option>
+                                <option value="paypalich_uk" selected={self.payment_method == PaymentServices::PaypalychUk}>{ self.provider_label(PaymentServices::PaypalychUk, "Paypalych UA") }</option>
+                                <option value="hotskins" selected={self.payment_method == PaymentServices::Hotskins}>{ self.provider_label(PaymentServices::Hotskins, "Hot Skins") }</option>
                             </select>
                         </div>
                     </div>
+                    {
+                        if let Some(fallback) = self.selected_fallback() {
+                            html!{
+                                <div class="dlg_f2">
+                                    <div class="dlg_f2_t">
+                                        { format!("{} сейчас работает со сбоями ", self.payment_method) }
+                                        <button onclick={ctx.link().callback(|_| PaymentMsg::SwitchToFallback)}>
+                                            { format!("Выбрать {fallback}") }
+                                        </button>
+                                    </div>
+                                </div>
+                            }
+                        } else {
+                            html!{}
+                        }
+                    }
+                    {
+                        if self.payment_method == PaymentServices::Enot && !self.pay_methods.is_empty() {
+                            html!{
+                                <div class="dlg_r_a">
+                                    <div class="dlg_r_b_b">
+                                        { "Чем оплатить" }
+                                    </div>
+                                    <div class="dlg_r_slct">
+                                        <select name="pay_method" id="pay_method" onchange={on_pay_method_input}>
+                                            <option value="any" selected={self.pay_method.is_none()}>{ "Любой способ" }</option>
+                                            {
+                                                for self.pay_methods.iter().map(|method| html!{
+                                                    <option value={format!("{method:?}")} selected={self.pay_method == Some(*method)}>
+                                                        { method.to_string() }
+                                                    </option>
+                                                })
+                                            }
+                                        </select>
+                                    </div>
+                                </div>
+                            }
+                        } else {
+                            html!{}
+                        }
+                    }
+                    {
+                        if self.payment_method == PaymentServices::Enot && self.page == Page::Crd && self.currencies.len() > 1 {
+                            html!{
+                                <div class="dlg_r_a">
+                                    <div class="dlg_r_b_b">
+                                        { "Валюта" }
+                                    </div>
+                                    <div class="dlg_r_slct">
+                                        <select name="currency" id="currency" onchange={on_currency_input}>
+                                            {
+                                                for self.currencies.iter().map(|rate| html!{
+                                                    <option value={rate.currency.to_string()} selected={self.currency == rate.currency}>
+                                                        { format!("{} (1 {} = {} CRD)", rate.currency, rate.currency, rate.crd_rate) }
+                                                    </option>
+                                                })
+                                            }
+                                        </select>
+                                    </div>
+                                </div>
+                            }
+                        } else {
+                            html!{}
+                        }
+                    }
                     <div class="sep_sm"></div>
                     <div class="dlg_f">
-                    <button class="fill" onclick={ctx.link().callback(|_| PaymentMsg::TryPayment)}>
+                    <button class="fill" disabled={self.is_solving} onclick={ctx.link().callback(|_| PaymentMsg::TryPayment)}>
                         { "Оплатить" }
                     </button>
                 </div>
@@ -230,12 +851,11 @@ impl Component for App {
                         match self.payment_method {
                             PaymentServices::Enot => html!{
                                 <div class="paycontent">
-                                <div class="pay_form_text">{"Банковские карты РФ, СНГ / Криптавалюты / Киви / Юмани "}</div>
+                                <div class="pay_form_text">{"Банковские карты РФ, СНГ / СБП / Криптовалюты / Юмани"}</div>
                                     <div class="payimg">
                                         <div class="enot_pay">
                                             <img src="/img/mir.png" alt="Mir" />
                                             <img src="/img/visa.png" alt="Mir" />
-                                            <img src="/img/qiwi.png" alt="Mir" />
                                             <img src="/img/maestro.png" alt="Mir" />
                                             <img src="/img/master.png" alt="Mir" />
                                         </div>
@@ -0,0 +1,165 @@
+use crate::app::api::BackendApi;
+use crate::app::util::{get_browser_locale, payment_error_message};
+use gloo_console::log;
+use gloo_timers::future::TimeoutFuture;
+use shared::{InvoiceInfo, InvoiceInfoResponse, InvoiceStatus};
+use yew::prelude::*;
+
+/**
+Как часто переспрашивать статус, пока платёж не завершён
+ */
+const STATUS_POLL_MS: u32 = 5_000;
+
+#[derive(Clone, PartialEq, Properties)]
+pub struct InvoiceStatusProps {
+    pub order_id: String,
+    /**
+    `result` из адреса возврата платёжки. Показывается, пока webhook ещё не пришёл
+     */
+    pub returned_success: Option<bool>,
+    pub on_back: Callback<()>,
+}
+
+pub enum InvoiceStatusMsg {
+    Load,
+    Loaded(InvoiceInfoResponse),
+}
+
+pub struct InvoiceStatusPage {
+    response: Option<InvoiceInfoResponse>,
+}
+
+impl InvoiceStatusPage {
+    fn is_final(info: &InvoiceInfo) -> bool {
+        !matches!(
+            info.status,
+            InvoiceStatus::WaitingForPayment | InvoiceStatus::Payed
+        )
+    }
+
+    fn status_text(info: &InvoiceInfo, returned_success: Option<bool>) -> &'static str {
+        match info.status {
+            InvoiceStatus::WaitingForPayment if returned_success == Some(true) => {
+                "Ждём подтверждение оплаты от платёжной системы..."
+            }
+            InvoiceStatus::WaitingForPayment if returned_success == Some(false) => {
+                "Оплата не прошла. Попробуйте ещё раз или выберите другой способ оплаты"
+            }
+            InvoiceStatus::WaitingForPayment => "Ожидает оплаты",
+            InvoiceStatus::FailedToCreate => {
+                payment_error_message(info.error.unwrap_or_default(), get_browser_locale())
+            }
+            InvoiceStatus::Aborted => "Платёж отменён",
+            InvoiceStatus::Payed => "Оплачено, выдаём покупку персонажу...",
+            InvoiceStatus::Delivered => "Оплачено и выдано персонажу. Спасибо!",
+            InvoiceStatus::Refunded => "Платёж возвращён",
+        }
+    }
+}
+
+impl Component for InvoiceStatusPage {
+    type Message = InvoiceStatusMsg;
+    type Properties = InvoiceStatusProps;
+
+    fn create(ctx: &Context<Self>) -> Self {
+        ctx.link().send_message(InvoiceStatusMsg::Load);
+
+        Self { response: None }
+    }
+
+    fn update(&mut self, ctx: &Context<Self>, msg: Self::Message) -> bool {
+        match msg {
+            InvoiceStatusMsg::Load => {
+                let order_id = ctx.props().order_id.clone();
+
+                ctx.link().send_future(async move {
+                    match BackendApi::get_invoice(&order_id).await {
+                        Ok(v) => InvoiceStatusMsg::Loaded(v),
+                        Err(e) => {
+                            log!(format!("{e:#?}"));
+                            InvoiceStatusMsg::Loaded(InvoiceInfoResponse::Err)
+                        }
+                    }
+                });
+
+                false
+            }
+            InvoiceStatusMsg::Loaded(resp) => {
+                let poll = match &resp {
+                    InvoiceInfoResponse::Ok(info) => !Self::is_final(info),
+                    InvoiceInfoResponse::NotFound => false,
+                    InvoiceInfoResponse::Err => true,
+                };
+
+                if poll {
+                    ctx.link().send_future(async move {
+                        TimeoutFuture::new(STATUS_POLL_MS).await;
+                        InvoiceStatusMsg::Load
+                    });
+                }
+
+                self.response = Some(resp);
+
+                true
+            }
+        }
+    }
+
+    fn view(&self, ctx: &Context<Self>) -> Html {
+        let props = ctx.props();
+        let on_back = props.on_back.reform(|_| ());
+
+        let body = match &self.response {
+            None => html! { <div class="dlg_r_hs">{ "Загрузка..." }</div> },
+            Some(InvoiceInfoResponse::NotFound) => {
+                html! { <div class="dlg_r_hs">{ "Платёж не найден" }</div> }
+            }
+            Some(InvoiceInfoResponse::Err) => {
+                html! { <div class="dlg_r_hs">{ "Ошибка сети, пробуем ещё раз..." }</div> }
+            }
+            Some(InvoiceInfoResponse::Ok(info)) => html! {
+                <>
+                    <div class="dlg_r_a">
+                        <div class="dlg_r_b">{ "Персонаж:" }</div>
+                        <div class="dlg_r_c">{ &info.char_name }</div>
+                    </div>
+                    <div class="dlg_r_a">
+                        <div class="dlg_r_b">{ "Сумма:" }</div>
+                        <div class="dlg_r_c">{ format!("{} {}", info.amount, info.currency) }</div>
+                    </div>
+                    {
+                        for info.lines.iter().map(|line| html! {
+                            <div class="dlg_r_a">
+                                <div class="dlg_r_b"></div>
+                                <div class="dlg_r_c">{ format!("{} x{}", line.name, line.quantity) }</div>
+                            </div>
+                        })
+                    }
+                    <div class="dlg_r_hs">{ Self::status_text(info, props.returned_success) }</div>
+                </>
+            },
+        };
+
+        html! {
+            <div class="dlg_a">
+                <div class="dlg_b">
+                    <div class="dlg_hdr">
+                    <span class="logo pull-right"></span>
+                        <div class="dlg_hdr_txt">
+                        <b>{ "Статус платежа" }</b>
+                        </div>
+                        <div class= "dragon"></div>
+                    </div>
+                    <div class="sep_sm"></div>
+                    { body }
+                    <div class="sep_sm"></div>
+                    <div class="dlg_f">
+                        <button class="fill" onclick={on_back}>
+                            { "Вернуться к оплате" }
+                        </button>
+                    </div>
+                </div>
+            </div>
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use wasm_bindgen::{JsCast, UnwrapThrowExt};
 use web_sys::{Event, HtmlInputElement, HtmlSelectElement, InputEvent};
+use shared::{Locale, PaymentError};
 use yew::prelude::*;
 
+#[allow(dead_code)]
 #[derive(Clone, PartialEq, Properties)]
 pub struct Props {
     pub value: String,
@@ -22,3 +24,71 @@ pub fn get_value_from_event(event: Event) -> String {
 
     target.value()
 }
+
+pub fn get_checked_from_event(event: Event) -> bool {
+    let event_target = event.target().unwrap_throw();
+    let target: HtmlInputElement = event_target.dyn_into().unwrap_throw();
+
+    target.checked()
+}
+
+pub fn get_browser_locale() -> Locale {
+    let language = web_sys::window().and_then(|v| v.navigator().language());
+
+    match language {
+        Some(v) if v.starts_with("ru") => Locale::Ru,
+        Some(_) => Locale::En,
+        None => Locale::Ru,
+    }
+}
+
+/**
+Текст ошибки создания платежа для игрока
+ */
+pub fn payment_error_message(error: PaymentError, locale: Locale) -> &'static str {
+    match (error, locale) {
+        (PaymentError::ProviderUnavailable, Locale::Ru) => {
+            "Платёжная система не отвечает, попробуйте позже или выберите другую"
+        }
+        (PaymentError::ProviderUnavailable, Locale::En) => {
+            "Payment provider is not responding, try again later or choose another one"
+        }
+        (PaymentError::ProviderAuth, Locale::Ru) => {
+            "Платёжная система временно недоступна, выберите другую"
+        }
+        (PaymentError::ProviderAuth, Locale::En) => {
+            "Payment provider is temporarily unavailable, choose another one"
+        }
+        (PaymentError::Validation, Locale::Ru) => {
+            "Платёжная система не приняла сумму или способ оплаты"
+        }
+        (PaymentError::Validation, Locale::En) => {
+            "Payment provider rejected the amount or payment method"
+        }
+        (PaymentError::RateLimited, Locale::Ru) => "Слишком много попыток, подождите минуту",
+        (PaymentError::RateLimited, Locale::En) => "Too many attempts, wait a minute",
+        (PaymentError::GameDbUnavailable, Locale::Ru) => {
+            "Игровой сервер недоступен, попробуйте позже"
+        }
+        (PaymentError::GameDbUnavailable, Locale::En) => {
+            "Game server is unavailable, try again later"
+        }
+        (PaymentError::Internal, Locale::Ru) => "Не удалось создать платёж",
+        (PaymentError::Internal, Locale::En) => "Failed to create payment",
+    }
+}
+
+/**
+Параметр из query-строки текущей страницы
+ */
+pub fn get_query_param(name: &str) -> Option<String> {
+    let search = web_sys::window()?.location().search().ok()?;
+
+    search
+        .trim_start_matches('?')
+        .split('&')
+        .filter_map(|v| v.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+        .filter(|v| !v.is_empty())
+}
@@ -1,7 +1,11 @@
+pub mod pow;
+
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum PaymentServices {
     Enot,
     Hotskins,
@@ -28,16 +32,312 @@ impl Display for PaymentServices {
     }
 }
 
+impl PaymentServices {
+    pub fn currency(&self) -> Currency {
+        match self {
+            PaymentServices::Enot | PaymentServices::Hotskins | PaymentServices::Paypalych => {
+                Currency::RUB
+            }
+            PaymentServices::PaypalychUk => Currency::USD,
+        }
+    }
+}
+
+/**
+Состояние платёжки для выбора на фронтенде
+ */
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+pub struct ProviderStatus {
+    pub service: PaymentServices,
+    /**
+    Много ошибок или медленные ответы за последние минуты
+     */
+    pub degraded: bool,
+    /**
+    Здоровая платёжка, которую стоит предложить вместо деградировавшей
+     */
+    pub fallback: Option<PaymentServices>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Currency {
+    RUB,
+    USD,
+    EUR,
+    UAH,
+    KZT,
+    /**
+    USDT в сети TRC20
+     */
+    USDT,
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Currency::RUB => "RUB",
+            Currency::USD => "USD",
+            Currency::EUR => "EUR",
+            Currency::UAH => "UAH",
+            Currency::KZT => "KZT",
+            Currency::USDT => "USDT",
+        })
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "RUB" => Ok(Currency::RUB),
+            "USD" => Ok(Currency::USD),
+            "EUR" => Ok(Currency::EUR),
+            "UAH" => Ok(Currency::UAH),
+            "KZT" => Ok(Currency::KZT),
+            "USDT" => Ok(Currency::USDT),
+            _ => Err(format!("Unknown currency {s}")),
+        }
+    }
+}
+
+/**
+Сколько CRD начисляется за единицу валюты
+ */
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+pub struct CurrencyRate {
+    pub currency: Currency,
+    pub crd_rate: f32,
+}
+
+impl CurrencyRate {
+    /**
+    Округляется до сотых, чтобы погрешность f32 не съедала целый CRD при выдаче
+     */
+    pub fn to_crd(&self, amount: f32) -> f32 {
+        (amount * self.crd_rate * 100.0).round() / 100.0
+    }
+}
+
+/**
+Формат конфига: `KZT:0.19`
+ */
+impl FromStr for CurrencyRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((currency, crd_rate)) = s.split_once(':') else {
+            return Err(format!("Expected CURRENCY:RATE, got {s}"));
+        };
+
+        let currency = Currency::from_str(currency)?;
+        let crd_rate = f32::from_str(crd_rate).map_err(|e| format!("Wrong rate {s}: {e}"))?;
+
+        if !crd_rate.is_finite() || crd_rate <= 0.0 {
+            return Err(format!("Rate must be positive: {s}"));
+        }
+
+        Ok(CurrencyRate { currency, crd_rate })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum InvoiceCreationResponse {
     Ok(String),
     WrongNick,
+    WrongPayerNick,
+    WrongEmail,
+    Banned,
+    ProductUnavailable,
+    RateLimited,
+    ChallengeRequired(PowChallenge),
+    PayMethodUnavailable,
+    CurrencyUnavailable,
+    Failed(PaymentError),
+}
+
+/**
+Почему не удалось создать платёж. Сохраняется в упавшем счёте, текст для игрока
+подбирает фронтенд
+ */
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Default)]
+pub enum PaymentError {
+    /**
+    Платёжка не отвечает, отвечает 5xx или не успевает ответить
+     */
+    ProviderUnavailable,
+    /**
+    Платёжка не приняла наши ключи или магазин отключён
+     */
+    ProviderAuth,
+    /**
+    Платёжка не приняла параметры счёта: сумму, валюту или способ оплаты
+     */
+    Validation,
+    /**
+    Платёжка ограничила частоту запросов
+     */
+    RateLimited,
+    /**
+    Игровая база недоступна, персонажа не проверить
+     */
+    GameDbUnavailable,
+    /**
+    Непонятный ответ платёжки, наша ошибка или счёт создан до появления типов ошибок
+     */
+    #[default]
+    Internal,
+}
+
+/**
+Proof-of-work задача: найти nonce, при котором sha256("{challenge}:{nonce}")
+начинается с `difficulty` нулевых бит
+ */
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PowChallenge {
+    pub challenge: String,
+    pub difficulty: u8,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PowSolution {
+    pub challenge: String,
+    pub nonce: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CharacterInfo {
+    pub name: String,
+    pub level: u8,
+    pub class_id: i32,
+    pub clan: Option<String>,
+    pub online: bool,
+    pub banned: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum CharacterInfoResponse {
+    Ok(CharacterInfo),
+    NotFound,
     Err,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct CreateInvoice {
     pub amount: f32,
     pub char_name: String,
     pub service: PaymentServices,
-}
\ No newline at end of file
+    #[serde(default)]
+    pub cart: Vec<CartLine>,
+    pub payer_email: Option<String>,
+    pub gift: Option<GiftDetails>,
+    #[serde(default)]
+    pub locale: Locale,
+    #[serde(default)]
+    pub pow: Option<PowSolution>,
+    /**
+    Способ оплаты, на котором сразу откроется страница платёжки. Только для Enot
+     */
+    #[serde(default)]
+    pub pay_method: Option<PayMethod>,
+    #[serde(default)]
+    pub promo_code: Option<String>,
+    /**
+    Валюта оплаты CRD через Enot. Если не указана - рубли
+     */
+    #[serde(default)]
+    pub currency: Option<Currency>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum PayMethod {
+    Card,
+    Sbp,
+    Crypto,
+    YooMoney,
+}
+
+impl Display for PayMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PayMethod::Card => "Банковская карта",
+            PayMethod::Sbp => "СБП",
+            PayMethod::Crypto => "Криптовалюта",
+            PayMethod::YooMoney => "ЮMoney",
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Default)]
+pub enum Locale {
+    #[default]
+    Ru,
+    En,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GiftDetails {
+    pub payer_char_name: Option<String>,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CartLine {
+    pub product_id: String,
+    pub quantity: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ProductItem {
+    pub item_id: u32,
+    pub count: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CatalogProduct {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub items: Vec<ProductItem>,
+    pub prices: HashMap<Currency, f32>,
+    pub stock: Option<u32>,
+}
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+pub enum InvoiceStatus {
+    WaitingForPayment,
+    FailedToCreate,
+    Aborted,
+    Payed,
+    Delivered,
+    Refunded,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InvoiceLineInfo {
+    pub product_id: String,
+    pub name: String,
+    pub quantity: u32,
+    pub price: f32,
+    pub items: Vec<ProductItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InvoiceInfo {
+    pub order_id: String,
+    pub char_name: String,
+    pub service: PaymentServices,
+    pub amount: f32,
+    pub currency: Currency,
+    pub status: InvoiceStatus,
+    pub lines: Vec<InvoiceLineInfo>,
+    #[serde(default)]
+    pub error: Option<PaymentError>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum InvoiceInfoResponse {
+    Ok(InvoiceInfo),
+    NotFound,
+    Err,
+}
@@ -0,0 +1,33 @@
+use sha2::{Digest, Sha256};
+
+/**
+Количество ведущих нулевых бит в sha256("{challenge}:{nonce}")
+ */
+pub fn leading_zero_bits(challenge: &str, nonce: u64) -> u32 {
+    let hash = Sha256::digest(format!("{challenge}:{nonce}").as_bytes());
+
+    let mut bits = 0;
+
+    for byte in hash {
+        if byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+
+    bits
+}
+
+pub fn is_solved(challenge: &str, nonce: u64, difficulty: u8) -> bool {
+    leading_zero_bits(challenge, nonce) >= difficulty as u32
+}
+
+/**
+Перебирает `count` nonce начиная с `from`. Перебор разбит на части, чтобы фронт мог
+отдавать управление браузеру между ними
+ */
+pub fn solve_range(challenge: &str, difficulty: u8, from: u64, count: u64) -> Option<u64> {
+    (from..from.saturating_add(count)).find(|v| is_solved(challenge, *v, difficulty))
+}